@@ -1,6 +1,6 @@
 use actix_web::web;
 
-use crate::handlers::{root, files, folders, auth, timelapse};
+use crate::handlers::{root, files, folders, search, auth, timelapse, admin, trash, jobs};
 
 pub fn config_app(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -14,15 +14,19 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
                     )
                     .service(
                         web::resource("move")
-                            .route(web::post().to(folders::move_folder)) // move folder folder_path into a sibling folder
+                            .route(web::post().to(folders::move_folder)) // move folder folder_path into any folder
                     )
                     .service(
                         web::resource("rename")
                             .route(web::post().to(folders::rename_folder)) // rename folder folder_path
                     )
+                    .service(
+                        web::resource("folders")
+                            .route(web::get().to(folders::get_subfolders)) // get the folders in folder_path as json for the folder picker
+                    )
                     .service(
                         web::resource("copy")
-                            .route(web::post().to(folders::copy_folder)) // copy folder folder_path
+                            .route(web::post().to(folders::copy_folder)) // copy folder folder_path next to itself or into any folder
                     )
                     .service(
                         web::resource("zip")
@@ -32,6 +36,34 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
                         web::resource("remove")
                             .route(web::post().to(folders::remove_folder)) // delete folder folder_path
                     )
+                    .service(
+                        web::resource("search")
+                            .route(web::get().to(search::search)) // search the names of everything below folder_path
+                    )
+                    .service(
+                        web::resource("recent")
+                            .route(web::get().to(search::recent)) // get the most recently modified files below folder_path
+                    )
+                    .service(
+                        web::resource("duplicates")
+                            .route(web::get().to(search::duplicates)) // find files with the same contents below folder_path
+                    )
+                    .service(
+                        web::resource("usage")
+                            .route(web::get().to(folders::get_folder_usage)) // get the disk usage of folder_path and what is in it
+                    )
+                    .service(
+                        web::resource("list")
+                            .route(web::get().to(files::get_file_list)) // get a page of files and folders in folder_path as json
+                    )
+                    .service(
+                        web::resource("events")
+                            .route(web::get().to(files::get_file_events)) // stream changes in folder_path and job progress as server-sent events
+                    )
+                    .service(
+                        web::resource("hidden")
+                            .route(web::post().to(files::set_show_hidden)) // show or hide hidden files for the logged in user
+                    )
                     .service(
                         web::scope("/files")
                             .service(
@@ -41,12 +73,32 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
                             )
                             .service(
                                 web::resource("move")
-                                    .route(web::post().to(files::move_entities)) // move selected entities into a child folder
+                                    .route(web::post().to(files::move_entities)) // move selected entities into any folder
+                            )
+                            .service(
+                                web::resource("copy")
+                                    .route(web::post().to(files::copy_entities)) // copy selected entities into any folder
                             )
                             .service(
                                 web::resource("remove")
                                     .route(web::post().to(files::remove_entities)) // remove selected entities
                             )
+                            .service(
+                                web::resource("zip")
+                                    .route(web::post().to(files::zip_entities)) // zip selected entities into one archive
+                            )
+                            .service(
+                                web::resource("download")
+                                    .route(web::post().to(files::download_entities)) // download selected entities as one zip
+                            )
+                            .service(
+                                web::resource("rename/preview")
+                                    .route(web::post().to(files::preview_rename_entities)) // show the new names of selected entities
+                            )
+                            .service(
+                                web::resource("rename")
+                                    .route(web::post().to(files::rename_entities)) // rename selected entities with a pattern
+                            )
                             .service(
                                 web::scope("/{file_name}")
                                     .service(
@@ -67,11 +119,11 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
                                     )
                                     .service(
                                         web::resource("move")
-                                            .route(web::post().to(files::move_file)) // move file_name into a sibling folder
+                                            .route(web::post().to(files::move_file)) // move file_name into any folder
                                     )
                                     .service(
                                         web::resource("copy")
-                                            .route(web::post().to(files::copy_file)) // copy file_name
+                                            .route(web::post().to(files::copy_file)) // copy file_name next to itself or into any folder
                                     )
                                     .service(
                                         web::resource("remove")
@@ -109,12 +161,140 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
                     .route(web::post().to(timelapse::stop))
             )
     )
-    .route("/user", web::get().to(auth::user))
+    .service(
+        web::scope("/admin")
+            .service(
+                web::resource("users")
+                    .route(web::get().to(admin::get_users)) // list all users
+                    .route(web::post().to(admin::add_user)) // create a new user
+            )
+            .service(
+                web::scope("/users/{username}")
+                    .service(
+                        web::resource("authority")
+                            .route(web::post().to(admin::set_user_authority)) // change the authority of username
+                    )
+                    .service(
+                        web::resource("access")
+                            .route(web::post().to(admin::set_user_access)) // change the home folder and access rules of username
+                    )
+                    .service(
+                        web::resource("password")
+                            .route(web::post().to(admin::reset_user_password)) // reset the password of username
+                    )
+                    .service(
+                        web::resource("sessions/remove")
+                            .route(web::post().to(admin::logout_user)) // end every session of username
+                    )
+                    .service(
+                        web::resource("totp/remove")
+                            .route(web::post().to(admin::reset_user_totp)) // turn off two-factor authentication for username
+                    )
+                    .service(
+                        web::resource("remove")
+                            .route(web::post().to(admin::remove_user)) // delete username
+                    )
+            )
+            .service(
+                web::resource("lockouts/remove")
+                    .route(web::post().to(admin::unlock_login)) // clear the failed logins of an address or username
+            )
+            .service(
+                web::resource("audit")
+                    .route(web::get().to(admin::get_audit)) // browse the audit log of filesystem changes
+            )
+            .service(
+                web::resource("index")
+                    .route(web::get().to(admin::get_index)) // get the state of the file index
+            )
+            .service(
+                web::resource("index/rebuild")
+                    .route(web::post().to(admin::rebuild_index)) // scan every folder into the file index again
+            )
+    )
+    .service(
+        web::scope("/trash")
+            .service(
+                web::resource("")
+                    .route(web::get().to(trash::get_trash)) // list what the logged in user can restore
+            )
+            .service(
+                web::resource("purge")
+                    .route(web::post().to(trash::empty_trash)) // permanently delete everything the logged in user can restore
+            )
+            .service(
+                web::resource("{id}/restore")
+                    .route(web::post().to(trash::restore)) // put id back where it was deleted from
+            )
+            .service(
+                web::resource("{id}/purge")
+                    .route(web::post().to(trash::purge)) // permanently delete id
+            )
+    )
+    .service(
+        web::scope("/jobs")
+            .service(
+                web::resource("")
+                    .route(web::get().to(jobs::get_jobs)) // list the background jobs of the logged in user
+            )
+            .service(
+                web::resource("list")
+                    .route(web::get().to(jobs::get_job_list)) // get the jobs and their progress as json
+            )
+            .service(
+                web::resource("{id}")
+                    .route(web::get().to(jobs::get_job)) // get job id and its progress as json
+            )
+            .service(
+                web::resource("{id}/cancel")
+                    .route(web::post().to(jobs::cancel_job)) // stop job id, or drop it if it has not started
+            )
+    )
+    .service(
+        web::scope("/user")
+            .service(
+                web::resource("")
+                    .route(web::get().to(auth::user))
+            )
+            .service(
+                web::resource("password")
+                    .route(web::post().to(auth::change_password)) // change the password of the logged in user
+            )
+            .service(
+                web::resource("totp")
+                    .route(web::post().to(auth::enable_totp)) // turn on two-factor authentication for the logged in user
+            )
+            .service(
+                web::resource("totp/remove")
+                    .route(web::post().to(auth::disable_totp))
+            )
+            .service(
+                web::resource("sessions/remove")
+                    .route(web::post().to(auth::revoke_all_sessions)) // log out everywhere
+            )
+            .service(
+                web::resource("sessions/{id}/remove")
+                    .route(web::post().to(auth::revoke_session))
+            )
+            .service(
+                web::resource("tokens")
+                    .route(web::post().to(auth::create_token)) // create an API token for the logged in user
+            )
+            .service(
+                web::resource("tokens/{id}/remove")
+                    .route(web::post().to(auth::revoke_token))
+            )
+    )
     .service(
         web::resource("login")
             .route(web::get().to(auth::login))
             .route(web::post().to(auth::try_login))   
     )
+    .service(
+        web::resource("login/totp")
+            .route(web::get().to(auth::login_totp))
+            .route(web::post().to(auth::try_login_totp)) // second login step for users with two-factor authentication
+    )
     .service(
         web::resource("logout")
             .route(web::get().to(auth::logout))
@@ -0,0 +1,238 @@
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use handlebars::Handlebars;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{models::{access::{self, AccessRule}, user::{User, UserAuthority}}, util::{audit::{self, AuditFilter, Operation}, auth::{Auth, Permission}, csrf, error::{AppError, AppErrorKind}, forward::{ForwardTo, self}, index::FileIndex, sessions::{self, SessionRegistry}, throttle::{AttemptKey, LoginThrottle}}};
+
+#[derive(Deserialize)]
+pub struct NewUserFormData {
+    username: String,
+    password: String,
+    authority: UserAuthority
+}
+
+#[derive(Deserialize)]
+pub struct UserAuthorityFormData {
+    authority: UserAuthority
+}
+
+#[derive(Deserialize)]
+pub struct UserAccessFormData {
+    home: String,
+    acl: String
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordFormData {
+    password: String
+}
+
+#[derive(Deserialize)]
+pub struct UnlockFormData {
+    kind: String,
+    key: String
+}
+
+#[derive(Deserialize)]
+pub struct AuditPageQuery {
+    page: Option<usize>
+}
+
+const AUDIT_PAGE_SIZE: usize = 100;
+
+pub async fn get_users(auth: Auth, hb: web::Data<Handlebars<'_>>, throttle: web::Data<Arc<Mutex<LoginThrottle>>>, registry: web::Data<Arc<Mutex<SessionRegistry>>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let session_counts = sessions::lock(&registry).counts();
+    let users: Vec<serde_json::Value> = User::list()
+        .map_err(AppError::root)?
+        .into_iter()
+        .map(|u| {
+            let acl: Vec<String> = u.acl.iter().map(|rule| rule.to_string()).collect();
+            json!({
+                "username": u.username,
+                "authority": u.authority,
+                "home": u.home,
+                "totp_enabled": u.totp_enabled,
+                "sessions": session_counts.get(&u.username).copied().unwrap_or(0),
+                "acl": acl.join("\n")
+            })
+        })
+        .collect();
+    let lockouts = throttle.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).lockouts();
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let data = json! ({
+        "title": "Users",
+        "user": user,
+        "flashes": flashes,
+        "csrf_token": csrf::token(&auth.session()),
+        "users": users,
+        "lockouts": lockouts,
+        "authorities": [UserAuthority::Guest, UserAuthority::User, UserAuthority::Admin]
+    });
+    let body = hb.render("users", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+pub async fn add_user(form: web::Form<NewUserFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let _user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let form = form.into_inner();
+    let new_user = User::create(&form.username, &form.password, form.authority).await
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    FlashMessage::success(format!("created user '{}'", new_user.username)).send();
+    Ok(forward::to(ForwardTo::Users))
+}
+
+pub async fn set_user_authority(username: web::Path<String>, form: web::Form<UserAuthorityFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let username = username.into_inner();
+    if user.username == username {
+        return Err(AppError::new(AppErrorKind::CannotChangeOwnAuthority, ForwardTo::Users));
+    }
+    let mut edit_user = User::find(&username)
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    edit_user.authority = form.into_inner().authority;
+    edit_user.save()
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    FlashMessage::success(format!("changed authority of '{}' to {:?}", edit_user.username, edit_user.authority)).send();
+    Ok(forward::to(ForwardTo::Users))
+}
+
+pub async fn set_user_access(username: web::Path<String>, form: web::Form<UserAccessFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let _user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let mut edit_user = User::find(&username.into_inner())
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    let home = access::normalize(&form.home)
+        .ok_or_else(|| AppError::new(AppErrorKind::FolderPathInvalid, ForwardTo::Users))?;
+    edit_user.home = if home.is_empty() { None } else { Some(home) };
+    edit_user.acl = form.acl.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(AccessRule::parse)
+        .collect::<Result<Vec<AccessRule>, AppErrorKind>>()
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    edit_user.save()
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    FlashMessage::success(format!("changed access of '{}'", edit_user.username)).send();
+    Ok(forward::to(ForwardTo::Users))
+}
+
+pub async fn reset_user_password(username: web::Path<String>, form: web::Form<ResetPasswordFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let _user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let edit_user = User::find(&username.into_inner())
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    edit_user.set_password(&form.password).await
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    FlashMessage::success(format!("reset password of '{}'", edit_user.username)).send();
+    Ok(forward::to(ForwardTo::Users))
+}
+
+pub async fn remove_user(username: web::Path<String>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let username = username.into_inner();
+    if user.username == username {
+        return Err(AppError::new(AppErrorKind::CannotDeleteSelf, ForwardTo::Users));
+    }
+    let remove_user = User::find(&username)
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    remove_user.delete()
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    FlashMessage::success(format!("removed user '{}'", remove_user.username)).send();
+    Ok(forward::to(ForwardTo::Users))
+}
+
+pub async fn unlock_login(form: web::Form<UnlockFormData>, throttle: web::Data<Arc<Mutex<LoginThrottle>>>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let form = form.into_inner();
+    let key = match form.kind.as_str() {
+        "ip" => AttemptKey::Ip(form.key),
+        _ => AttemptKey::Username(form.key)
+    };
+    throttle.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).unlock(&key);
+    log::info!("user '{}' unlocked login for {:?}", user.username, key);
+    FlashMessage::success("unlocked login").send();
+    Ok(forward::to(ForwardTo::Users))
+}
+
+/// For users who lost their authenticator and their recovery codes
+pub async fn reset_user_totp(username: web::Path<String>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let edit_user = User::find(&username.into_inner())
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    edit_user.disable_totp()
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    log::warn!("user '{}' reset two-factor authentication of '{}'", user.username, edit_user.username);
+    FlashMessage::success(format!("reset two-factor authentication of '{}'", edit_user.username)).send();
+    Ok(forward::to(ForwardTo::Users))
+}
+
+pub async fn logout_user(username: web::Path<String>, auth: Auth, registry: web::Data<Arc<Mutex<SessionRegistry>>>) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Users))?;
+    let username = username.into_inner();
+    let count = sessions::lock(&registry).revoke_all(&username);
+    log::warn!("user '{}' logged out {} sessions of '{}'", user.username, count, username);
+    FlashMessage::success(format!("logged out {} sessions of '{}'", count, username)).send();
+    Ok(forward::to(ForwardTo::Users))
+}
+
+pub async fn get_audit(filter: web::Query<AuditFilter>, query: web::Query<AuditPageQuery>, auth: Auth, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Audit))?;
+    let page = query.page.unwrap_or(1).max(1);
+    let (entries, more) = audit::read(&filter, (page - 1) * AUDIT_PAGE_SIZE, AUDIT_PAGE_SIZE)
+        .map_err(|k| AppError::new(k, ForwardTo::Users))?;
+    let entries: Vec<serde_json::Value> = entries.iter().map(|entry| entry.to_json()).collect();
+    let filter_query = serde_urlencoded::to_string(&*filter).unwrap_or_default();
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let data = json! ({
+        "title": "Audit log",
+        "user": user,
+        "flashes": flashes,
+        "entries": entries,
+        "filter": *filter,
+        "filter_query": filter_query,
+        "operations": Operation::ALL,
+        "page": page,
+        "previous_page": if page > 1 { Some(page - 1) } else { None },
+        "next_page": if more { Some(page + 1) } else { None }
+    });
+    let body = hb.render("audit", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+pub async fn get_index(auth: Auth, hb: web::Data<Handlebars<'_>>, index: web::Data<Arc<Mutex<FileIndex>>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Index))?;
+    let status = index.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).status();
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let data = json! ({
+        "title": "Index",
+        "user": user,
+        "flashes": flashes,
+        "csrf_token": csrf::token(&auth.session()),
+        "index": status
+    });
+    let body = hb.render("index-status", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// Scans every folder again, for changes the index missed
+pub async fn rebuild_index(auth: Auth, index: web::Data<Arc<Mutex<FileIndex>>>) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::ManageUsers)
+        .map_err(|k| AppError::page(k, ForwardTo::Index))?;
+    index.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).request_rebuild();
+    log::info!("user '{}' requested an index rebuild", user.username);
+    FlashMessage::success("rebuilding the index").send();
+    Ok(forward::to(ForwardTo::Index))
+}
@@ -1,11 +1,14 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
 use actix_session::Session;
-use actix_web::{HttpResponse, web};
+use actix_web::{HttpRequest, HttpResponse, web};
 use actix_web_flash_messages::{IncomingFlashMessages, FlashMessage};
 use handlebars::Handlebars;
 use serde::{Serialize, Deserialize};
 use serde_json::json;
+use time::OffsetDateTime;
 
-use crate::{models::user::User, util::{error::AppError, forward::{ForwardTo, self}}};
+use crate::{models::{access, folder::Folder, token::TokenScope, totp, user::User}, util::{auth::Auth, csrf, error::{AppError, AppErrorKind}, forward::{ForwardTo, self}, sessions::{self, SessionRegistry}, throttle::LoginThrottle}};
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Login {
@@ -13,48 +16,318 @@ pub struct Login {
     password: String
 }
 
-pub async fn user(session: Session, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
-    let user = User::get(session)
+#[derive(Deserialize)]
+pub struct ChangePasswordFormData {
+    current_password: String,
+    new_password: String,
+    confirm_password: String
+}
+
+#[derive(Deserialize)]
+pub struct TotpFormData {
+    code: String
+}
+
+#[derive(Deserialize)]
+pub struct DisableTotpFormData {
+    password: String,
+    code: String
+}
+
+/// A login that passed the password check and is waiting for the second factor
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    username: String,
+    started: i64
+}
+
+const PENDING_LOGIN_KEY: &str = "pending_login";
+const PENDING_LOGIN_SECS: i64 = 5 * 60;
+/// Secret shown on the user page until the user confirms a code from it
+const TOTP_SETUP_KEY: &str = "totp_setup";
+
+#[derive(Deserialize)]
+pub struct NewTokenFormData {
+    name: String,
+    folder: String,
+    expires_days: String,
+    read_only: Option<String>
+}
+
+pub async fn user(auth: Auth, hb: web::Data<Handlebars<'_>>, registry: web::Data<Arc<Mutex<SessionRegistry>>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let user = auth.user()
         .map_err(AppError::login)?;
     let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
-    let data = json! ({
+    render_user(&user, &auth, hb, &registry, flashes, json!({}))
+}
+
+/// New tokens and recovery codes are passed in extra and shown on the page
+/// once since only their hashes are kept
+fn render_user(user: &User, auth: &Auth, hb: web::Data<Handlebars<'_>>, registry: &Mutex<SessionRegistry>, flashes: Vec<(String,String)>, extra: serde_json::Value) -> Result<HttpResponse, AppError> {
+    let tokens: Vec<serde_json::Value> = user.tokens()
+        .map_err(AppError::root)?
+        .iter()
+        .map(|token| token.to_json())
+        .collect();
+    let totp_setup = if user.totp_enabled || auth.session_user().is_err() {
+        None
+    }
+    else {
+        let session = auth.session();
+        let secret = match session.get::<String>(TOTP_SETUP_KEY) {
+            Ok(Some(secret)) => secret,
+            _ => {
+                let secret = totp::generate_secret();
+                session.insert(TOTP_SETUP_KEY, &secret)
+                    .map_err(|err| AppError::new(AppErrorKind::Session(err.to_string(), None), ForwardTo::User))?;
+                secret
+            }
+        };
+        let url = totp::otpauth_url(&user.username, &secret);
+        Some(json!({ "secret": secret, "qr": totp::qr_svg(&url) }))
+    };
+    let mut data = json! ({
         "title": "FS",
         "flashes": flashes,
-        "user": user
+        "csrf_token": csrf::token(&auth.session()),
+        "user": user,
+        "tokens": tokens,
+        "totp_enabled": user.totp_enabled,
+        "totp_setup": totp_setup,
+        "sessions": sessions::lock(registry).sessions_of(&user.username),
+        "current_session": sessions::current_id(&auth.session())
     });
+    if let (Some(data), Some(extra)) = (data.as_object_mut(), extra.as_object()) {
+        data.extend(extra.clone());
+    }
     let body = hb.render("user", &data).unwrap();
     Ok(HttpResponse::Ok().body(body))
 }
 
 pub async fn login(session: Session, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let csrf_token = csrf::token(&session);
     let user = User::get(session).ok();
     let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
     let data = json! ({
         "title": "FS",
         "flashes": flashes,
+        "csrf_token": csrf_token,
         "user": user
     });
     let body = hb.render("login", &data).unwrap();
     Ok(HttpResponse::Ok().body(body))
 }
 
-pub async fn try_login(login: web::Form<Login>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn try_login(req: HttpRequest, login: web::Form<Login>, session: Session, throttle: web::Data<Arc<Mutex<LoginThrottle>>>) -> Result<HttpResponse, AppError> {
     let login = login.into_inner();
-    let user = User::fetch(&login.username, &login.password).await
+    let ip = client_ip(&req);
+    lock(&throttle)
+        .check(&ip, &login.username)
+        .map_err(|k| {
+            log::warn!("locked out login for user '{}' from {}", login.username, ip);
+            AppError::login(k)
+        })?;
+    let user = match User::fetch(&login.username, &login.password).await {
+        Ok(user) => user,
+        Err(AppErrorKind::InvalidUserCredentials) => {
+            log::warn!("failed login for user '{}' from {}", login.username, ip);
+            lock(&throttle).record_failure(&ip, &login.username);
+            return Err(AppError::login(AppErrorKind::InvalidUserCredentials));
+        },
+        Err(err) => return Err(AppError::login(err))
+    };
+    if user.totp_enabled {
+        let pending = PendingLogin { username: user.username, started: OffsetDateTime::now_utc().unix_timestamp() };
+        session.insert(PENDING_LOGIN_KEY, pending)
+            .map_err(|err| AppError::login(AppErrorKind::Session(err.to_string(), None)))?;
+        return Ok(forward::to(ForwardTo::LoginTotp));
+    }
+    lock(&throttle).record_success(&user.username);
+    finish_login(user, session, &req)
+}
+
+pub async fn login_totp(session: Session, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    if !matches!(session.get::<PendingLogin>(PENDING_LOGIN_KEY), Ok(Some(_))) {
+        return Ok(forward::to(ForwardTo::Login));
+    }
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let data = json! ({
+        "title": "FS",
+        "flashes": flashes,
+        "csrf_token": csrf::token(&session)
+    });
+    let body = hb.render("login_totp", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// Second login step for users with TOTP. Wrong codes count as failed logins
+pub async fn try_login_totp(req: HttpRequest, form: web::Form<TotpFormData>, session: Session, throttle: web::Data<Arc<Mutex<LoginThrottle>>>) -> Result<HttpResponse, AppError> {
+    let pending = match session.get::<PendingLogin>(PENDING_LOGIN_KEY) {
+        Ok(Some(pending)) => pending,
+        _ => return Err(AppError::login(AppErrorKind::TotpLoginExpired))
+    };
+    if OffsetDateTime::now_utc().unix_timestamp() - pending.started > PENDING_LOGIN_SECS {
+        session.remove(PENDING_LOGIN_KEY);
+        return Err(AppError::login(AppErrorKind::TotpLoginExpired));
+    }
+    let ip = client_ip(&req);
+    lock(&throttle)
+        .check(&ip, &pending.username)
+        .map_err(|k| {
+            log::warn!("locked out two-factor login for user '{}' from {}", pending.username, ip);
+            AppError::new(k, ForwardTo::LoginTotp)
+        })?;
+    let user = User::find(&pending.username)
         .map_err(AppError::login)?;
+    match user.verify_second_factor(&form.code) {
+        Ok(recovery_codes_left) => {
+            if let Some(left) = recovery_codes_left {
+                log::warn!("user '{}' logged in with a recovery code, {} left", user.username, left);
+                FlashMessage::warning(format!("used a recovery code, {} left", left)).send();
+            }
+        },
+        Err(AppErrorKind::TotpCodeInvalid) => {
+            log::warn!("failed two-factor login for user '{}' from {}", user.username, ip);
+            lock(&throttle).record_failure(&ip, &user.username);
+            return Err(AppError::new(AppErrorKind::TotpCodeInvalid, ForwardTo::LoginTotp));
+        },
+        Err(err) => return Err(AppError::login(err))
+    }
+    session.remove(PENDING_LOGIN_KEY);
+    lock(&throttle).record_success(&user.username);
+    finish_login(user, session, &req)
+}
+
+fn finish_login(user: User, session: Session, req: &HttpRequest) -> Result<HttpResponse, AppError> {
+    let home = user.home.as_deref()
+        .and_then(|home| Folder::from_relative_path(home).ok())
+        .map_or(ForwardTo::Root, ForwardTo::Folder);
     let forward = session.get("redirect")
-        .unwrap_or_else(|_| {Some(forward::location(&ForwardTo::Root))})
-        .unwrap_or_else(|| forward::location(&ForwardTo::Root));
+        .unwrap_or_else(|_| {Some(forward::location(&home))})
+        .unwrap_or_else(|| forward::location(&home));
     session.remove("redirect");
+    sessions::start(&session, req)
+        .map_err(AppError::login)?;
     user.insert(session)
         .map_err(AppError::login)?;
     FlashMessage::success("logged in successfully").send();
     Ok(forward::to_string(&forward))
 }
 
+fn client_ip(req: &HttpRequest) -> String {
+    req.peer_addr().map_or_else(|| String::from("unknown"), |addr| addr.ip().to_string())
+}
+
+/// A poisoned lock still holds valid counts so keep using it rather than letting everyone in
+fn lock(throttle: &Mutex<LoginThrottle>) -> MutexGuard<'_, LoginThrottle> {
+    throttle.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 pub async fn logout(session: Session) -> Result<HttpResponse, AppError> {
-    User::remove(session)
+    User::remove(session.clone())
         .map_err(AppError::login)?;
+    session.purge();
     FlashMessage::success("logged out successfully").send();
     Ok(forward::to(ForwardTo::Login))
-}
\ No newline at end of file
+}
+
+pub async fn change_password(form: web::Form<ChangePasswordFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.session_user()
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    let form = form.into_inner();
+    if form.new_password != form.confirm_password {
+        return Err(AppError::new(AppErrorKind::PasswordsDoNotMatch, ForwardTo::User));
+    }
+    let user = User::fetch(&user.username, &form.current_password).await
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    user.set_password(&form.new_password).await
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    FlashMessage::success("changed password").send();
+    Ok(forward::to(ForwardTo::User))
+}
+
+pub async fn create_token(form: web::Form<NewTokenFormData>, auth: Auth, hb: web::Data<Handlebars<'_>>, registry: web::Data<Arc<Mutex<SessionRegistry>>>) -> Result<HttpResponse, AppError> {
+    let user = auth.session_user()
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    let form = form.into_inner();
+    let folder = access::normalize(&form.folder)
+        .ok_or_else(|| AppError::new(AppErrorKind::FolderPathInvalid, ForwardTo::User))?;
+    let scope = TokenScope {
+        read_only: form.read_only.is_some(),
+        folder: if folder.is_empty() { None } else { Some(folder) }
+    };
+    let expires_days = match form.expires_days.trim() {
+        "" => None,
+        days => Some(days.parse::<i64>().ok().filter(|days| *days > 0)
+            .ok_or_else(|| AppError::new(AppErrorKind::TokenExpiryInvalid, ForwardTo::User))?)
+    };
+    let name = form.name.trim();
+    let token = user.create_token(if name.is_empty() { "unnamed" } else { name }, scope, expires_days)
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    log::info!("user '{}' created API token '{}'", user.username, name);
+    let flashes = vec![(String::from("success"), String::from("created API token, copy it now because it will not be shown again"))];
+    render_user(&user, &auth, hb, &registry, flashes, json!({ "new_token": token }))
+}
+
+pub async fn revoke_token(id: web::Path<String>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.session_user()
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    user.revoke_token(&id)
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    FlashMessage::success("revoked API token").send();
+    Ok(forward::to(ForwardTo::User))
+}
+
+pub async fn enable_totp(form: web::Form<TotpFormData>, auth: Auth, hb: web::Data<Handlebars<'_>>, registry: web::Data<Arc<Mutex<SessionRegistry>>>) -> Result<HttpResponse, AppError> {
+    let mut user = auth.session_user()
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    let session = auth.session();
+    let secret = match session.get::<String>(TOTP_SETUP_KEY) {
+        Ok(Some(secret)) => secret,
+        _ => return Err(AppError::new(AppErrorKind::TotpCodeInvalid, ForwardTo::User))
+    };
+    if totp::verify(&secret, &form.code, 0).is_none() {
+        return Err(AppError::new(AppErrorKind::TotpCodeInvalid, ForwardTo::User));
+    }
+    let recovery_codes = user.enable_totp(secret)
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    session.remove(TOTP_SETUP_KEY);
+    user.totp_enabled = true;
+    log::info!("user '{}' enabled two-factor authentication", user.username);
+    let flashes = vec![(String::from("success"), String::from("enabled two-factor authentication, save the recovery codes now because they will not be shown again"))];
+    render_user(&user, &auth, hb, &registry, flashes, json!({ "recovery_codes": recovery_codes }))
+}
+
+pub async fn disable_totp(form: web::Form<DisableTotpFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.session_user()
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    let user = User::fetch(&user.username, &form.password).await
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    user.verify_second_factor(&form.code)
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    user.disable_totp()
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    log::warn!("user '{}' disabled two-factor authentication", user.username);
+    FlashMessage::success("disabled two-factor authentication").send();
+    Ok(forward::to(ForwardTo::User))
+}
+
+pub async fn revoke_session(id: web::Path<String>, auth: Auth, registry: web::Data<Arc<Mutex<SessionRegistry>>>) -> Result<HttpResponse, AppError> {
+    let user = auth.session_user()
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    sessions::lock(&registry).revoke(&user.username, &id)
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    FlashMessage::success("logged out session").send();
+    Ok(forward::to(ForwardTo::User))
+}
+
+/// Ends every session of the user including this one
+pub async fn revoke_all_sessions(auth: Auth, registry: web::Data<Arc<Mutex<SessionRegistry>>>) -> Result<HttpResponse, AppError> {
+    let user = auth.session_user()
+        .map_err(|k| AppError::new(k, ForwardTo::User))?;
+    let count = sessions::lock(&registry).revoke_all(&user.username);
+    auth.session().purge();
+    log::info!("user '{}' logged out {} sessions everywhere", user.username, count);
+    FlashMessage::success(format!("logged out {} sessions everywhere", count)).send();
+    Ok(forward::to(ForwardTo::Login))
+}
@@ -1,33 +1,151 @@
+use std::sync::{Arc, Mutex};
+
+use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_session::Session;
-use actix_web::{web, HttpResponse, 
+use actix_web::{web, HttpRequest, HttpResponse, 
     http::{self, header::{ContentDisposition, DispositionType, DispositionParam}}
 };
 use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use futures_util::stream;
 use serde::Deserialize;
 use serde_json::json;
 use handlebars::Handlebars;
 
-use crate::{models::{folder::Folder, user::User}, util::{error::{AppError, AppErrorKind}, forward::ForwardTo}};
+use crate::{models::{conflict::{ConflictPolicy, ItemOutcome, Outcome}, folder::{url_name, Folder}, listing::{Page, Sort, SortKey, SortOrder, SORT_SESSION_KEY}, rename::{self, PlannedRename, RenamePattern}}, util::{audit::{self, Operation}, auth::{Auth, Permission}, csrf, error::{AppError, AppErrorKind}, forward::ForwardTo, jobs::{self, Job, JobQueue}, live::{self, FolderFeed, LiveUpdates}, sessions::SessionRegistry}};
 use crate::util::forward;
 
-const PARENT_OPTION: &str = "|Move to parent folder|";
+/// Past this many items of one upload, unzip or move the rest are only counted, so the
+/// messages still fit in the session cookie
+const OUTCOME_MESSAGE_LIMIT: usize = 10;
 
 #[derive(Deserialize)]
 pub struct RenameFileFormData {
     file_name: String
 }
 
+/// The folder to move to, see Folder::move_destination
 #[derive(Deserialize)]
 pub struct MoveFileIntoFormData {
-    folder_name: String
+    #[serde(default)]
+    folder_name: String,
+    #[serde(default)]
+    destination: String,
+    #[serde(default)]
+    conflict: ConflictPolicy
 }
 
 #[derive(Deserialize)]
 pub struct MoveEntitiesIntoFormData {
     selected_folders: String,
     selected_files: String,
-    folder_name: String
+    #[serde(default)]
+    folder_name: String,
+    #[serde(default)]
+    destination: String,
+    #[serde(default)]
+    conflict: ConflictPolicy
+}
+
+/// Without a destination the copy is made in the same folder
+#[derive(Deserialize, Default)]
+pub struct CopyFormData {
+    #[serde(default)]
+    destination: String,
+    #[serde(default)]
+    conflict: ConflictPolicy
+}
+
+#[derive(Deserialize)]
+pub struct CopyEntitiesFormData {
+    selected_folders: String,
+    selected_files: String,
+    #[serde(default)]
+    destination: String,
+    #[serde(default)]
+    conflict: ConflictPolicy
+}
+
+/// Without an archive name the archive is named after the one entry selected, or the folder
+#[derive(Deserialize)]
+pub struct ZipEntitiesFormData {
+    selected_folders: String,
+    selected_files: String,
+    #[serde(default)]
+    archive_name: String,
+    #[serde(default)]
+    conflict: ConflictPolicy
+}
+
+#[derive(Deserialize)]
+pub struct DownloadEntitiesFormData {
+    selected_folders: String,
+    selected_files: String
+}
+
+/// The selection with the fields of a RenamePattern, sent again unchanged to apply a preview
+#[derive(Deserialize)]
+pub struct RenameEntitiesFormData {
+    selected_folders: String,
+    selected_files: String,
+    #[serde(default)]
+    find: String,
+    #[serde(default)]
+    replace: String,
+    #[serde(default)]
+    regex: bool,
+    #[serde(default)]
+    date_prefix: bool,
+    #[serde(default)]
+    numbering: bool,
+    start: Option<String>,
+    digits: Option<String>
+}
+
+impl RenameEntitiesFormData {
+    fn pattern(&self) -> RenamePattern {
+        RenamePattern {
+            find: self.find.clone(),
+            replace: self.replace.clone(),
+            regex: self.regex,
+            date_prefix: self.date_prefix,
+            numbering: self.numbering,
+            start: self.start.clone(),
+            digits: self.digits.as_deref().and_then(|digits| digits.trim().parse().ok())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UnzipFileFormData {
+    #[serde(default)]
+    conflict: ConflictPolicy
+}
+
+/// Changes the sort of the files view and picks a page of it, all are optional.
+/// A page starts after the entry named by after if it is given, otherwise at offset
+#[derive(Deserialize)]
+pub struct ListQuery {
+    sort: Option<SortKey>,
+    order: Option<SortOrder>,
+    offset: Option<usize>,
+    after: Option<String>,
+    limit: Option<usize>
+}
+
+impl ListQuery {
+    fn page(&self) -> Page {
+        let default = Page::default();
+        Page {
+            offset: self.offset.unwrap_or(default.offset),
+            after: self.after.clone(),
+            limit: self.limit.filter(|limit| *limit > 0).unwrap_or(default.limit)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ShowHiddenFormData {
+    show_hidden: bool
 }
 
 #[derive(Deserialize)]
@@ -36,93 +154,212 @@ pub struct RemoveEntitiesFormData {
     selected_files: String
 }
 
-pub async fn get_files(folder_path: web::Path<String>, session: Session, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+pub async fn get_files(folder_path: web::Path<String>, query: web::Query<ListQuery>, auth: Auth, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
-    let (folders, files) = folder.entity_list(false)
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    let sort = sort(&auth, &query);
+    let listing = folder.entity_list(false, &user.access_list(), user.show_hidden, sort, &query.page())
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.parent().unwrap_or_default())))?;
     let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
-    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.to_string(), a.name().to_owned())}).collect();
+    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.url(), a.name().to_owned())}).collect();
     let data = json! ({
         "title": "FS",
         "user": user,
         "flashes": flashes,
-        "folder_path": folder.to_string(),
+        "csrf_token": csrf::token(&auth.session()),
+        "folder_path": folder.url(),
         "crumbs": crumbs,
-        "entity_count": folders.len() + files.len(),
-        "folders": folders,
-        "files": files,
-        "parent_option": PARENT_OPTION.clone()
+        "entity_count": listing.len(),
+        "folders": listing.folders_json(),
+        "files": listing.files_json(),
+        "pages": listing.pages_json(),
+        "columns": sort.columns()
     });
     let body = hb.render("files", &data).unwrap();
     Ok(HttpResponse::Ok().body(body))
 }
 
-pub async fn get_file_detail(path: web::Path<(String,String)>, session: Session, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+/// The sort asked for in the query, which is remembered in the session,
+/// otherwise the one last chosen in this session
+fn sort(auth: &Auth, query: &ListQuery) -> Sort {
+    let session = auth.session();
+    let mut sort = session.get::<Sort>(SORT_SESSION_KEY).ok().flatten().unwrap_or_default();
+    if query.sort.is_none() && query.order.is_none() {
+        return sort;
+    }
+    sort.key = query.sort.unwrap_or(sort.key);
+    sort.order = query.order.unwrap_or(sort.order);
+    if let Err(err) = session.insert(SORT_SESSION_KEY, sort) {
+        log::error!("could not store sort in session: {}", err);
+    }
+    sort
+}
+
+/// The same page of entries as the files view as JSON, for scripts
+pub async fn get_file_list(folder_path: web::Path<String>, query: web::Query<ListQuery>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    let sort = sort(&auth, &query);
+    let listing = folder.entity_list(false, &user.access_list(), user.show_hidden, sort, &query.page())
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.parent().unwrap_or_default())))?;
+    Ok(HttpResponse::Ok().json(json!({
+        "folder": folder.relative_path(),
+        "sort": sort,
+        "folders": listing.folders_json(),
+        "files": listing.files_json(),
+        "total": listing.total,
+        "offset": listing.offset,
+        "next": listing.next()
+    })))
+}
+
+/// Streams what happens in folder_path as server-sent events, so the files view shows entries
+/// added, changed, removed and renamed by anyone, and the progress of the user's jobs, without
+/// reloading. A browser that reconnects picks up after the last change it got
+pub async fn get_file_events(folder_path: web::Path<String>, req: HttpRequest, auth: Auth, live: web::Data<Arc<Mutex<LiveUpdates>>>, queue: web::Data<Arc<Mutex<JobQueue>>>, registry: web::Data<Arc<Mutex<SessionRegistry>>>) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    let last_event_id = req.headers().get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let login = auth.login()
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    let (live, queue) = (live.get_ref().clone(), queue.get_ref().clone());
+    let feed = FolderFeed::new(folder.clone(), user, last_event_id, &live, &queue);
+    let state = (feed, login, folder, live, queue, registry.get_ref().clone());
+    let events = stream::unfold(state, |(mut feed, login, folder, live, queue, registry)| async move {
+        loop {
+            if feed.recheck_due() {
+                // logged out, revoked or no longer allowed in the folder, the reconnect is then refused too
+                match login.require_in(Permission::Read, &folder, &registry) {
+                    Ok(user) => feed.set_user(user),
+                    Err(_) => return None
+                }
+            }
+            let events = feed.poll(&live, &queue);
+            if !events.is_empty() {
+                return Some((Ok::<web::Bytes, actix_web::Error>(web::Bytes::from(events)), (feed, login, folder, live, queue, registry)));
+            }
+            actix_web::rt::time::sleep(live::POLL_INTERVAL).await;
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((http::header::CACHE_CONTROL, "no-cache"))
+        .streaming(events))
+}
+
+pub async fn set_show_hidden(folder_path: web::Path<String>, form: web::Form<ShowHiddenFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let mut user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    user.show_hidden = form.show_hidden;
+    user.save()
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    Ok(forward::to(ForwardTo::Folder(folder)))
+}
+
+pub async fn get_file_detail(path: web::Path<(String,String)>, auth: Auth, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
     let (folder_path, file_name) = path.into_inner();
     let folder = Folder::new(&folder_path)
         .map_err(AppError::root)?;
-    let user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
+    let user = auth.require_in(Permission::Read, &folder.join(&file_name).map_err(AppError::root)?)
+        .map_err(|k| AppError::page(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
     let details = folder.file_details(&file_name)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
     let is_image = folder.is_file_image(&file_name)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
     let content_path = folder.file_content_path(&file_name)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
-    let folders = folder.entity_list(true)
-        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?.0;
+    let text = folder.read_text(&file_name).await
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let lines: Option<Vec<serde_json::Value>> = text.map(|text| {
+        text.lines().enumerate().map(|(index, line)| json!({ "number": index + 1, "text": line })).collect()
+    });
     let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
-    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.to_string(), a.name().to_owned())}).collect();
+    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.url(), a.name().to_owned())}).collect();
     let data = json! ({
         "title": "FS",
         "user": user,
         "flashes": flashes,
-        "folder_path": folder.to_string(),
+        "csrf_token": csrf::token(&auth.session()),
+        "folder_path": folder.url(),
         "crumbs": crumbs,
+        "file_url_name": url_name(&file_name),
         "file_name": file_name,
         "details": details,
         "is_image": is_image,
         "content_path": content_path,
-        "folders": folders,
-        "parent_option": PARENT_OPTION.clone()
+        "lines": lines
     });
     let body = hb.render("file-detail", &data).unwrap();
     Ok(HttpResponse::Ok().body(body))
 }
 
-pub async fn get_file_content(path: web::Path<(String,String)>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn get_file_content(path: web::Path<(String,String)>, auth: Auth) -> Result<HttpResponse, AppError> {
     let (folder_path, file_name) = path.into_inner();
     let folder = Folder::new(&folder_path)
         .map_err(AppError::root)?;
-    let _user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
+    let _user = auth.require_in(Permission::Read, &folder.join(&file_name).map_err(AppError::root)?)
+        .map_err(|k| AppError::page(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
     let content = folder.read_file(&file_name).await
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
     Ok(HttpResponse::Ok().body(content))
 }
 
-pub async fn upload_file(folder_path: web::Path<String>, payload: Multipart, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn upload_file(folder_path: web::Path<String>, payload: Multipart, auth: Auth) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let user = auth.require_in(Permission::Write, &folder)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
-    match folder.upload_file(payload).await {
-        Ok(file_names) if file_names.len() == 1 => FlashMessage::success(format!("uploaded file '{}'", file_names[0])).send(),
-        Ok(file_names) if file_names.len() > 1 => FlashMessage::success(format!("uploaded {} files", file_names.len())).send(),
-        Ok(_) => FlashMessage::error("no files were uploaded").send(),
-        Err(e) => FlashMessage::error(e.to_string()).send()
+    let result = folder.upload_file(payload, &user.username).await;
+    match &result {
+        Ok(outcomes) => for item in outcomes {
+            match &item.outcome {
+                Outcome::Skipped => (),
+                Outcome::Failed(err) => {
+                    let _ = auth.audit(Operation::Upload, audit::path(&folder, &item.name), None, Err::<(), _>(err));
+                },
+                outcome => {
+                    let name = outcome.written_name(&item.name).unwrap_or(&item.name);
+                    let _ = auth.audit(Operation::Upload, audit::path(&folder, name), None, Ok::<(), AppErrorKind>(()));
+                }
+            }
+        },
+        Err(_) => {
+            let _ = auth.audit(Operation::Upload, folder.relative_path(), None, result.as_ref().map(|_| ()));
+        }
     }
+    let outcomes = match result {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            FlashMessage::error(e.to_string()).send();
+            return Ok(forward::to(ForwardTo::Folder(folder)));
+        }
+    };
+    let uploaded: Vec<&ItemOutcome> = outcomes.iter().filter(|item| item.outcome.is_written()).collect();
+    match uploaded.len() {
+        0 if outcomes.is_empty() => FlashMessage::error("no files were uploaded").send(),
+        0 => (),
+        1 => FlashMessage::success(format!("uploaded file '{}'", uploaded[0].outcome.written_name(&uploaded[0].name).unwrap_or_default())).send(),
+        count => FlashMessage::success(format!("uploaded {} files", count)).send()
+    }
+    send_outcomes(&outcomes);
     Ok(forward::to(ForwardTo::Folder(folder)))
 }
 
-pub async fn download_file(path: web::Path<(String,String)>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn download_file(path: web::Path<(String,String)>, auth: Auth) -> Result<HttpResponse, AppError> {
     let (folder_path, file_name) = path.into_inner();
     let folder = Folder::new(&folder_path)
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let _user = auth.require_in(Permission::Download, &folder.join(&file_name).map_err(AppError::root)?)
         .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
     let file_content = folder.read_file(&file_name).await
         .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder, file_name.clone())))?;
@@ -133,168 +370,353 @@ pub async fn download_file(path: web::Path<(String,String)>, session: Session) -
     Ok(HttpResponse::Ok().append_header((http::header::CONTENT_DISPOSITION, content_disposition)).body(file_content))
 }
 
-pub async fn rename_file(path: web::Path<(String,String)>, form: web::Form<RenameFileFormData>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn rename_file(path: web::Path<(String,String)>, form: web::Form<RenameFileFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
     let (folder_path, file_name) = path.into_inner();
     let folder = Folder::new(&folder_path)
         .map_err(AppError::root)?;
-    let _user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    folder.rename_file(&file_name, &form.file_name)
+    for file in [&file_name, &form.file_name] {
+        auth.require_in(Permission::Write, &folder.join(file).map_err(AppError::root)?)
+            .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
+    }
+    auth.audit(Operation::Rename, audit::path(&folder, &file_name), Some(audit::path(&folder, &form.file_name)), folder.rename_file(&file_name, &form.file_name))
         .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
     FlashMessage::success(format!("renamed file '{}' to '{}'", &file_name, &form.file_name)).send();
     Ok(forward::to(ForwardTo::FileDetail(folder, form.file_name.clone())))
 }
 
-pub async fn copy_file(path: web::Path<(String,String)>, session: Session) -> Result<HttpResponse, AppError> {
+/// The form is optional so scripts can still copy in place with an empty request.
+/// The copy is made by a background job
+pub async fn copy_file(path: web::Path<(String,String)>, form: Option<web::Form<CopyFormData>>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
     let (folder_path, file_name) = path.into_inner();
     let folder = Folder::new(&folder_path)
         .map_err(AppError::root)?;
-    let _user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    let new_file = folder.copy_file(&file_name)
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    FlashMessage::success(format!("copied file '{}' to '{}'", &file_name, &new_file.name())).send();
-    Ok(forward::to(ForwardTo::Folder(folder)))
-}
-
-pub async fn move_file(path: web::Path<(String,String)>, form: web::Form<MoveFileIntoFormData>, session: Session) -> Result<HttpResponse, AppError> {
-    if form.folder_name == PARENT_OPTION {
-        return move_file_up(path, session).await;
-    }
-    let (folder_path, file_name) = path.into_inner();
-    let folder = Folder::new(&folder_path)
-        .map_err(AppError::root)?;
-    let _user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    let child_folder = folder.join(&form.folder_name)
+    let form = form.map(web::Form::into_inner).unwrap_or_default();
+    let new_folder = match form.destination.is_empty() {
+        true => folder.clone(),
+        false => Folder::new(&form.destination)
+            .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?
+    };
+    let user = auth.require_in(Permission::Read, &folder.join(&file_name).map_err(AppError::root)?)
+        .and_then(|_| auth.require_in(Permission::Write, &new_folder))
         .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    folder.move_entity(&file_name, &child_folder)
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder, file_name.clone())))?;
-    FlashMessage::success(format!("moved file '{}' to '{}'", &file_name, child_folder.name())).send();
-    Ok(forward::to(ForwardTo::FileDetail(child_folder, file_name)))
+    let job = Job::new(Operation::Copy, &user.username, audit::path(&folder, &file_name), Some(new_folder.relative_path()), new_folder.clone());
+    let task = jobs::copy_task(auth.auditor(), folder.clone(), vec![file_name.clone()], new_folder.clone(), form.conflict);
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, task);
+    FlashMessage::info(format!("copying file '{}' into '{}', see jobs for progress", &file_name, new_folder.name())).send();
+    Ok(forward::to(ForwardTo::Folder(new_folder)))
 }
 
-pub async fn move_file_up(path: web::Path<(String,String)>, session: Session) -> Result<HttpResponse, AppError> {
+/// The file is moved by a background job, since a move to another drive copies it
+pub async fn move_file(path: web::Path<(String,String)>, form: web::Form<MoveFileIntoFormData>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
     let (folder_path, file_name) = path.into_inner();
     let folder = Folder::new(&folder_path)
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let new_folder = folder.move_destination(&form.folder_name, &form.destination)
         .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    if folder.is_root() {
-        return Err(AppError::new(AppErrorKind::CannotDeleteRoot, ForwardTo::FileDetail(folder, file_name)));
+    if new_folder == folder {
+        return Err(AppError::new(AppErrorKind::AlreadyInFolder, ForwardTo::FileDetail(folder, file_name)));
     }
-    let parent = folder.parent().unwrap_or_default();
-    folder.move_entity(&file_name, &parent)
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder, file_name.clone())))?;
-    FlashMessage::success(format!("moved file '{}' up a folder", file_name)).send();
-    Ok(forward::to(ForwardTo::FileDetail(parent, file_name)))
+    let user = auth.require_in(Permission::Write, &folder.join(&file_name).map_err(AppError::root)?)
+        .and_then(|_| auth.require_in(Permission::Write, &new_folder))
+        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
+    let job = Job::new(Operation::Move, &user.username, audit::path(&folder, &file_name), Some(new_folder.relative_path()), new_folder.clone());
+    let task = jobs::move_task(auth.auditor(), folder, vec![file_name.clone()], new_folder.clone(), form.conflict);
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, task);
+    FlashMessage::info(format!("moving file '{}' into '{}', see jobs for progress", &file_name, new_folder.name())).send();
+    Ok(forward::to(ForwardTo::Folder(new_folder)))
 }
 
-pub async fn move_entities(folder_path: web::Path<String>, form: web::Form<MoveEntitiesIntoFormData>, session: Session) -> Result<HttpResponse, AppError> {
-    if form.folder_name == PARENT_OPTION {
-        return move_entities_up(folder_path, form, session).await;
-    }
+/// Moves the selected files and folders into the destination with a background job
+pub async fn move_entities(folder_path: web::Path<String>, form: web::Form<MoveEntitiesIntoFormData>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let new_folder = folder.move_destination(&form.folder_name, &form.destination)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
-    let new_folder = folder.join(&form.folder_name)
+    if new_folder == folder {
+        return Err(AppError::new(AppErrorKind::AlreadyInFolder, ForwardTo::Folder(folder)));
+    }
+    let user = auth.require_in(Permission::Write, &new_folder)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
-    let selected_entities = form.selected_folders.split("/").chain(form.selected_files.split("/"))
-        .filter_map(|e| { if e.len() == 0 { None } else { folder.join(e).ok() }});
-    let mut count = 0;
+    let selected_entities = selected(&folder, &form.selected_folders)
+        .and_then(|folders| Ok([folders, selected(&folder, &form.selected_files)?].concat()))
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let mut names = Vec::new();
     for entity in selected_entities {
-        if entity.name() == new_folder.name() {
+        if new_folder.is_inside(&entity) {
             FlashMessage::error("cannot move a folder into itself").send();
             continue;
         }
-        match folder.move_entity(entity.name(), &new_folder) {
-            Ok(()) => count = count + 1,
-            Err(e) => {
-                FlashMessage::error(e.to_string()).send();
-            }
+        if let Err(e) = auth.require_all_in(Permission::Write, &entity) {
+            FlashMessage::error(format!("{} '{}'", e, entity.name())).send();
+            continue;
         }
+        names.push(entity.name().to_owned());
     }
-    if count > 0 {
-        FlashMessage::success(format!("moved {} files/folders into '{}'", count, new_folder.name())).send();
+    if !names.is_empty() {
+        let count = names.len();
+        let job = Job::new(Operation::Move, &user.username, folder.relative_path(), Some(new_folder.relative_path()), new_folder.clone());
+        let task = jobs::move_task(auth.auditor(), folder.clone(), names, new_folder.clone(), form.conflict);
+        queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, task);
+        FlashMessage::info(format!("moving {} files/folders into '{}', see jobs for progress", count, new_folder.name())).send();
     }
     Ok(forward::to(ForwardTo::Folder(folder)))
 }
 
-pub async fn move_entities_up(folder_path: web::Path<String>, form: web::Form<MoveEntitiesIntoFormData>, session: Session) -> Result<HttpResponse, AppError> {
+/// Copies the selected files and folders into the destination, or next to themselves
+/// with new names when it is the folder they are in, with a background job
+pub async fn copy_entities(folder_path: web::Path<String>, form: web::Form<CopyEntitiesFormData>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let new_folder = match form.destination.is_empty() {
+        true => folder.clone(),
+        false => Folder::new(&form.destination)
+            .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?
+    };
+    let user = auth.require_in(Permission::Write, &new_folder)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
-    if folder.is_root() {
-        return Err(AppError::new(AppErrorKind::CannotGetParentOfRoot, ForwardTo::Folder(folder)));
-    }
-    let selected_entities = form.selected_folders.split("/").chain(form.selected_files.split("/"))
-        .filter_map(|e| { if e.len() == 0 { None } else { folder.join(e).ok() }});
-    let parent = folder.parent().unwrap_or_default();
+    let entities = allowed(&auth, Permission::Read, &folder, &form.selected_folders, &form.selected_files)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let names: Vec<String> = entities.iter().map(|entity| entity.name().to_owned()).collect();
+    let job = Job::new(Operation::Copy, &user.username, folder.relative_path(), Some(new_folder.relative_path()), new_folder.clone());
+    let task = jobs::copy_task(auth.auditor(), folder.clone(), names.clone(), new_folder.clone(), form.conflict);
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, task);
+    FlashMessage::info(format!("copying {} files/folders into '{}', see jobs for progress", names.len(), new_folder.name())).send();
+    Ok(forward::to(ForwardTo::Folder(new_folder)))
+}
+
+/// Zips the selected files and folders into one archive in the folder they are in, with a background job
+pub async fn zip_entities(folder_path: web::Path<String>, form: web::Form<ZipEntitiesFormData>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let user = auth.require_in(Permission::Write, &folder)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let entities = allowed(&auth, Permission::Read, &folder, &form.selected_folders, &form.selected_files)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let archive_name = archive_name(&form.archive_name, &folder, &entities);
+    auth.require_in(Permission::Write, &folder.join(&archive_name).map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let names: Vec<String> = entities.iter().map(|entity| entity.name().to_owned()).collect();
+    let job = Job::new(Operation::Zip, &user.username, folder.relative_path(), Some(audit::path(&folder, &archive_name)), folder.clone());
+    let (auditor, task_folder, conflict) = (auth.auditor(), folder.clone(), form.conflict);
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, move |progress| {
+        let result = task_folder.zip_entities(&names, &archive_name, conflict, &auditor.user, progress);
+        let outcome = auditor.audit_outcome(Operation::Zip, task_folder.relative_path(), &task_folder, &archive_name, result)?;
+        Ok(vec![ItemOutcome::new(&archive_name, outcome)])
+    });
+    FlashMessage::info(format!("zipping {} files/folders, see jobs for progress", entities.len())).send();
+    Ok(forward::to(ForwardTo::Folder(folder)))
+}
+
+/// Downloads the selected files and folders as one zip, written to a temporary file on disk
+/// that is streamed to the browser and gone once it has been sent
+pub async fn download_entities(req: HttpRequest, folder_path: web::Path<String>, form: web::Form<DownloadEntitiesFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let entities = allowed(&auth, Permission::Download, &folder, &form.selected_folders, &form.selected_files)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let archive_name = archive_name("", &folder, &entities);
+    let names = entities.iter().map(|entity| entity.name().to_owned()).collect();
+    let archive = folder.zip_to_file(names).await
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let content_disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(archive_name.clone())],
+    };
+    // the archive is read from disk a piece at a time as it is sent
+    let archive = NamedFile::from_file(archive, &archive_name)
+        .map_err(|err| AppError::new(err.into(), ForwardTo::Folder(folder.clone())))?
+        .set_content_disposition(content_disposition);
+    Ok(archive.into_response(&req))
+}
+
+/// Shows what each selected name becomes with the pattern, so it can be checked before renaming
+pub async fn preview_rename_entities(folder_path: web::Path<String>, form: web::Form<RenameEntitiesFormData>, auth: Auth, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let user = auth.require_in(Permission::Write, &folder)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let plans = rename_plans(&auth, &folder, &form)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.url(), a.name().to_owned())}).collect();
+    let data = json! ({
+        "title": "FS",
+        "user": user,
+        "flashes": flashes,
+        "csrf_token": csrf::token(&auth.session()),
+        "folder_path": folder.url(),
+        "crumbs": crumbs,
+        "selected_folders": form.selected_folders,
+        "selected_files": form.selected_files,
+        "pattern": form.pattern(),
+        "plans": plans.iter().map(PlannedRename::to_json).collect::<Vec<serde_json::Value>>(),
+        "ready_count": plans.iter().filter(|plan| plan.is_ready()).count()
+    });
+    let body = hb.render("rename-preview", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// Renames the selected files and folders with the pattern, the same as shown by the preview
+pub async fn rename_entities(folder_path: web::Path<String>, form: web::Form<RenameEntitiesFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    auth.require_in(Permission::Write, &folder)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let plans = rename_plans(&auth, &folder, &form)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let results = rename::apply(&folder, &plans)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let mut outcomes: Vec<ItemOutcome> = plans.iter()
+        .filter_map(|plan| plan.problem.as_ref().map(|problem| ItemOutcome::new(&plan.from, Outcome::Failed(problem.clone()))))
+        .collect();
     let mut count = 0;
-    for entity in selected_entities {
-        match folder.move_entity(entity.name(), &parent) {
-            Ok(()) => count = count + 1,
-            Err(e) => {
-                FlashMessage::error(e.to_string()).send();
-            }
+    for (plan, result) in results {
+        match auth.audit(Operation::Rename, audit::path(&folder, &plan.from), Some(audit::path(&folder, &plan.to)), result) {
+            Ok(()) => count += 1,
+            Err(e) => outcomes.push(ItemOutcome::new(&plan.from, Outcome::Failed(e.to_string())))
         }
     }
-    if count > 0 {
-        FlashMessage::success(format!("moved {} files/folders up a folder", count)).send();
+    match count {
+        0 if outcomes.is_empty() => FlashMessage::info("no names were changed").send(),
+        0 => (),
+        count => FlashMessage::success(format!("renamed {} files/folders", count)).send()
     }
+    send_outcomes(&outcomes);
     Ok(forward::to(ForwardTo::Folder(folder)))
 }
 
-pub async fn unzip_file(path: web::Path<(String,String)>, session: Session) -> Result<HttpResponse, AppError> {
+/// The form is optional so scripts can still unzip with an empty request, which fails on conflicts.
+/// The zip is extracted by a background job
+pub async fn unzip_file(path: web::Path<(String,String)>, form: Option<web::Form<UnzipFileFormData>>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
     let (folder_path, file_name) = path.into_inner();
     let folder = Folder::new(&folder_path)
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let user = auth.require_in(Permission::Read, &folder.join(&file_name).map_err(AppError::root)?)
+        .and_then(|_| auth.require_in(Permission::Write, &folder))
         .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    folder.unzip_file(&file_name).await
-        .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    FlashMessage::success(format!("unzipped file '{}'", file_name)).send();
+    let conflict = form.map(|form| form.conflict).unwrap_or_default();
+    let job = Job::new(Operation::Unzip, &user.username, audit::path(&folder, &file_name), Some(folder.relative_path()), folder.clone());
+    let (auditor, task_folder, task_file_name) = (auth.auditor(), folder.clone(), file_name.clone());
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, move |progress| {
+        let result = task_folder.unzip_file(&task_file_name, conflict, &auditor.user, progress);
+        auditor.audit(Operation::Unzip, audit::path(&task_folder, &task_file_name), Some(task_folder.relative_path()), result)
+    });
+    FlashMessage::info(format!("unzipping file '{}', see jobs for progress", file_name)).send();
     Ok(forward::to(ForwardTo::Folder(folder)))
 }
 
-pub async fn remove_file(path: web::Path<(String,String)>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn remove_file(path: web::Path<(String,String)>, auth: Auth) -> Result<HttpResponse, AppError> {
     let (folder_path, file_name) = path.into_inner();
     let folder = Folder::new(&folder_path)
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let user = auth.require_in(Permission::Delete, &folder.join(&file_name).map_err(AppError::root)?)
         .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    folder.remove_file(&file_name)
+    auth.audit(Operation::Delete, audit::path(&folder, &file_name), None, folder.remove_file(&file_name, &user.username).await)
         .map_err(|k| AppError::new(k, ForwardTo::FileDetail(folder.clone(), file_name.clone())))?;
-    FlashMessage::success(format!("removed file '{}'", file_name)).send();
+    FlashMessage::success(format!("moved file '{}' to the trash", file_name)).send();
     Ok(forward::to(ForwardTo::Folder(folder)))
 }
 
-pub async fn remove_entities(folder_path: web::Path<String>, form: web::Form<RemoveEntitiesFormData>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn remove_entities(folder_path: web::Path<String>, form: web::Form<RemoveEntitiesFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let user = auth.require(Permission::Delete)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let selected_folders = selected(&folder, &form.selected_folders)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
-    let selected_folders = form.selected_folders.split("/")
-        .filter_map(|e| { if e.len() == 0 { None } else { folder.join(e).ok() }});
     let mut count = 0;
     for remove_folder in selected_folders {
-        match remove_folder.remove() {
-            Ok(()) => count = count + 1,
+        if let Err(e) = auth.require_all_in(Permission::Delete, &remove_folder) {
+            FlashMessage::error(format!("{} '{}'", e, remove_folder.name())).send();
+            continue;
+        }
+        match auth.audit(Operation::Delete, remove_folder.relative_path(), None, remove_folder.remove(&user.username).await) {
+            Ok(_) => count += 1,
             Err(e) => FlashMessage::error(e.to_string()).send()
         }
     }
-    let selected_files = form.selected_files.split("/")
-        .filter_map(|e| { if e.len() == 0 { None } else { folder.join(e).ok() }});
+    let selected_files = selected(&folder, &form.selected_files)
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
     for remove_file in selected_files {
-        match folder.remove_file(remove_file.name()) {
-            Ok(()) => count = count + 1,
+        if let Err(e) = auth.require_in(Permission::Delete, &remove_file) {
+            FlashMessage::error(format!("{} '{}'", e, remove_file.name())).send();
+            continue;
+        }
+        match auth.audit(Operation::Delete, remove_file.relative_path(), None, folder.remove_file(remove_file.name(), &user.username).await) {
+            Ok(_) => count += 1,
             Err(e) => FlashMessage::error(e.to_string()).send()
         }
     }
     if count > 0 {
-        FlashMessage::success(format!("removed {} files/folders", count)).send();
+        FlashMessage::success(format!("moved {} files/folders to the trash", count)).send();
     }
     Ok(forward::to(ForwardTo::Folder(folder)))
 }
+
+/// Reads the names of a multi-select form field, sent as a JSON array so any name can be selected
+fn selected(folder: &Folder, names: &str) -> Result<Vec<Folder>, AppErrorKind> {
+    if names.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let names: Vec<String> = serde_json::from_str(names)
+        .map_err(|_| AppErrorKind::SelectionInvalid)?;
+    names.iter().map(|name| folder.join(name)).collect()
+}
+
+/// The selected files and folders the user has permission for on everything in them.
+/// The others are left out with an error message, and nothing left is an error
+fn allowed(auth: &Auth, permission: Permission, folder: &Folder, selected_folders: &str, selected_files: &str) -> Result<Vec<Folder>, AppErrorKind> {
+    let mut entities = selected(folder, selected_folders)?;
+    entities.extend(selected(folder, selected_files)?);
+    entities.retain(|entity| match auth.require_all_in(permission, entity) {
+        Ok(_) => true,
+        Err(e) => {
+            FlashMessage::error(format!("{} '{}'", e, entity.name())).send();
+            false
+        }
+    });
+    match entities.is_empty() {
+        true => Err(AppErrorKind::SelectionInvalid),
+        false => Ok(entities)
+    }
+}
+
+/// The name given, or the one entry's name, or the folder's name, always ending in ".zip"
+fn archive_name(name: &str, folder: &Folder, entities: &[Folder]) -> String {
+    let name = match (name.trim(), entities) {
+        ("", [entity]) => entity.name(),
+        ("", _) => folder.name(),
+        (name, _) => name
+    };
+    match name.ends_with(".zip") {
+        true => name.to_owned(),
+        false => format!("{}.zip", name)
+    }
+}
+
+/// Plans the rename of the selected entries the user can write to, where a new name
+/// the user cannot write to is a problem of that entry
+fn rename_plans(auth: &Auth, folder: &Folder, form: &RenameEntitiesFormData) -> Result<Vec<PlannedRename>, AppErrorKind> {
+    let names: Vec<String> = allowed(auth, Permission::Write, folder, &form.selected_folders, &form.selected_files)?
+        .iter().map(|entity| entity.name().to_owned()).collect();
+    let mut plans = form.pattern().plan(folder, &names)?;
+    for plan in plans.iter_mut().filter(|plan| plan.is_ready()) {
+        if let Err(e) = folder.join(&plan.to).and_then(|entity| auth.require_in(Permission::Write, &entity)) {
+            plan.problem = Some(e.to_string());
+        }
+    }
+    Ok(plans)
+}
+
+/// Tells the user about each item that was not written as asked, failures as errors
+fn send_outcomes(outcomes: &[ItemOutcome]) {
+    let messages: Vec<(String, bool)> = outcomes.iter()
+        .filter_map(|item| item.message().map(|message| (message, matches!(item.outcome, Outcome::Failed(_)))))
+        .collect();
+    for (message, failed) in messages.iter().take(OUTCOME_MESSAGE_LIMIT) {
+        if *failed { FlashMessage::error(message) } else { FlashMessage::info(message) }.send();
+    }
+    if messages.len() > OUTCOME_MESSAGE_LIMIT {
+        FlashMessage::info(format!("and {} more", messages.len() - OUTCOME_MESSAGE_LIMIT)).send();
+    }
+}
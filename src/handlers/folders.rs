@@ -1,13 +1,12 @@
-use actix_session::Session;
+use std::{path::Path, sync::{Arc, Mutex}};
+
 use actix_web::{web, HttpResponse};
 use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
 use handlebars::Handlebars;
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::{models::{folder::Folder, user::User}, util::{error::{AppError, AppErrorKind}, forward::{ForwardTo, self}}};
-
-const PARENT_OPTION: &str = "|Move to parent folder|";
+use crate::{models::{conflict::ConflictPolicy, folder::Folder, listing::{Page, Sort}, usage::{self, UsageEntry}}, util::{audit::{self, Operation}, index::FileIndex, auth::{Auth, Permission}, csrf, error::{AppError, AppErrorKind}, forward::{ForwardTo, self}, jobs::{self, Job, JobQueue}}};
 
 #[derive(Deserialize)]
 pub struct NewFolderFormData {
@@ -19,142 +18,256 @@ pub struct RenameFolderFormData {
     folder_name: String,
 }
 
+/// The folder to move to, see Folder::move_destination
 #[derive(Deserialize)]
 pub struct MoveFolderIntoFormData {
+    #[serde(default)]
     folder_name: String,
+    #[serde(default)]
+    destination: String,
+    #[serde(default)]
+    conflict: ConflictPolicy,
+}
+
+/// Without a destination the copy is made next to the folder
+#[derive(Deserialize, Default)]
+pub struct CopyFolderFormData {
+    #[serde(default)]
+    destination: String,
+    #[serde(default)]
+    conflict: ConflictPolicy,
 }
 
 pub async fn get_folder_detail(
     folder_path: web::Path<String>,
-    session: Session,
+    auth: Auth,
     hb: web::Data<Handlebars<'_>>,
+    index: web::Data<Arc<Mutex<FileIndex>>>,
     flashes: IncomingFlashMessages
 ) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    let details = folder.details()
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::FolderDetail(folder.clone())))?;
+    let indexed = {
+        let index = index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        index.is_ready().then(|| index.usage(&folder))
+    };
+    let usage = match indexed {
+        Some(usage) => usage,
+        None => folder.usage().await
+            .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.parent().unwrap_or_default())))?
+    };
+    let details = folder.details(&usage)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.parent().unwrap_or_default())))?;
-    let folders = match folder.parent() {
-        Ok(parent) => match parent.entity_list(true) {
-            Ok(list) => list.0,
-            Err(_) => Vec::new()
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.url(), a.name().to_owned())}).collect();
+    let data = json! ({
+        "title": "FS",
+        "user": user,
+        "flashes": flashes,
+        "csrf_token": csrf::token(&auth.session()),
+        "folder_path": folder.url(),
+        "crumbs": crumbs,
+        "details": details
+    });
+    let body = hb.render("folder-detail", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// How many of the largest folders and files the disk usage page lists
+const USAGE_ROWS: usize = 100;
+
+pub async fn get_folder_usage(
+    folder_path: web::Path<String>,
+    auth: Auth,
+    hb: web::Data<Handlebars<'_>>,
+    index: web::Data<Arc<Mutex<FileIndex>>>,
+    flashes: IncomingFlashMessages
+) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::FolderDetail(folder.clone())))?;
+    let access = user.access_list();
+    let indexed = {
+        let index = index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        index.is_ready().then(|| (index.usage(&folder), index.breakdown(&folder)))
+    };
+    let (usage, mut entries) = match indexed {
+        Some((usage, children)) => {
+            let mut entries: Vec<UsageEntry> = children.into_iter().filter_map(|(name, (is_folder, usage))| {
+                let entity = folder.join(&name).ok()?;
+                entity.is_visible(&access, user.show_hidden).then_some(UsageEntry { folder: entity, is_folder, usage })
+            }).collect();
+            usage::sort_largest(&mut entries);
+            (usage, entries)
         },
-        Err(_) => Vec::new()
+        None => {
+            let usage = folder.usage().await
+                .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
+            let entries = folder.usage_breakdown(&access, user.show_hidden).await
+                .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
+            (usage, entries)
+        }
     };
+    let more = entries.len().saturating_sub(USAGE_ROWS);
+    entries.truncate(USAGE_ROWS);
+    let disk_space = folder.to_path().ok().and_then(|path| usage::disk_space(Path::new(&path)));
     let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
-    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.to_string(), a.name().to_owned())}).collect();
+    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.url(), a.name().to_owned())}).collect();
     let data = json! ({
         "title": "FS",
         "user": user,
         "flashes": flashes,
-        "folder_path": folder.to_string(),
+        "folder_path": folder.url(),
         "crumbs": crumbs,
-        "folders": folders,
-        "details": details,
-        "parent_option": PARENT_OPTION.clone()
+        "usage": usage.to_json(),
+        "disk_space": disk_space.map(|space| space.to_json()),
+        "entries": entries.iter().map(|entry| entry.to_json(usage.size)).collect::<Vec<serde_json::Value>>(),
+        "more": more
     });
-    let body = hb.render("folder-detail", &data).unwrap();
+    let body = hb.render("usage", &data).unwrap();
     Ok(HttpResponse::Ok().body(body))
 }
 
-pub async fn add_folder(folder_path: web::Path<String>, form: web::Form<NewFolderFormData>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn add_folder(folder_path: web::Path<String>, form: web::Form<NewFolderFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let _user = auth.require_in(Permission::Write, &folder.join(&form.folder_name).map_err(AppError::root)?)
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
-    folder.create_dir(&form.folder_name)
+    auth.audit(Operation::CreateFolder, audit::path(&folder, &form.folder_name), None, folder.create_dir(&form.folder_name))
         .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
     FlashMessage::success(format!("created folder '{}'", form.folder_name)).send();
     Ok(forward::to(ForwardTo::Folder(folder)))
 }
 
-pub async fn rename_folder(folder_path: web::Path<String>, form: web::Form<RenameFolderFormData>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn rename_folder(folder_path: web::Path<String>, form: web::Form<RenameFolderFormData>, auth: Auth) -> Result<HttpResponse, AppError> {
     let mut folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let renamed_folder = folder.parent().and_then(|p| p.join(&form.folder_name))
+        .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
+    auth.require_all_in(Permission::Write, &folder)
+        .and_then(|_| auth.require_in(Permission::Write, &renamed_folder))
         .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
     let old_folder = folder.clone();
-    folder.rename(&form.folder_name)
+    let result = folder.rename(&form.folder_name);
+    auth.audit(Operation::Rename, old_folder.relative_path(), Some(renamed_folder.relative_path()), result)
         .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
     FlashMessage::success(format!("renamed folder '{}' to '{}'", old_folder.name(), form.folder_name)).send();
     Ok(forward::to(ForwardTo::FolderDetail(folder.clone())))
 }
 
-pub async fn move_folder(folder_path: web::Path<String>, form: web::Form<MoveFolderIntoFormData>, session: Session) -> Result<HttpResponse, AppError> {
-    if form.folder_name == PARENT_OPTION {
-        return move_folder_up(folder_path, session).await;
-    }
+/// The folder is moved by a background job, since a move to another drive copies everything in it
+pub async fn move_folder(folder_path: web::Path<String>, form: web::Form<MoveFolderIntoFormData>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
     if folder.is_root() {
         return Err(AppError::new(AppErrorKind::CannotMoveRoot, ForwardTo::FolderDetail(folder)));
     }
     let parent_folder = folder.parent().unwrap_or_default();
-    let sibling_folder = parent_folder.join(&form.folder_name)
+    let new_folder = parent_folder.move_destination(&form.folder_name, &form.destination)
         .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    if folder.name() == sibling_folder.name() {
+    if new_folder == parent_folder {
+        return Err(AppError::new(AppErrorKind::AlreadyInFolder, ForwardTo::FolderDetail(folder)));
+    }
+    if new_folder.is_inside(&folder) {
         return Err(AppError::new(AppErrorKind::CannotMoveFolderIntoItself, ForwardTo::FolderDetail(folder)));
     }
-    parent_folder.move_entity(folder.name(), &sibling_folder) 
+    let user = auth.require_all_in(Permission::Write, &folder)
+        .and_then(|_| auth.require_in(Permission::Write, &new_folder))
         .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    FlashMessage::success(format!("moved folder '{}' into '{}'", folder.name(), sibling_folder.name())).send();
-    Ok(forward::to(ForwardTo::FolderDetail(sibling_folder.join(folder.name()).unwrap_or_default())))
+    let job = Job::new(Operation::Move, &user.username, folder.relative_path(), Some(new_folder.relative_path()), new_folder.clone());
+    let task = jobs::move_task(auth.auditor(), parent_folder, vec![folder.name().to_owned()], new_folder.clone(), form.conflict);
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, task);
+    FlashMessage::info(format!("moving folder '{}' into '{}', see jobs for progress", folder.name(), new_folder.name())).send();
+    Ok(forward::to(ForwardTo::Folder(new_folder)))
 }
 
-pub async fn move_folder_up(folder_path: web::Path<String>, session: Session) -> Result<HttpResponse, AppError> {
+/// The form is optional so scripts can still copy in place with an empty request.
+/// The copy is made by a background job
+pub async fn copy_folder(folder_path: web::Path<String>, form: Option<web::Form<CopyFolderFormData>>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    let parent_folder = folder.parent().unwrap_or_default();
-    if parent_folder.is_root() {
-        return Err(AppError::new(AppErrorKind::CannotMoveAboveRoot, ForwardTo::FolderDetail(folder)));
+    if folder.is_root() {
+        return Err(AppError::new(AppErrorKind::CannotCopyRoot, ForwardTo::FolderDetail(folder)));
     }
-    let grandparent_folder = parent_folder.parent().unwrap_or_default();
-    parent_folder.move_entity(folder.name(), &grandparent_folder)
+    let parent_folder = folder.parent().unwrap_or_default();
+    let form = form.map(web::Form::into_inner).unwrap_or_default();
+    let new_folder = match form.destination.is_empty() {
+        true => parent_folder.clone(),
+        false => Folder::new(&form.destination)
+            .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?
+    };
+    let user = auth.require_all_in(Permission::Read, &folder)
+        .and_then(|_| auth.require_in(Permission::Write, &new_folder))
         .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    FlashMessage::success(format!("moved folder '{}' up a folder", folder.name())).send();
-    Ok(forward::to(ForwardTo::FolderDetail(grandparent_folder.join(folder.name()).unwrap_or_default())))
+    if new_folder.is_inside(&folder) {
+        return Err(AppError::new(AppErrorKind::CannotCopyFolderIntoItself, ForwardTo::FolderDetail(folder)));
+    }
+    let job = Job::new(Operation::Copy, &user.username, folder.relative_path(), Some(new_folder.relative_path()), new_folder.clone());
+    let task = jobs::copy_task(auth.auditor(), parent_folder, vec![folder.name().to_owned()], new_folder.clone(), form.conflict);
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, task);
+    FlashMessage::info(format!("copying folder '{}' into '{}', see jobs for progress", folder.name(), new_folder.name())).send();
+    Ok(forward::to(ForwardTo::Folder(new_folder)))
 }
 
-pub async fn copy_folder(folder_path: web::Path<String>, session: Session) -> Result<HttpResponse, AppError> {
+/// Lists the folders in a folder as JSON for the folder picker, with the folder path each
+/// one is chosen by. Parent is left out where the user cannot go further up
+pub async fn get_subfolders(folder_path: web::Path<String>, auth: Auth) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    let new_folder = folder.copy()
-        .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    FlashMessage::success(format!("copied folder '{}' to '{}'", &folder.name(), &new_folder.name())).send();
-    Ok(forward::to(ForwardTo::Folder(folder.parent().unwrap_or_default())))
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    let listing = folder.entity_list(true, &user.access_list(), user.show_hidden, Sort::default(), &Page::all())
+        .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+    let parent = folder.parent().ok().filter(|parent| auth.require_in(Permission::Read, parent).is_ok());
+    let folders: Vec<serde_json::Value> = listing.folders.iter().map(|entry| json!({
+        "name": entry.name,
+        "path": entry.folder.url(),
+        "destination": entry.folder.to_string()
+    })).collect();
+    Ok(HttpResponse::Ok().json(json!({
+        "name": folder.name(),
+        "display": format!("/{}", folder.relative_path()),
+        "path": folder.url(),
+        "destination": folder.to_string(),
+        "parent": parent.map(|parent| parent.url()),
+        "folders": folders
+    })))
 }
 
-pub async fn zip_folder(folder_path: web::Path<String>, session: Session) -> Result<HttpResponse, AppError> {
+/// The zip is made next to the folder by a background job
+pub async fn zip_folder(folder_path: web::Path<String>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
     if folder.is_root() {
         return Err(AppError::new(AppErrorKind::CannotZipRoot, ForwardTo::FolderDetail(folder)));
     }
-    folder.zip().await
+    let user = auth.require_all_in(Permission::Read, &folder)
+        .and_then(|_| auth.require_in(Permission::Write, &folder.parent()?))
         .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    FlashMessage::success(format!("zipped folder '{}'", folder.name())).send();
-    Ok(forward::to(ForwardTo::Folder(folder.parent().unwrap_or_default())))
+    let parent = folder.parent().unwrap_or_default();
+    let archive_path = format!("{}.zip", folder.relative_path());
+    let job = Job::new(Operation::Zip, &user.username, folder.relative_path(), Some(archive_path.clone()), parent.clone());
+    let (auditor, task_folder) = (auth.auditor(), folder.clone());
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).submit(job, move |progress| {
+        auditor.audit(Operation::Zip, task_folder.relative_path(), Some(archive_path), task_folder.zip(progress))?;
+        Ok(Vec::new())
+    });
+    FlashMessage::info(format!("zipping folder '{}', see jobs for progress", folder.name())).send();
+    Ok(forward::to(ForwardTo::Folder(parent)))
 }
 
-pub async fn remove_folder(folder_path: web::Path<String>, session: Session) -> Result<HttpResponse, AppError> {
+pub async fn remove_folder(folder_path: web::Path<String>, auth: Auth) -> Result<HttpResponse, AppError> {
     let folder = Folder::new(&folder_path.into_inner())
         .map_err(AppError::root)?;
-    let _user = User::get(session)
+    let user = auth.require_all_in(Permission::Delete, &folder)
         .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
     let old_folder_name = folder.name();
     let parent = folder.parent().unwrap_or_default();
-    folder.remove()
+    auth.audit(Operation::Delete, folder.relative_path(), None, folder.remove(&user.username).await)
         .map_err(|k| AppError::new(k, ForwardTo::FolderDetail(folder.clone())))?;
-    FlashMessage::success(format!("removed folder '{}'", old_folder_name)).send();
+    FlashMessage::success(format!("moved folder '{}' to the trash", old_folder_name)).send();
     Ok(forward::to(ForwardTo::FolderDetail(parent)))
 }
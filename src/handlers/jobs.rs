@@ -0,0 +1,66 @@
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use handlebars::Handlebars;
+use serde_json::json;
+
+use crate::{models::user::{User, UserAuthority}, util::{auth::Auth, csrf, error::{AppError, AppErrorKind}, forward::{self, ForwardTo}, jobs::{Job, JobQueue}}};
+
+/// Admins see and cancel every job, other users only the ones they started
+fn can_manage(user: &User, job: &Job) -> bool {
+    user.authority == UserAuthority::Admin || job.user == user.username
+}
+
+fn visible_jobs(user: &User, queue: &Mutex<JobQueue>) -> Vec<serde_json::Value> {
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+        .jobs()
+        .filter(|job| can_manage(user, job))
+        .map(Job::to_json)
+        .collect()
+}
+
+pub async fn get_jobs(auth: Auth, hb: web::Data<Handlebars<'_>>, queue: web::Data<Arc<Mutex<JobQueue>>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let user = auth.user()
+        .map_err(|k| AppError::page(k, ForwardTo::Jobs))?;
+    let jobs = visible_jobs(&user, &queue);
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let data = json! ({
+        "title": "Jobs",
+        "user": user,
+        "flashes": flashes,
+        "csrf_token": csrf::token(&auth.session()),
+        "jobs": jobs
+    });
+    let body = hb.render("jobs", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// The jobs page polls this for the progress of every job the user can see
+pub async fn get_job_list(auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
+    let user = auth.user()
+        .map_err(|k| AppError::new(k, ForwardTo::Jobs))?;
+    Ok(HttpResponse::Ok().json(json!({ "jobs": visible_jobs(&user, &queue) })))
+}
+
+pub async fn get_job(id: web::Path<u64>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
+    let user = auth.user()
+        .map_err(|k| AppError::new(k, ForwardTo::Jobs))?;
+    let queue = queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let job = queue.get(*id)
+        .and_then(|job| if can_manage(&user, job) { Ok(job) } else { Err(AppErrorKind::JobNotFound) })
+        .map_err(|k| AppError::new(k, ForwardTo::Jobs))?;
+    Ok(HttpResponse::Ok().json(job.to_json()))
+}
+
+pub async fn cancel_job(id: web::Path<u64>, auth: Auth, queue: web::Data<Arc<Mutex<JobQueue>>>) -> Result<HttpResponse, AppError> {
+    let user = auth.user()
+        .map_err(|k| AppError::new(k, ForwardTo::Jobs))?;
+    let mut queue = queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    queue.get(*id)
+        .and_then(|job| if can_manage(&user, job) { Ok(job.id) } else { Err(AppErrorKind::JobNotFound) })
+        .and_then(|id| queue.cancel(id))
+        .map_err(|k| AppError::new(k, ForwardTo::Jobs))?;
+    FlashMessage::success(format!("cancelled job {}", id)).send();
+    Ok(forward::to(ForwardTo::Jobs))
+}
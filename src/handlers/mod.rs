@@ -2,4 +2,8 @@ pub mod root;
 pub mod files;
 pub mod folders;
 pub mod auth;
-pub mod timelapse;
\ No newline at end of file
+pub mod timelapse;
+pub mod admin;
+pub mod search;
+pub mod trash;
+pub mod jobs;
@@ -3,11 +3,18 @@ use actix_web::{HttpResponse, http, Responder, web};
 use handlebars::Handlebars;
 use serde_json::json;
 
-use crate::models::user::User;
+use crate::{models::{folder::Folder, user::User}, util::{auth::Auth, forward::{self, ForwardTo}}};
 
 
-pub async fn index() -> impl Responder {
-    HttpResponse::PermanentRedirect().insert_header((http::header::LOCATION, "/fs/root/files")).finish()
+/// Sends users jailed to a home folder straight to it
+pub async fn index(auth: Auth) -> impl Responder {
+    let home = auth.user().ok()
+        .and_then(|user| user.home)
+        .and_then(|home| Folder::from_relative_path(&home).ok());
+    match home {
+        Some(home) => forward::to(ForwardTo::Folder(home)),
+        None => HttpResponse::PermanentRedirect().insert_header((http::header::LOCATION, "/fs/root/files")).finish()
+    }
 }
 
 pub async fn about(hb: web::Data<Handlebars<'_>>, session: Session) -> impl Responder {
@@ -0,0 +1,237 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use actix_web::{web::{self, Bytes}, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use futures_util::{stream, StreamExt};
+use handlebars::Handlebars;
+use serde_json::json;
+
+use crate::{models::{content, folder::Folder, listing::format_size, search::{search_limit, EntryKind, MatchMode, Search, SearchQuery, SearchResult, SearchWalk}}, util::{auth::{Auth, Permission}, error::AppError, forward::ForwardTo, index::{self, FileIndex, IndexEntry}}};
+
+/// Where in the rendered search page the results are streamed in
+const RESULTS_MARKER: &str = "<!--results-->";
+/// How many results are searched for before they are sent on to the browser
+const BATCH_SIZE: usize = 50;
+/// How many files the recent files page shows
+const RECENT_COUNT: usize = 100;
+/// How many groups of duplicates are shown, largest files first
+const DUPLICATE_GROUPS: usize = 100;
+
+pub async fn search(
+    folder_path: web::Path<String>,
+    query: web::Query<SearchQuery>,
+    auth: Auth,
+    hb: web::Data<Handlebars<'static>>,
+    index: web::Data<Arc<Mutex<FileIndex>>>,
+    flashes: IncomingFlashMessages
+) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    let query = query.into_inner();
+    let searching = query.q.is_some();
+    let mut indexed = None;
+    let walk = if searching {
+        let search = Search::new(&query)
+            .map_err(|k| AppError::new(k, ForwardTo::Search(folder.clone())))?;
+        let index = index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if index.is_ready() && !search.has_content() {
+            indexed = Some(index.search(&folder, &search, &user.access_list(), user.show_hidden, search_limit()));
+            None
+        }
+        else {
+            drop(index);
+            Some(SearchWalk::new(&folder, search, user.access_list(), user.show_hidden)
+                .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?)
+        }
+    }
+    else {
+        None
+    };
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.url(), a.name().to_owned())}).collect();
+    let data = json! ({
+        "title": "FS",
+        "user": user,
+        "flashes": flashes,
+        "folder_path": folder.url(),
+        "crumbs": crumbs,
+        "query": query,
+        "modes": [MatchMode::Substring, MatchMode::Glob, MatchMode::Regex],
+        "kinds": EntryKind::ALL,
+        "contexts": (0..=content::MAX_CONTEXT).collect::<Vec<usize>>(),
+        "searching": searching
+    });
+    let body = hb.render("search", &data).unwrap();
+    if let Some((results, truncated)) = indexed {
+        // the index answers at once, so there is nothing to stream
+        let mut rows: String = results.iter()
+            .map(|result| hb.render("search-result", &result.to_json()).unwrap_or_default())
+            .collect();
+        rows.push_str(&hb.render("search-summary", &json!({ "found": results.len(), "truncated": truncated })).unwrap_or_default());
+        return Ok(HttpResponse::Ok().body(body.replacen(RESULTS_MARKER, &rows, 1)));
+    }
+    let walk = match walk {
+        Some(walk) => walk,
+        None => return Ok(HttpResponse::Ok().body(body))
+    };
+    let (head, tail) = match body.split_once(RESULTS_MARKER) {
+        Some((head, tail)) => (head.to_owned(), tail.to_owned()),
+        None => return Ok(HttpResponse::Ok().body(body))
+    };
+    let results = stream::unfold(Some(walk), move |walk| {
+        let hb = hb.clone();
+        async move {
+            let mut walk = walk?;
+            let (walk, results) = web::block(move || {
+                let results = walk.next_batch(BATCH_SIZE);
+                (walk, results)
+            }).await.ok()?;
+            if results.is_empty() {
+                let summary = json!({ "found": walk.found(), "truncated": walk.is_truncated() });
+                return Some((hb.render("search-summary", &summary).unwrap_or_default(), None));
+            }
+            let rows: String = results.iter()
+                .map(|result| hb.render("search-result", &result.to_json()).unwrap_or_default())
+                .collect();
+            Some((rows, Some(walk)))
+        }
+    });
+    let page = stream::once(async move { head })
+        .chain(results)
+        .chain(stream::once(async move { tail }))
+        .map(|html| Ok::<Bytes, actix_web::Error>(Bytes::from(html)));
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").streaming(page))
+}
+
+pub async fn recent(
+    folder_path: web::Path<String>,
+    auth: Auth,
+    hb: web::Data<Handlebars<'_>>,
+    index: web::Data<Arc<Mutex<FileIndex>>>,
+    flashes: IncomingFlashMessages
+) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    let recent = {
+        let index = index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        index.is_ready().then(|| index.recent(&folder, &user.access_list(), user.show_hidden, RECENT_COUNT))
+    };
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.url(), a.name().to_owned())}).collect();
+    let data = json! ({
+        "title": "FS",
+        "user": user,
+        "flashes": flashes,
+        "folder_path": folder.url(),
+        "crumbs": crumbs,
+        "indexed": recent.is_some(),
+        "files": recent.unwrap_or_default().iter().map(SearchResult::to_json).collect::<Vec<_>>()
+    });
+    let body = hb.render("recent", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// Files below a folder with the same contents. Files are only hashed when another file
+/// has the same size, and each hash is kept in the index until the file changes
+pub async fn duplicates(
+    folder_path: web::Path<String>,
+    auth: Auth,
+    hb: web::Data<Handlebars<'_>>,
+    index: web::Data<Arc<Mutex<FileIndex>>>,
+    flashes: IncomingFlashMessages
+) -> Result<HttpResponse, AppError> {
+    let folder = Folder::new(&folder_path.into_inner())
+        .map_err(AppError::root)?;
+    let user = auth.require_in(Permission::Read, &folder)
+        .map_err(|k| AppError::page(k, ForwardTo::Folder(folder.clone())))?;
+    let same_size = {
+        let index = index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        index.is_ready().then(|| index.same_size(&folder, &user.access_list(), user.show_hidden))
+    };
+    let indexed = same_size.is_some();
+    let mut groups: Vec<serde_json::Value> = Vec::new();
+    if let Some(same_size) = same_size {
+        let root = Folder::canonical_root()
+            .map_err(|k| AppError::new(k, ForwardTo::Folder(folder.clone())))?;
+        let hashed = web::block(move || {
+            let mut hashed = Vec::new();
+            let mut found = 0;
+            // the groups come largest files first, so once enough duplicates are found
+            // the smaller files would not be shown and are not read
+            for group in same_size {
+                if found >= DUPLICATE_GROUPS {
+                    break;
+                }
+                let group: Vec<(String, IndexEntry, String)> = group.into_iter().filter_map(|(path, entry)| {
+                    let hash = entry.hash.clone().or_else(|| index::hash_file(&root.join(&path)).ok())?;
+                    Some((path, entry, hash))
+                }).collect();
+                let mut counts: HashMap<&str, usize> = HashMap::new();
+                for (_, _, hash) in &group {
+                    *counts.entry(hash.as_str()).or_default() += 1;
+                }
+                found += counts.values().filter(|count| **count > 1).count();
+                hashed.push(group);
+            }
+            hashed
+        }).await.unwrap_or_default();
+        let mut index = index.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (path, entry, hash) in hashed.iter().flatten() {
+            if entry.hash.is_none() {
+                index.set_hash(path, entry, hash.clone());
+            }
+        }
+        drop(index);
+        for group in hashed {
+            let mut by_hash: HashMap<&str, Vec<&(String, IndexEntry, String)>> = HashMap::new();
+            for file in &group {
+                by_hash.entry(file.2.as_str()).or_default().push(file);
+            }
+            let mut duplicates: Vec<Vec<&(String, IndexEntry, String)>> = by_hash.into_values().filter(|files| files.len() > 1).collect();
+            duplicates.sort_by(|a, b| a[0].0.cmp(&b[0].0));
+            for mut files in duplicates {
+                files.sort_by(|a, b| a.0.cmp(&b.0));
+                let size = files[0].1.size;
+                let files: Vec<serde_json::Value> = files.iter()
+                    .filter_map(|(path, entry, _)| {
+                        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+                        Some(SearchResult {
+                            folder: Folder::from_relative_path(parent).ok()?,
+                            name: name.to_owned(),
+                            is_folder: false,
+                            size: entry.size,
+                            modified: entry.modified,
+                            hits: None,
+                        }.to_json())
+                    })
+                    .collect();
+                groups.push(json!({
+                    "size_display": format_size(size),
+                    "count": files.len(),
+                    "wasted_display": format_size(size * (files.len() as u64 - 1)),
+                    "files": files
+                }));
+            }
+            if groups.len() >= DUPLICATE_GROUPS {
+                break;
+            }
+        }
+    }
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let crumbs: Vec<(String,String)> = folder.ancestors(true).iter().map(|a| { (a.url(), a.name().to_owned())}).collect();
+    let data = json! ({
+        "title": "FS",
+        "user": user,
+        "flashes": flashes,
+        "folder_path": folder.url(),
+        "crumbs": crumbs,
+        "indexed": indexed,
+        "groups": groups
+    });
+    let body = hb.render("duplicates", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
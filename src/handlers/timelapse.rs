@@ -1,17 +1,16 @@
 use std::sync::{Arc, Mutex};
 
-use actix_session::Session;
 use actix_web::{HttpResponse, web};
 use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
 use handlebars::Handlebars;
 use serde_json::json;
 
-use crate::{models::user::User, util::{error::AppError, forward::{ForwardTo, self}, timelapse::{TimelapseThread, TimelapseSettings}}};
+use crate::util::{auth::{Auth, Permission}, csrf, error::AppError, forward::{ForwardTo, self}, timelapse::{TimelapseThread, TimelapseSettings}};
 
 
-pub async fn timelapse(hb: web::Data<Handlebars<'_>>, timelapse_mutex: web::Data<Arc<Mutex<TimelapseThread>>>, session: Session, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
-    let user = User::get(session)
-        .map_err(|k| AppError::new(k, ForwardTo::Timelapse))?;
+pub async fn timelapse(hb: web::Data<Handlebars<'_>>, timelapse_mutex: web::Data<Arc<Mutex<TimelapseThread>>>, auth: Auth, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::Timelapse)
+        .map_err(|k| AppError::page(k, ForwardTo::Timelapse))?;
     let mut is_running = false;
     match timelapse_mutex.lock() {
         Ok(timelapse) => {
@@ -24,14 +23,15 @@ pub async fn timelapse(hb: web::Data<Handlebars<'_>>, timelapse_mutex: web::Data
         "title": "Timelapse",
         "user": user,
         "flashes": flashes,
+        "csrf_token": csrf::token(&auth.session()),
         "is_running": is_running
     });
     let body = hb.render("timelapse", &data).unwrap();
     Ok(HttpResponse::Ok().body(body))
 }
 
-pub async fn start(timelapse_mutex: web::Data<Arc<Mutex<TimelapseThread>>>, form: web::Form<TimelapseSettings>, session: Session) -> Result<HttpResponse, AppError> {
-    let _user = User::get(session)
+pub async fn start(timelapse_mutex: web::Data<Arc<Mutex<TimelapseThread>>>, form: web::Form<TimelapseSettings>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let _user = auth.require(Permission::Timelapse)
         .map_err(|k| AppError::new(k, ForwardTo::Timelapse))?;
     match timelapse_mutex.lock() {
         Ok(mut timelapse) => {
@@ -47,8 +47,8 @@ pub async fn start(timelapse_mutex: web::Data<Arc<Mutex<TimelapseThread>>>, form
     Ok(forward::to(ForwardTo::Timelapse))
 }
 
-pub async fn stop(timelapse_mutex: web::Data<Arc<Mutex<TimelapseThread>>>, session: Session) -> Result<HttpResponse, AppError> {
-    let _user = User::get(session)
+pub async fn stop(timelapse_mutex: web::Data<Arc<Mutex<TimelapseThread>>>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let _user = auth.require(Permission::Timelapse)
         .map_err(|k| AppError::new(k, ForwardTo::Timelapse))?;
     match timelapse_mutex.lock() {
         Ok(mut timelapse) => {
@@ -0,0 +1,87 @@
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use handlebars::Handlebars;
+use serde_json::json;
+
+use crate::{models::{folder::Folder, listing::format_size, trash::{self, TrashItem}, user::{User, UserAuthority}}, util::{audit::Operation, auth::{Auth, Permission}, csrf, error::{AppError, AppErrorKind}, forward::{self, ForwardTo}}};
+
+/// Admins manage everything in the trash, other users only what they deleted
+fn can_manage(user: &User, item: &TrashItem) -> bool {
+    user.authority == UserAuthority::Admin || item.deleted_by == user.username
+}
+
+pub async fn get_trash(auth: Auth, hb: web::Data<Handlebars<'_>>, flashes: IncomingFlashMessages) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::Delete)
+        .map_err(|k| AppError::page(k, ForwardTo::Trash))?;
+    let items: Vec<TrashItem> = web::block(trash::list).await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|item| can_manage(&user, item))
+        .collect();
+    let size: u64 = items.iter().map(|item| item.size).sum();
+    let items: Vec<serde_json::Value> = items.iter().map(TrashItem::to_json).collect();
+    let flashes: Vec<(String,String)> = flashes.iter().map(|f| {(f.level().to_string(), f.content().to_string())}).collect();
+    let data = json! ({
+        "title": "Trash",
+        "user": user,
+        "flashes": flashes,
+        "csrf_token": csrf::token(&auth.session()),
+        "items": items,
+        "size_display": format_size(size),
+        "retention_days": trash::retention_days(),
+        "max_size_display": trash::max_size().map(format_size)
+    });
+    let body = hb.render("trash", &data).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// Puts an item back where it was deleted from, which needs write access there
+pub async fn restore(id: web::Path<String>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::Delete)
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    let item = trash::get(&id)
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    if !can_manage(&user, &item) {
+        return Err(AppError::new(AppErrorKind::PermissionDenied, ForwardTo::Trash));
+    }
+    let original = Folder::from_relative_path(&item.original_path())
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    auth.require_all_in(Permission::Write, &original)
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    auth.audit(Operation::Restore, item.original_path(), None, trash::restore(&item).await)
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    FlashMessage::success(format!("restored '/{}'", item.original_path())).send();
+    Ok(forward::to(ForwardTo::Trash))
+}
+
+pub async fn purge(id: web::Path<String>, auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::Delete)
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    let item = trash::get(&id)
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    if !can_manage(&user, &item) {
+        return Err(AppError::new(AppErrorKind::PermissionDenied, ForwardTo::Trash));
+    }
+    auth.audit(Operation::Purge, item.original_path(), None, trash::purge(&item).await)
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    FlashMessage::success(format!("permanently deleted '/{}'", item.original_path())).send();
+    Ok(forward::to(ForwardTo::Trash))
+}
+
+/// Purges every item the user can manage
+pub async fn empty_trash(auth: Auth) -> Result<HttpResponse, AppError> {
+    let user = auth.require(Permission::Delete)
+        .map_err(|k| AppError::new(k, ForwardTo::Trash))?;
+    let items = web::block(trash::list).await.unwrap_or_default();
+    let mut count = 0;
+    for item in items.iter().filter(|item| can_manage(&user, item)) {
+        match auth.audit(Operation::Purge, item.original_path(), None, trash::purge(item).await) {
+            Ok(()) => count += 1,
+            Err(e) => FlashMessage::error(e.to_string()).send()
+        }
+    }
+    if count > 0 {
+        FlashMessage::success(format!("permanently deleted {} files/folders", count)).send();
+    }
+    Ok(forward::to(ForwardTo::Trash))
+}
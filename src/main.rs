@@ -1,12 +1,11 @@
 use std::{fs::File, io::BufReader, sync::{Arc, Mutex}};
-use actix_web::{middleware::Logger, App, HttpServer, web};
+use actix_web::{dev::Service, middleware::Logger, App, HttpServer, web};
 use actix_files::Files;
 use actix_web_flash_messages::{FlashMessagesFramework, storage::CookieMessageStore, Level};
-use actix_session::{SessionMiddleware, storage::CookieSessionStore, config::PersistentSession};
+use actix_session::{SessionMiddleware, config::{PersistentSession, TtlExtensionPolicy}};
 use handlebars::Handlebars;
 use rustls::{Certificate, PrivateKey, ServerConfig};
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use time::Duration;
 
 pub mod tests;
 pub mod app_config;
@@ -16,7 +15,8 @@ pub mod util;
 
 use app_config::config_app;
 
-use crate::util::timelapse;
+use crate::models::{folder::Folder, trash, user::User};
+use crate::util::{auth, csrf::CsrfProtection, index::{self, FileIndex}, jobs::{self, JobQueue}, live::LiveUpdates, sessions::{ServerSessionStore, SessionRegistry}, throttle::LoginThrottle, timelapse};
 
 #[cfg(debug_assertions)]
 const HOST: &str = "127.0.0.1";
@@ -39,6 +39,10 @@ async fn main() -> std::io::Result<()> {
         .default_filter_or(if LOG_LEVEL == Level::Debug { "debug" } else { "info" })
     );
 
+    User::init_store().unwrap_or_else(|err| {
+        panic!("Could not create the users file {}: {}", User::users_file(), err);
+    });
+
     let mut hbars = Handlebars::new();
     hbars.set_dev_mode(LOG_LEVEL == Level::Debug);
     hbars.register_templates_directory(".hbs", "./static/templates").unwrap();
@@ -52,23 +56,62 @@ async fn main() -> std::io::Result<()> {
     let rustls_config = init_rustls_config();
 
     let timelapse_thread = web::Data::new(Arc::new(Mutex::new(timelapse::TimelapseThread::new())));
+    let login_throttle = web::Data::new(Arc::new(Mutex::new(LoginThrottle::new())));
+    let session_registry = Arc::new(Mutex::new(SessionRegistry::load()));
+    let session_ttl = session_registry.lock().unwrap().cookie_ttl();
+    let session_store = ServerSessionStore::new(session_registry.clone());
+    let session_registry = web::Data::new(session_registry);
+    let file_index = Arc::new(Mutex::new(FileIndex::new()));
+    let live_updates = Arc::new(Mutex::new(LiveUpdates::new()));
+    if let Err(err) = index::start(file_index.clone(), live_updates.clone()) {
+        log::error!("could not start the indexer, searches will read the disk: {}", err);
+    }
+    let file_index = web::Data::new(file_index);
+    let job_queue = Arc::new(Mutex::new(JobQueue::new()));
+    if let Err(err) = jobs::start(job_queue.clone()) {
+        log::error!("could not start the job workers, copies, zips and unzips will wait forever: {}", err);
+    }
+    let job_queue = web::Data::new(job_queue);
+    let live_updates = web::Data::new(live_updates);
+    match Folder::canonical_root() {
+        Ok(root) if trash::is_exposed(&root) => log::warn!("the trash folder {} can be browsed, set FS_TRASH_FOLDER to a folder outside the root folder", trash::trash_folder().display()),
+        _ => ()
+    }
+    if let Err(err) = trash::start_purging() {
+        log::error!("could not start purging the trash: {}", err);
+    }
     
     log::info!("starting HTTP server at http://{}:{}", HOST, PORT);
     HttpServer::new(move || {
         App::new()
             .app_data(hbars_ref.clone())
             .app_data(timelapse_thread.clone())
+            .app_data(login_throttle.clone())
+            .app_data(session_registry.clone())
+            .app_data(file_index.clone())
+            .app_data(job_queue.clone())
+            .app_data(live_updates.clone())
+            .wrap(CsrfProtection)
             .wrap(SessionMiddleware::builder(
-                    CookieSessionStore::default(),
+                    session_store.clone(),
                     private_key.to_owned())
                 .cookie_name("session_cookie".to_string())
                 .session_lifecycle(PersistentSession::default()
-                    .session_ttl(Duration::days(1)))
+                    .session_ttl(session_ttl)
+                    .session_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest))
                 .build()
             )
             .service(Files::new("/static", "static").show_files_listing())
             .wrap(message_framework.clone())
             .configure(config_app)
+            .wrap_fn(|req, srv| {
+                let is_token = auth::bearer_token(req.request()).is_some();
+                let res = srv.call(req);
+                async move {
+                    let res = res.await?;
+                    Ok(if is_token { auth::api_response(res) } else { res.map_into_boxed_body() })
+                }
+            })
             .wrap(Logger::default())
     })
     .bind_rustls((HOST, PORT), rustls_config)?
@@ -103,10 +146,9 @@ fn open_cert_file() -> Vec<Certificate> {
     let cert_file = &mut BufReader::new(File::open("private/cert.pem").unwrap_or_else(|_| {
         panic!("Could not find or read private/cert.pem. If you do not have one, for personal use README.md explains how to create one yourself.");
     }));
-    let cert_chain = certs(cert_file)
+    certs(cert_file)
         .unwrap()
         .into_iter()
         .map(Certificate)
-        .collect();
-    cert_chain
+        .collect()
 }
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+
+use crate::util::{auth::Permission, error::AppErrorKind};
+
+/// How much of a path a user can see. Ordered from least to most access
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Deny,
+    /// The folder itself is denied but it leads to a folder that is not,
+    /// so it can be listed showing only the entries on the way there
+    Traverse,
+    Read,
+    Write,
+}
+
+impl Access {
+    pub fn permits(&self, permission: Permission) -> bool {
+        match self {
+            Access::Deny => false,
+            Access::Traverse => permission == Permission::Read,
+            Access::Read => matches!(permission, Permission::Read | Permission::Download),
+            Access::Write => true
+        }
+    }
+}
+
+/// Grants or denies access to a folder and everything under it.
+/// The path is relative to the root folder and separated with '/'. Ex. "photos/2022"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccessRule {
+    pub path: String,
+    pub access: Access,
+}
+
+impl AccessRule {
+    /// Parses a rule written as "<access> <path>". Ex. "read photos/2022"
+    pub fn parse(line: &str) -> Result<Self, AppErrorKind> {
+        let (access, path) = line.trim().split_once(char::is_whitespace)
+            .ok_or_else(|| AppErrorKind::AccessRuleInvalid(line.to_owned()))?;
+        let access = match access.to_lowercase().as_str() {
+            "deny" => Access::Deny,
+            "read" => Access::Read,
+            "write" => Access::Write,
+            _ => return Err(AppErrorKind::AccessRuleInvalid(line.to_owned()))
+        };
+        let path = normalize(path).ok_or_else(|| AppErrorKind::AccessRuleInvalid(line.to_owned()))?;
+        Ok(Self { path, access })
+    }
+}
+
+impl std::fmt::Display for AccessRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", format!("{:?}", self.access).to_lowercase(), self.path)
+    }
+}
+
+/// Decides what a single user can access. The most specific rule matching a path wins.
+/// A user with a home folder is jailed to it: everything not granted by a rule is denied.
+#[derive(Debug, Clone)]
+pub struct AccessList {
+    rules: Vec<AccessRule>,
+    default: Access,
+    /// Folder everything is further limited to, like the folder of an API token
+    scope: Option<String>,
+    max: Access,
+}
+
+impl AccessList {
+    pub fn new(home: Option<&str>, acl: &[AccessRule]) -> Self {
+        let mut rules = acl.to_vec();
+        let default = match home {
+            Some(home) => {
+                rules.push(AccessRule { path: home.to_owned(), access: Access::Write });
+                Access::Deny
+            },
+            None => Access::Write
+        };
+        Self { rules, default, scope: None, max: Access::Write }
+    }
+
+    /// Limits the list to a folder and caps the access anywhere to max
+    pub fn restrict(mut self, folder: Option<&str>, max: Access) -> Self {
+        self.scope = folder.map(str::to_owned);
+        self.max = max;
+        self
+    }
+
+    /// Returns the access for a path relative to the root folder
+    pub fn access(&self, path: &str) -> Access {
+        let target = components(path);
+        let access = match &self.scope {
+            Some(scope) => {
+                let scope = components(scope);
+                if target.starts_with(&scope) {
+                    self.rule_access(&target)
+                }
+                else if scope.starts_with(&target) {
+                    self.rule_access(&target).min(Access::Traverse)
+                }
+                else {
+                    Access::Deny
+                }
+            },
+            None => self.rule_access(&target)
+        };
+        access.min(self.max)
+    }
+
+    fn rule_access(&self, target: &[&str]) -> Access {
+        let mut best: Option<(usize, Access)> = None;
+        for rule in &self.rules {
+            let rule_path = components(&rule.path);
+            if target.starts_with(&rule_path) && best.is_none_or(|(len, _)| rule_path.len() > len) {
+                best = Some((rule_path.len(), rule.access));
+            }
+        }
+        let access = best.map_or(self.default, |(_, access)| access);
+        if access == Access::Deny && self.rules_below(target).any(|rule| rule.access >= Access::Read) {
+            return Access::Traverse;
+        }
+        access
+    }
+
+    /// Returns the lowest access of the path and anything below it. Used for operations
+    /// that carry a whole folder along, so a restricted subfolder cannot be moved or
+    /// copied out from under its rule
+    pub fn subtree_access(&self, path: &str) -> Access {
+        let target = components(path);
+        self.rules_below(&target)
+            .map(|rule| rule.access)
+            .fold(self.access(path), Access::min)
+    }
+
+    fn rules_below<'a>(&'a self, target: &'a [&'a str]) -> impl Iterator<Item = &'a AccessRule> {
+        self.rules.iter().filter(move |rule| {
+            let rule_path = components(&rule.path);
+            rule_path.len() > target.len() && rule_path.starts_with(target)
+        })
+    }
+}
+
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+/// Trims slashes and rejects paths that could step outside the root folder
+pub fn normalize(path: &str) -> Option<String> {
+    let parts = components(path.trim());
+    if parts.iter().any(|c| *c == "." || *c == "..") {
+        return None;
+    }
+    Some(parts.join("/"))
+}
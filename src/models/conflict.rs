@@ -0,0 +1,129 @@
+use std::{fs, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{models::{folder::Folder, trash}, util::error::AppErrorKind};
+
+/// What happens when a folder or file is written where one of the same name already is
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// The item is not written and reported as failed
+    #[default]
+    Fail,
+    /// The one already there is moved to the trash
+    Overwrite,
+    /// The item is left out and the one already there is kept
+    Skip,
+    /// The item is written under a new name like "notes(2).txt"
+    Rename,
+    /// Whichever was modified last is kept. New content like an upload counts as newest
+    KeepNewer,
+}
+
+impl ConflictPolicy {
+    pub const ALL: [ConflictPolicy; 5] = [
+        ConflictPolicy::Fail, ConflictPolicy::Overwrite, ConflictPolicy::Skip,
+        ConflictPolicy::Rename, ConflictPolicy::KeepNewer
+    ];
+
+    /// Reads a policy by the name it has in forms, ex. "keep_newer"
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|policy| serde_json::to_value(policy).is_ok_and(|value| value == name.trim()))
+    }
+
+    /// Decides where an item goes when it is written to target, making room for it if the
+    /// policy replaces what is there. Modified is when the item itself last changed
+    pub fn resolve(self, target: &Folder, modified: Option<SystemTime>, user: &str) -> Result<Resolution, AppErrorKind> {
+        let existing = match fs::symlink_metadata(target.to_path()?) {
+            Ok(existing) => existing,
+            Err(_) => return Ok(Resolution::Write(target.clone(), Outcome::Done))
+        };
+        let overwrite = || -> Result<Resolution, AppErrorKind> {
+            trash::move_to_trash(target, user)?;
+            Ok(Resolution::Write(target.clone(), Outcome::Overwritten))
+        };
+        match self {
+            ConflictPolicy::Fail => Err(AppErrorKind::EntityAlreadyExists(target.relative_path())),
+            ConflictPolicy::Skip => Ok(Resolution::Skip),
+            ConflictPolicy::Rename => {
+                let name = target.create_unique_name()?;
+                Ok(Resolution::Write(target.parent()?.join(&name)?, Outcome::Renamed(name)))
+            },
+            ConflictPolicy::Overwrite => overwrite(),
+            ConflictPolicy::KeepNewer => {
+                let existing_is_newer = modified.is_some_and(|modified| existing.modified().is_ok_and(|existing| existing >= modified));
+                if existing_is_newer { Ok(Resolution::Skip) } else { overwrite() }
+            }
+        }
+    }
+}
+
+pub enum Resolution {
+    /// Write the item here, where nothing is in the way now
+    Write(Folder, Outcome),
+    /// Leave the item out
+    Skip,
+}
+
+/// What happened to one item of a move, upload or unzip
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Nothing was in the way
+    Done,
+    /// What was in the way was moved to the trash
+    Overwritten,
+    /// Written under this name instead
+    Renamed(String),
+    /// Left out, what was in the way is kept
+    Skipped,
+    Failed(String),
+}
+
+impl Outcome {
+    /// If the item was written, under any name
+    pub fn is_written(&self) -> bool {
+        matches!(self, Outcome::Done | Outcome::Overwritten | Outcome::Renamed(_))
+    }
+
+    /// The name an item sent as name was written under, None if it was not written
+    pub fn written_name<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        match self {
+            Outcome::Renamed(new_name) => Some(new_name),
+            outcome if outcome.is_written() => Some(name),
+            _ => None
+        }
+    }
+}
+
+impl From<Result<Outcome, AppErrorKind>> for Outcome {
+    fn from(result: Result<Outcome, AppErrorKind>) -> Self {
+        result.unwrap_or_else(|err| Outcome::Failed(err.to_string()))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ItemOutcome {
+    /// The name the item was sent or selected with
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+impl ItemOutcome {
+    pub fn new(name: &str, outcome: Outcome) -> Self {
+        Self { name: name.to_owned(), outcome }
+    }
+
+    /// Tells the user about anything other than the item being written as asked
+    pub fn message(&self) -> Option<String> {
+        match &self.outcome {
+            Outcome::Done => None,
+            Outcome::Overwritten => Some(format!("replaced '{}', the old one is in the trash", self.name)),
+            Outcome::Renamed(name) => Some(format!("'{}' already exists, saved as '{}'", self.name, name)),
+            Outcome::Skipped => Some(format!("skipped '{}', kept the one already there", self.name)),
+            Outcome::Failed(err) if err.contains(&self.name) => Some(err.clone()),
+            Outcome::Failed(err) => Some(format!("'{}': {}", self.name, err)),
+        }
+    }
+}
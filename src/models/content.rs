@@ -0,0 +1,145 @@
+use std::{env, fs::File, io::Read, path::Path};
+
+use regex::Regex;
+use serde_json::json;
+
+use crate::util::error::AppErrorKind;
+
+const TEXT_MAX_SIZE_ENV: &str = "FS_TEXT_MAX_SIZE";
+const DEFAULT_TEXT_MAX_SIZE: u64 = 2 << 20;
+/// A file with a nul byte this close to its start is taken to be binary, the same check grep makes
+const BINARY_CHECK_LENGTH: usize = 8000;
+/// Past this many matching lines the rest of a file's matches are left out
+const FILE_HIT_LIMIT: usize = 50;
+/// Lines longer than this many characters are cut short in the results
+const LINE_DISPLAY_LENGTH: usize = 300;
+/// The most lines of context shown around each match
+pub const MAX_CONTEXT: usize = 5;
+
+/// Returns the size of the largest file searched inside or shown as text. Defaults to 2 MB
+/// but can be changed with the FS_TEXT_MAX_SIZE environment variable
+pub fn text_max_size() -> u64 {
+    env::var(TEXT_MAX_SIZE_ENV).ok()
+        .and_then(|size| size.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TEXT_MAX_SIZE)
+}
+
+/// Reads a file as text, unless it is larger than the text size limit or looks binary.
+/// Bytes that are not UTF-8 are replaced rather than failing the whole file
+pub fn read_text(path: &Path) -> Option<String> {
+    let max_size = text_max_size();
+    let file = File::open(path).ok()?;
+    let data = file.metadata().ok()?;
+    if !data.is_file() || data.len() > max_size {
+        return None;
+    }
+    let mut bytes = Vec::new();
+    file.take(max_size).read_to_end(&mut bytes).ok()?;
+    if bytes[..bytes.len().min(BINARY_CHECK_LENGTH)].contains(&0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// What to look for inside files
+pub enum ContentMatcher {
+    /// Lines containing the text, ignoring case
+    Substring(String),
+    Regex(Regex),
+}
+
+impl ContentMatcher {
+    pub fn new(text: &str, regex: bool) -> Result<Self, AppErrorKind> {
+        if regex {
+            Regex::new(text)
+                .map(ContentMatcher::Regex)
+                .map_err(|err| AppErrorKind::SearchInvalid(err.to_string()))
+        }
+        else {
+            Ok(ContentMatcher::Substring(text.to_lowercase()))
+        }
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        match self {
+            ContentMatcher::Substring(text) => line.to_lowercase().contains(text),
+            ContentMatcher::Regex(regex) => regex.is_match(line),
+        }
+    }
+
+    /// Finds the matching lines of a text with up to context lines around each, like grep -C.
+    /// Matches close enough for their context to touch are shown as one group
+    pub fn hits(&self, text: &str, context: usize) -> Option<ContentHits> {
+        let lines: Vec<&str> = text.lines().collect();
+        let matched: Vec<usize> = lines.iter().enumerate()
+            .filter(|(_, line)| self.is_match(line))
+            .map(|(index, _)| index)
+            .take(FILE_HIT_LIMIT + 1)
+            .collect();
+        if matched.is_empty() {
+            return None;
+        }
+        let more = matched.len() > FILE_HIT_LIMIT;
+        let mut groups: Vec<Vec<HitLine>> = Vec::new();
+        let mut shown_until = 0;
+        for &index in matched.iter().take(FILE_HIT_LIMIT) {
+            let start = index.saturating_sub(context);
+            let end = (index + context + 1).min(lines.len());
+            if groups.is_empty() || start > shown_until {
+                groups.push(Vec::new());
+            }
+            let group = groups.last_mut().expect("a group was just pushed");
+            for (number, line) in lines.iter().enumerate().take(end).skip(start.max(shown_until)) {
+                group.push(HitLine { number: number + 1, text: shorten(line), matched: false });
+            }
+            if let Some(line) = group.iter_mut().find(|line| line.number == index + 1) {
+                line.matched = true;
+            }
+            shown_until = shown_until.max(end);
+        }
+        Some(ContentHits { groups, more })
+    }
+}
+
+/// One line shown in the results of a content search
+#[derive(Debug, Clone, PartialEq)]
+pub struct HitLine {
+    /// Counted from 1
+    pub number: usize,
+    pub text: String,
+    /// If the line matched rather than being context around a match
+    pub matched: bool,
+}
+
+/// The lines of a file matching a content search, grouped with the lines around them
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentHits {
+    pub groups: Vec<Vec<HitLine>>,
+    /// If there were more matching lines than are shown
+    pub more: bool,
+}
+
+impl ContentHits {
+    /// Location is where the file is viewed, each line links to its place there
+    pub fn to_json(&self, location: &str) -> serde_json::Value {
+        let groups: Vec<Vec<serde_json::Value>> = self.groups.iter().map(|group| {
+            group.iter().map(|line| json!({
+                "number": line.number,
+                "text": line.text,
+                "matched": line.matched,
+                "location": format!("{}#L{}", location, line.number)
+            })).collect()
+        }).collect();
+        json!({
+            "groups": groups,
+            "more": self.more
+        })
+    }
+}
+
+fn shorten(line: &str) -> String {
+    match line.char_indices().nth(LINE_DISPLAY_LENGTH) {
+        Some((end, _)) => format!("{}\u{2026}", &line[..end]),
+        None => line.to_owned()
+    }
+}
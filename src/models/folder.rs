@@ -1,27 +1,81 @@
 use std::ffi::OsStr;
 use std::{fs, io::Write, time::SystemTime, env};
-use std::io::{Error, ErrorKind};
-use std::path::MAIN_SEPARATOR;
+use std::io::{Error, ErrorKind, Seek, SeekFrom};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
 use actix_multipart::{Multipart, MultipartError};
 use actix_web::web;
 use actix_http::error::ParseError;
 use serde_json::json;
 use futures_util::TryStreamExt;
-
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::models::access::{Access, AccessList};
+use crate::models::conflict::{ConflictPolicy, ItemOutcome, Outcome, Resolution};
+use crate::models::content;
+use crate::models::trash::{self, TrashItem};
+use crate::models::ignore::IgnoreRules;
+use crate::models::listing::{format_size, natural_cmp, Entry, Listing, Page, Sort, SortKey, SortOrder};
+use crate::models::usage::{self, Usage, UsageEntry};
 use crate::util::error::AppErrorKind;
-use crate::util::{zip, time_format};
+use crate::util::{jobs::{self, Progress}, zip, time_format};
 
 const ROOT_URL: &str = "root";
 const ROOT_FOLDER_ENV: &str = "FS_ROOT_FOLDER";
+const SYMLINKS_ENV: &str = "FS_SYMLINKS";
+const DOWNLOAD_FOLDER_ENV: &str = "FS_DOWNLOAD_FOLDER";
+const DEFAULT_DOWNLOAD_FOLDER: &str = "private/downloads";
+/// The multipart field of an upload holding its conflict policy
+const CONFLICT_FIELD: &str = "conflict";
+/// Separates the folder names in a folder path. Names are percent-encoded
+/// so a '+' inside a name never looks like a separator
+const PATH_SEPARATOR: char = '+';
+/// Chooses the folder above as the destination of a move. Not a legal name so it
+/// cannot be mistaken for a folder called the same
+const PARENT_OPTION: &str = "..";
+/// Everything but the unreserved characters of RFC 3986 is encoded in a name
+const NAME_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// What to do with symbolic links found under the root folder. Set with the
+/// FS_SYMLINKS environment variable to "follow", "never" or "deny"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Links are listed and followed if they resolve to somewhere inside the root folder. The default
+    FollowInsideRoot,
+    /// Links are hidden and never followed
+    Never,
+    /// Links are listed but opening them is denied
+    ShowButDeny,
+}
+
+impl SymlinkPolicy {
+    pub fn from_env() -> Self {
+        match env::var(SYMLINKS_ENV).as_deref() {
+            Err(_) | Ok("follow") => Self::FollowInsideRoot,
+            Ok("never") => Self::Never,
+            Ok("deny") => Self::ShowButDeny,
+            Ok(other) => {
+                log::error!("unknown {} value '{}', symbolic links will not be followed", SYMLINKS_ENV, other);
+                Self::Never
+            }
+        }
+    }
+}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Folder {
-    path: String
+    /// The folder names below the root folder, as they are on disk
+    names: Vec<String>
 }
 
-impl Default for Folder {
-    fn default() -> Self {
-        Self { path: ROOT_URL.to_owned() }
+/// Returns the folder path structure separated with '+' with every name percent-encoded.
+/// Ex. "root+test_files+c%2B%2B%20notes"
+impl std::fmt::Display for Folder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", ROOT_URL)?;
+        for name in &self.names {
+            write!(f, "{}{}", PATH_SEPARATOR, utf8_percent_encode(name, NAME_ENCODE_SET))?;
+        }
+        Ok(())
     }
 }
 
@@ -35,32 +89,105 @@ impl Folder {
         }
     }
 
-    /// Creates a new Folder with the given path. The path must start with "root" and 
-    /// follow the pattern of folder names separated by '+'. Ex. "root+test_files+folder name"
+    /// Creates a new Folder from a folder path as returned by to_string. The path must start
+    /// with "root" and follow the pattern of percent-encoded folder names separated by '+'.
+    /// Ex. "root+test_files+folder%20name"
     pub fn new(path: &str) -> Result<Self, AppErrorKind> {
-        if !path.starts_with(ROOT_URL) || path.contains("+..+") {
-            log::error!("------>{}<-----", path);
+        let mut parts = path.split(PATH_SEPARATOR);
+        if parts.next() != Some(ROOT_URL) {
+            log::warn!("invalid folder path '{}'", path);
             return Err(AppErrorKind::FolderPathInvalid);
         }
-        Ok(Self { path: path.to_owned() })
+        parts.try_fold(Self::default(), |folder, part| {
+            let name = percent_decode_str(part).decode_utf8()
+                .map_err(|_| AppErrorKind::FolderPathInvalid)?;
+            folder.join(&name)
+        })
     }
 
-    /// Returns the folder path structure separated with '+'
-    pub fn to_string(&self) -> String {
-        self.path.to_string()
+    /// Returns the folder path encoded once more so it survives being decoded
+    /// as a segment of a url. Used for every link and redirect to the folder
+    pub fn url(&self) -> String {
+        // names are already encoded so the only character left to encode is '%'
+        self.to_string().replace('%', "%25")
     }
 
-    /// Returns the folder path structure compliant to the current OS
-    pub fn to_path(&self) -> String {
-        self.path.replace(&format!("{}+",ROOT_URL), &format!(".{}{}", MAIN_SEPARATOR, Folder::root_folder()))
-                 .replace(ROOT_URL, &format!(".{}{}", MAIN_SEPARATOR, Folder::root_folder()))
-                 .replace("+", &String::from(MAIN_SEPARATOR))
+    /// Returns the canonical location of the root folder on disk
+    pub fn canonical_root() -> Result<PathBuf, AppErrorKind> {
+        fs::canonicalize(format!(".{}{}", MAIN_SEPARATOR, Folder::root_folder())).map_err(Into::into)
     }
 
-    /// Returns a new Folder with path appended on to self.path
-    pub fn join(&self, path: &str) -> Result<Self, AppErrorKind> {
-        let join = format!("{}+{}", self.path, path);
-        Self::new(&join)
+    /// Returns the location of the folder on disk. Every folder on the way is resolved
+    /// and must stay inside the root folder, so neither a symbolic link nor a crafted
+    /// name can reach outside of it. A link as the last name is checked the same way
+    /// but not replaced by its target, so renaming or removing it changes the link itself.
+    /// Ignored files and folders and everything in them cannot be reached
+    pub fn to_path(&self) -> Result<String, AppErrorKind> {
+        self.resolve()?.0.into_os_string().into_string().map_err(|_| AppErrorKind::FolderPathInvalid)
+    }
+
+    /// Resolves the folder like to_path and also returns the ignore rules that apply inside it
+    pub fn resolve(&self) -> Result<(PathBuf, IgnoreRules), AppErrorKind> {
+        let root = Folder::canonical_root()?;
+        let policy = SymlinkPolicy::from_env();
+        let mut rules = IgnoreRules::global();
+        let mut path = root.clone();
+        for (index, name) in self.names.iter().enumerate() {
+            rules.add_folder(&path, index);
+            let next = path.join(name);
+            if rules.is_ignored(&self.names[..=index], &next) {
+                log::warn!("denied ignored path '{}'", self.relative_path());
+                return Err(AppErrorKind::PathIgnored);
+            }
+            let is_last = index + 1 == self.names.len();
+            if !fs::symlink_metadata(&next).is_ok_and(|data| data.file_type().is_symlink()) {
+                path = next;
+                continue;
+            }
+            if policy != SymlinkPolicy::FollowInsideRoot {
+                log::warn!("denied symbolic link '{}'", self.relative_path());
+                return Err(AppErrorKind::SymlinkDenied);
+            }
+            match fs::canonicalize(&next) {
+                Ok(target) if !target.starts_with(&root) => {
+                    log::warn!("denied '{}' which resolves to '{}' outside the root folder", self.relative_path(), target.display());
+                    return Err(AppErrorKind::PathEscapesRoot);
+                },
+                Ok(target) => path = if is_last { next } else { target },
+                // a broken link can still be renamed or removed
+                Err(_) if is_last => path = next,
+                Err(err) => return Err(err.into())
+            }
+        }
+        rules.add_folder(&path, self.names.len());
+        Ok((path, rules))
+    }
+
+    /// Creates a Folder from a path relative to the root folder separated with '/'
+    pub fn from_relative_path(path: &str) -> Result<Self, AppErrorKind> {
+        path.split('/').filter(|c| !c.is_empty()).try_fold(Self::default(), |folder, name| folder.join(name))
+    }
+
+    /// The folder names below the root folder
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Returns the folder path relative to the root folder separated with '/'. Ex. "test_files/folder name"
+    pub fn relative_path(&self) -> String {
+        self.names.join("/")
+    }
+
+    /// Returns a new Folder for the file or folder called name inside this one.
+    /// The name must be a single legal file name, not a path
+    pub fn join(&self, name: &str) -> Result<Self, AppErrorKind> {
+        if !is_valid_name(name) {
+            log::warn!("invalid name '{}' in '{}'", name, self);
+            return Err(AppErrorKind::FolderPathInvalid);
+        }
+        let mut names = self.names.clone();
+        names.push(name.to_owned());
+        Ok(Self { names })
     }
 
     /// Returns a list of the folder path parents starting with root
@@ -79,8 +206,7 @@ impl Folder {
 
     /// Returns the name of the folder
     pub fn name(&self) -> &str {
-        let folders = self.path.split("+");
-        folders.last().unwrap()
+        self.names.last().map_or(ROOT_URL, String::as_str)
     }
 
     /// Returns a Folder representing the parent of the current folder.
@@ -89,148 +215,392 @@ impl Folder {
         if self.is_root() {
             return Err(AppErrorKind::CannotGetParentOfRoot);
         }
-        let mut folders: Vec<&str> = self.path.split("+").collect();
-        folders.pop();
-        Self::new(&folders.join("+"))
+        Ok(Self { names: self.names[..self.names.len() - 1].to_vec() })
     }
 
     pub fn is_root(&self) -> bool {
-        self.path == ROOT_URL
+        self.names.is_empty()
     }
+
+    /// The folder something in this folder is moved to. Either any folder, as a folder path
+    /// like "root+photos", or a folder next to it by name, where ".." is the folder above
+    pub fn move_destination(&self, folder_name: &str, destination: &str) -> Result<Self, AppErrorKind> {
+        match (folder_name, destination) {
+            (_, destination) if !destination.is_empty() => Folder::new(destination),
+            ("", _) => Err(AppErrorKind::DestinationMissing),
+            (PARENT_OPTION, _) => self.parent().map_err(|_| AppErrorKind::CannotMoveAboveRoot),
+            (folder_name, _) => self.join(folder_name)
+        }
+    }
+
+    /// Names starting with a '.' are hidden unless the user chose to show them
+    pub fn is_hidden(&self) -> bool {
+        self.name().starts_with('.')
+    }
+}
+
+/// Percent-encodes a file name for use as one segment of a url
+pub fn url_name(name: &str) -> String {
+    utf8_percent_encode(name, NAME_ENCODE_SET).to_string()
+}
+
+/// Moves a folder or file, copying it and removing the original when the two places are on
+/// different filesystems, where it cannot just be renamed. Links are moved as links
+pub fn move_path(from: &Path, to: &Path) -> std::io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+            if let Err(err) = copy_path(from, to) {
+                // leave the original as it was rather than half in both places
+                let _ = remove_path(to);
+                return Err(err);
+            }
+            remove_path(from)
+        },
+        result => result
+    }
+}
+
+fn copy_path(from: &Path, to: &Path) -> std::io::Result<()> {
+    let data = fs::symlink_metadata(from)?;
+    if data.file_type().is_symlink() {
+        copy_link(from, to)
+    }
+    else if data.is_dir() {
+        fs::create_dir(to)?;
+        for dir_entry in fs::read_dir(from)? {
+            let dir_entry = dir_entry?;
+            copy_path(&dir_entry.path(), &to.join(dir_entry.file_name()))?;
+        }
+        fs::set_permissions(to, data.permissions())
+    }
+    else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+#[cfg(unix)]
+fn copy_link(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_link(from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, format!("cannot copy link '{}'", from.display())))
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(data) if data.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) => Err(err)
+    }
+}
+
+/// Copies the source file to target, removing the copy again if it fails part way
+fn copy_new_file(source: &Folder, target: &Folder, progress: &Progress) -> Result<(), AppErrorKind> {
+    let target_path = target.to_path()?;
+    if let Err(err) = jobs::copy_file(Path::new(&source.to_path()?), Path::new(&target_path), progress) {
+        let _ = fs::remove_file(&target_path);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Any name Linux allows in a directory except the entries for the directory and its parent
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
 }
 
 /// Handles calls to fs functions
 impl Folder {
 
-    pub fn entity_list(&self, folders_only: bool) -> Result<(Vec<serde_json::Value>, Vec<serde_json::Value>), AppErrorKind> {
-        let entities = self.entities(folders_only)?;
-        Ok((
-            entities.0.into_iter().map(|folder| { json!({
-                    "path": folder.to_string(),
-                    "name": folder.name()
-                })
-            }).collect(),
-            entities.1.into_iter().map(|file| { json!({
-                    "path": file.to_string(),
-                    "name": file.name()
-                })
-            }).collect()
-        ))
+    /// Returns a page of the folders and files in this folder, sorted with sort, leaving out
+    /// the ones denied by access and, unless show_hidden is set, names starting with a '.'.
+    /// Sorted by name, only the metadata of the entries on the page is read
+    pub fn entity_list(&self, folders_only: bool, access: &AccessList, show_hidden: bool, sort: Sort, page: &Page) -> Result<Listing, AppErrorKind> {
+        let path = PathBuf::from(self.to_path()?);
+        let mut entities = self.visible_entities(folders_only, access, show_hidden)?;
+        let total = entities.0.len() + entities.1.len();
+        let entry = |entity: Folder, is_folder: bool| {
+            let data = fs::metadata(path.join(entity.name())).ok();
+            Entry::new(entity, is_folder, data.as_ref())
+        };
+        let mut entries: Vec<Entry>;
+        let range;
+        if sort.key == SortKey::Name {
+            if sort.order == SortOrder::Desc {
+                entities.0.reverse();
+                entities.1.reverse();
+            }
+            let all: Vec<(Folder, bool)> = entities.0.into_iter().map(|f| (f, true))
+                .chain(entities.1.into_iter().map(|f| (f, false)))
+                .collect();
+            range = page.range(all.iter().map(|(entity, _)| entity.name()), total);
+            entries = all.into_iter().skip(range.0).take(range.1 - range.0)
+                .map(|(entity, is_folder)| entry(entity, is_folder))
+                .collect();
+        }
+        else {
+            let mut folders: Vec<Entry> = entities.0.into_iter().map(|entity| entry(entity, true)).collect();
+            let mut files: Vec<Entry> = entities.1.into_iter().map(|entity| entry(entity, false)).collect();
+            sort.sort(&mut folders);
+            sort.sort(&mut files);
+            folders.append(&mut files);
+            range = page.range(folders.iter().map(|entry| entry.name.as_str()), total);
+            entries = folders;
+            entries.truncate(range.1);
+            entries.drain(..range.0);
+        }
+        let files = entries.split_off(entries.iter().position(|entry| !entry.is_folder).unwrap_or(entries.len()));
+        Ok(Listing { folders: entries, files, total, offset: range.0, limit: page.limit })
+    }
+
+    /// The entities without the hidden ones, unless show_hidden, and the ones the user is denied
+    fn visible_entities(&self, folders_only: bool, access: &AccessList, show_hidden: bool) -> Result<(Vec<Self>, Vec<Self>), AppErrorKind> {
+        let mut entities = self.entities(folders_only)?;
+        entities.0.retain(|entity| entity.is_visible(access, show_hidden));
+        entities.1.retain(|entity| entity.is_visible(access, show_hidden));
+        Ok(entities)
+    }
+
+    /// Returns if the entity is listed for a user, which it is not if it is hidden,
+    /// unless they show hidden files, or denied to them
+    pub fn is_visible(&self, access: &AccessList, show_hidden: bool) -> bool {
+        (show_hidden || !self.is_hidden()) && access.access(&self.relative_path()) != Access::Deny
     }
 
     pub fn entities(&self, folders_only: bool) -> Result<(Vec<Self>, Vec<Self>), AppErrorKind> {
-        let mut dir = fs::read_dir(self.to_path())?;
+        let (path, rules) = self.resolve()?;
+        let dir = fs::read_dir(path)?;
+        let policy = SymlinkPolicy::from_env();
         let mut entities = (Vec::new(), Vec::new());
-        while let Some(entry) = dir.next() {
-            if let Ok(dir_entry) = entry {
-                if let (Ok(file_name), Ok(file_type)) = (dir_entry.file_name().into_string(), dir_entry.file_type()) {
-                    if file_type.is_dir() {
-                        entities.0.push(Self {
-                            path: self.join(&file_name)?.to_string()
-                        });
-                    } else if !folders_only {
-                        entities.1.push(Self {
-                            path: self.join(&file_name)?.to_string()
-                        });
+        for dir_entry in dir.flatten() {
+            if let (Ok(file_name), Ok(mut file_type)) = (dir_entry.file_name().into_string(), dir_entry.file_type()) {
+                let entity = self.join(&file_name)?;
+                if rules.is_ignored(&entity.names, &dir_entry.path()) {
+                    continue;
+                }
+                if file_type.is_symlink() {
+                    let visible = match policy {
+                        SymlinkPolicy::Never => false,
+                        SymlinkPolicy::ShowButDeny => true,
+                        SymlinkPolicy::FollowInsideRoot => entity.to_path().is_ok()
+                    };
+                    if !visible {
+                        continue;
+                    }
+                    if let Ok(data) = fs::metadata(dir_entry.path()) {
+                        file_type = data.file_type();
                     }
-
                 }
+                if file_type.is_dir() {
+                    entities.0.push(entity);
+                } else if !folders_only {
+                    entities.1.push(entity);
+                }
+
             }
         }
         entities.0.sort_by(|a,b| { 
-            a.name().cmp(b.name())
+            natural_cmp(a.name(), b.name())
         });
         entities.1.sort_by(|a,b| { 
-            a.name().cmp(b.name())
+            natural_cmp(a.name(), b.name())
         });
         Ok(entities)
     }
 
-    pub fn details(&self) -> Result<serde_json::Value, AppErrorKind> {
-        self.common_details(None).map_err(Into::into)
+    /// Usage is everything below the folder, from Folder::usage
+    pub fn details(&self, usage: &Usage) -> Result<serde_json::Value, AppErrorKind> {
+        self.common_details(None, Some(usage))
     }
 
     pub fn file_details(&self, file_name: &str) -> Result<serde_json::Value, AppErrorKind> {
-        self.common_details(Some(file_name)).map_err(Into::into)
+        self.common_details(Some(file_name), None)
+    }
+
+    /// Measures everything below the folder off the async executor
+    pub async fn usage(&self) -> Result<Usage, AppErrorKind> {
+        let path = self.to_path()?;
+        match web::block(move || usage::measure(Path::new(&path))).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_e) => Err(AppErrorKind::FailedToMeasureFolder)
+        }
+    }
+
+    /// Measures every folder and file directly in the folder the user can see, largest first
+    pub async fn usage_breakdown(&self, access: &AccessList, show_hidden: bool) -> Result<Vec<UsageEntry>, AppErrorKind> {
+        let path = PathBuf::from(self.to_path()?);
+        let (folders, files) = self.visible_entities(false, access, show_hidden)?;
+        let follow_links = SymlinkPolicy::from_env() == SymlinkPolicy::FollowInsideRoot;
+        let measure = move || {
+            let is_followed = |entity_path: &Path| follow_links || !fs::symlink_metadata(entity_path).is_ok_and(|data| data.file_type().is_symlink());
+            let mut entries: Vec<UsageEntry> = folders.into_iter().map(|entity| {
+                let entity_path = path.join(entity.name());
+                let usage = Some(&entity_path).filter(|p| is_followed(p))
+                    .and_then(|p| usage::measure(p).ok())
+                    .unwrap_or_default();
+                UsageEntry { folder: entity, is_folder: true, usage }
+            }).chain(files.into_iter().map(|entity| {
+                let entity_path = path.join(entity.name());
+                let size = Some(&entity_path).filter(|p| is_followed(p))
+                    .and_then(|p| fs::metadata(p).ok())
+                    .map(|data| data.len())
+                    .unwrap_or(0);
+                UsageEntry { folder: entity, is_folder: false, usage: Usage { size, files: 1, folders: 0 } }
+            })).collect();
+            usage::sort_largest(&mut entries);
+            entries
+        };
+        web::block(measure).await.map_err(|_| AppErrorKind::FailedToMeasureFolder)
     }
 
     pub fn is_file_image(&self, file_name: &str) -> Result<bool, AppErrorKind> {
-        let path = fs::canonicalize(self.join(&file_name)?.to_path())?;
+        let path = fs::canonicalize(self.join(file_name)?.to_path()?)?;
         let ext = path.extension().unwrap_or(OsStr::new("Unknown")).to_str().unwrap_or("unknown");
         Ok(ext == "ico" || ext == "jpg")
     }
 
     pub fn file_content_path(&self, file_name: &str) -> Result<String, AppErrorKind> {
-        Ok(format!("{}fs{}{}{}content{}{}", MAIN_SEPARATOR, MAIN_SEPARATOR, self.to_string(), MAIN_SEPARATOR, MAIN_SEPARATOR, file_name))
+        Ok(format!("/fs/{}/content/{}", self.url(), url_name(file_name)))
     }
 
     pub fn create_dir(&self, folder_name: &str) -> Result<(), AppErrorKind> {
-        fs::create_dir(self.join(&folder_name)?.to_path()).map_err(Into::into)
+        fs::create_dir(self.join(folder_name)?.to_path()?).map_err(Into::into)
     }
 
     pub fn rename(&mut self, name: &str) -> Result<(), AppErrorKind> {
         if self.is_root() {
             return Err(AppErrorKind::CannotRenameRoot);
         }
-        let new_folder = self.parent()?.join(&name)?;
-        let result = fs::rename(self.to_path(), new_folder.to_path());
+        let new_folder = self.parent()?.join(name)?;
+        let result = fs::rename(self.to_path()?, new_folder.to_path()?);
         if let Ok(()) = result {
-            self.path = new_folder.path
+            self.names = new_folder.names
         }
         result.map_err(Into::into)
     }
 
     pub fn rename_file(&self, old_name: &str, new_name: &str) -> Result<(), AppErrorKind> {
-        fs::rename(self.join(old_name)?.to_path(), self.join(new_name)?.to_path()).map_err(Into::into)
+        fs::rename(self.join(old_name)?.to_path()?, self.join(new_name)?.to_path()?).map_err(Into::into)
     }
 
-    pub fn move_entity(&self, entity_name: &str, new_folder: &Folder) -> Result<(), AppErrorKind> {
-        fs::rename(self.join(entity_name)?.to_path(), new_folder.join(entity_name)?.to_path()).map_err(Into::into)
+    /// Moves a folder or file into new_folder, where the policy decides what happens if
+    /// the name is taken. Returns what was done, the name it has there if it was renamed
+    pub fn move_entity(&self, entity_name: &str, new_folder: &Folder, policy: ConflictPolicy, user: &str) -> Result<Outcome, AppErrorKind> {
+        let source = self.join(entity_name)?;
+        let source_path = PathBuf::from(source.to_path()?);
+        let modified = fs::symlink_metadata(&source_path)?.modified().ok();
+        match policy.resolve(&new_folder.join(entity_name)?, modified, user)? {
+            Resolution::Write(target, outcome) => {
+                move_path(&source_path, Path::new(&target.to_path()?))?;
+                Ok(outcome)
+            },
+            Resolution::Skip => Ok(Outcome::Skipped)
+        }
     }
 
-    pub fn copy(&self) -> Result<Self, AppErrorKind> {
+    pub fn copy(&self, progress: &Progress) -> Result<Self, AppErrorKind> {
         if self.is_root() {
             return Err(AppErrorKind::CannotCopyRoot);
         }
-        let new_name = self.create_unique_name();
+        let new_name = self.create_unique_name()?;
         let new_folder = self.parent()?.join(&new_name)?;
-        fs::create_dir(new_folder.to_path())?;
-        self.copy_to(self, &new_folder)?;
+        self.copy_new_folder(self, &new_folder, progress)?;
         Ok(new_folder)
     }
 
-    pub fn copy_to(&self, source_folder: &Self, target_folder: &Self) -> Result<(), AppErrorKind> {
+    pub fn copy_to(&self, source_folder: &Self, target_folder: &Self, progress: &Progress) -> Result<(), AppErrorKind> {
         let entities = source_folder.entities(false)?;
         for folder in entities.0 {
+            // a linked folder could link back to a folder it is in and never end
+            if folder.is_symlink()? {
+                log::warn!("skipped copying linked folder '{}'", folder.relative_path());
+                continue;
+            }
             let new_folder = target_folder.join(folder.name())?;
-            fs::create_dir(new_folder.to_path())?;
-            self.copy_to(&folder, &new_folder)?;
+            fs::create_dir(new_folder.to_path()?)?;
+            self.copy_to(&folder, &new_folder, progress)?;
         }
         for file in entities.1 {
-            fs::copy(file.to_path(), target_folder.join(file.name())?.to_path())?;
+            let path = match file.to_path() {
+                Ok(path) => path,
+                Err(AppErrorKind::SymlinkDenied) => continue,
+                Err(err) => return Err(err)
+            };
+            jobs::copy_file(Path::new(&path), Path::new(&target_folder.join(file.name())?.to_path()?), progress)?;
         }
         Ok(())
     }
 
-    pub fn copy_file(&self, entity_name: &str) -> Result<Folder, AppErrorKind> {
+    /// Creates target_folder and copies source_folder into it. If the copy fails or is
+    /// cancelled part way, what was copied is removed rather than left half done
+    fn copy_new_folder(&self, source_folder: &Self, target_folder: &Self, progress: &Progress) -> Result<(), AppErrorKind> {
+        let target_path = target_folder.to_path()?;
+        fs::create_dir(&target_path)?;
+        if let Err(err) = self.copy_to(source_folder, target_folder, progress) {
+            let _ = remove_path(Path::new(&target_path));
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub fn copy_file(&self, entity_name: &str, progress: &Progress) -> Result<Folder, AppErrorKind> {
         let file = self.join(entity_name)?;
-        let new_name = file.create_unique_name();
-        fs::copy(file.to_path(), self.join(&new_name)?.to_path())?;
-        Ok(self.join(&new_name)?)
+        let new_name = file.create_unique_name()?;
+        copy_new_file(&file, &self.join(&new_name)?, progress)?;
+        self.join(&new_name)
     }
 
-    pub fn create_unique_name(&self) -> String {
+    /// Copies a folder or file into target_folder, where the policy decides what happens if
+    /// the name is taken. Copying into the folder it is in always gives the copy a new name
+    pub fn copy_into(&self, entity_name: &str, target_folder: &Folder, policy: ConflictPolicy, user: &str, progress: &Progress) -> Result<Outcome, AppErrorKind> {
+        let source = self.join(entity_name)?;
+        if target_folder.is_inside(&source) {
+            return Err(AppErrorKind::CannotCopyFolderIntoItself);
+        }
+        let data = fs::metadata(source.to_path()?)?;
+        if target_folder == self {
+            let copy = if data.is_dir() { source.copy(progress)? } else { self.copy_file(entity_name, progress)? };
+            return Ok(Outcome::Renamed(copy.name().to_owned()));
+        }
+        match policy.resolve(&target_folder.join(entity_name)?, data.modified().ok(), user)? {
+            Resolution::Write(target, outcome) => {
+                if data.is_dir() {
+                    self.copy_new_folder(&source, &target, progress)?;
+                }
+                else {
+                    copy_new_file(&source, &target, progress)?;
+                }
+                Ok(outcome)
+            },
+            Resolution::Skip => Ok(Outcome::Skipped)
+        }
+    }
+
+    /// Returns if this is the other folder or somewhere below it
+    pub fn is_inside(&self, other: &Folder) -> bool {
+        self.names.starts_with(&other.names)
+    }
+
+    /// Returns if the last name of the folder is a symbolic link
+    pub fn is_symlink(&self) -> Result<bool, AppErrorKind> {
+        let parent = self.parent()?.to_path()?;
+        Ok(fs::symlink_metadata(Path::new(&parent).join(self.name())).is_ok_and(|data| data.file_type().is_symlink()))
+    }
+
+    pub fn create_unique_name(&self) -> Result<String, AppErrorKind> {
         let mut count = 2;
-        let path = self.to_path();
-        let path = std::path::Path::new(&path);
+        let path = self.to_path()?;
+        let path = Path::new(&path);
         let stem = path.file_stem().unwrap().to_str().unwrap();
         let parent_path = path.parent().unwrap();
         let mut name = path.file_name().unwrap().to_str().unwrap().to_owned();
         loop {
             let path = parent_path.join(&name);
             let exists = path.try_exists();
-            if exists.is_ok() && exists.unwrap() == false {
-                return name;
+            if exists.is_ok() && !exists.unwrap() {
+                return Ok(name);
             }
             match path.extension() {
                 Some(ext) => {
@@ -244,16 +614,53 @@ impl Folder {
         }
     }
 
-    pub async fn upload_file(&self, mut payload: Multipart) -> Result<Vec<String>, MultipartError> {
-        let mut file_names = Vec::new();
+    /// Writes the files of a multipart upload into the folder. A "conflict" field sent before
+    /// the files chooses what happens to the ones whose name is taken, by default they fail
+    pub async fn upload_file(&self, mut payload: Multipart, user: &str) -> Result<Vec<ItemOutcome>, MultipartError> {
+        let mut policy = ConflictPolicy::default();
+        let mut outcomes = Vec::new();
         // iterate over multipart stream
         while let Some(mut field) = payload.try_next().await? {
             let file_name = field.content_disposition().get_filename().unwrap_or_default().to_string();
-            file_names.push(file_name.clone());
+            if field.name() == CONFLICT_FIELD {
+                let mut value = Vec::new();
+                while let Some(chunk) = field.try_next().await? {
+                    value.extend_from_slice(&chunk);
+                }
+                policy = ConflictPolicy::parse(&String::from_utf8_lossy(&value)).unwrap_or_default();
+                continue;
+            }
+            // an empty file input is sent as a field without a name
+            if file_name.is_empty() {
+                continue;
+            }
+            let file = match self.join(&file_name) {
+                Ok(file) => file,
+                Err(e) => return Err(MultipartError::Parse(ParseError::Io(
+                    Error::new(ErrorKind::InvalidInput, e.to_string()))))
+            };
+            let resolve_user = user.to_owned();
+            let resolve = move || match policy.resolve(&file, None, &resolve_user) {
+                Ok(Resolution::Write(file, outcome)) => file.to_path()
+                    .map(|path| (Some(path), outcome))
+                    .unwrap_or_else(|e| (None, Outcome::Failed(e.to_string()))),
+                Ok(Resolution::Skip) => (None, Outcome::Skipped),
+                Err(e) => (None, Outcome::Failed(e.to_string()))
+            };
+            let (file_path, outcome) = match web::block(resolve).await {
+                Ok((Some(file_path), outcome)) => (file_path, outcome),
+                Ok((None, outcome)) => {
+                    // the file is not written, so what was sent of it is read and dropped
+                    while field.try_next().await?.is_some() {}
+                    outcomes.push(ItemOutcome::new(&file_name, outcome));
+                    continue;
+                },
+                Err(e) => return Err(MultipartError::Parse(ParseError::Io(
+                    Error::new(ErrorKind::WouldBlock, e))))
+            };
             // log::debug!("field: {:?}", file_name);
-            // File::create is blocking operation, use threadpool
-            let file_path = self.join(&file_name).unwrap().to_path();
-            let mut file = match web::block(move || std::fs::File::create(file_path)).await {
+            // creating the file is blocking operation, use threadpool
+            let mut file = match web::block(move || fs::OpenOptions::new().write(true).create_new(true).open(file_path)).await {
                 Ok(Ok(f)) => f,
                 Ok(Err(e)) => return Err(MultipartError::Parse(ParseError::Io(e))),
                 Err(e) => return Err(MultipartError::Parse(ParseError::Io(
@@ -271,62 +678,121 @@ impl Folder {
                         Error::new(ErrorKind::WouldBlock, e))))
                 };
             }
+            outcomes.push(ItemOutcome::new(&file_name, outcome));
         }
-        Ok(file_names)
+        Ok(outcomes)
     }
 
     pub async fn read_file(&self, name: &str) -> Result<Vec<u8>, AppErrorKind> {
-        let file_path = self.join(name)?.to_path();
+        let file_path = self.join(name)?.to_path()?;
         match web::block(move || fs::read(file_path)).await {
             Ok(result) => result.map_err(Into::into),
             Err(_e) => Err(AppErrorKind::FailedToReadFile)
         }
     }
 
-    pub fn remove_file(&self, name: &str) -> Result<(), AppErrorKind> {
-        fs::remove_file(self.join(name)?.to_path()).map_err(Into::into)
+    /// Reads a file as text, or returns None if it is too large or binary
+    pub async fn read_text(&self, name: &str) -> Result<Option<String>, AppErrorKind> {
+        let file_path = self.join(name)?.to_path()?;
+        web::block(move || content::read_text(Path::new(&file_path))).await
+            .map_err(|_e| AppErrorKind::FailedToReadFile)
     }
 
-    pub fn remove(&self) -> Result<(), AppErrorKind> {
-        if self.is_root() {
-            return Err(AppErrorKind::CannotDeleteRoot);
-        }
-        fs::remove_dir_all(self.to_path()).map_err(Into::into)
+    /// Moves the file to the trash, where it can be restored from until it is purged
+    pub async fn remove_file(&self, name: &str, deleted_by: &str) -> Result<TrashItem, AppErrorKind> {
+        trash::move_to_trash_blocking(&self.join(name)?, deleted_by).await
+    }
+
+    /// Moves the folder to the trash, where it can be restored from until it is purged
+    pub async fn remove(&self, deleted_by: &str) -> Result<TrashItem, AppErrorKind> {
+        trash::move_to_trash_blocking(self, deleted_by).await
+    }
+
+    pub fn zip(&self, progress: &Progress) -> Result<(), AppErrorKind> {
+        let parent_path = self.parent()?.to_path()?;
+        let root = Folder::canonical_root()?;
+        let follow_links = SymlinkPolicy::from_env() == SymlinkPolicy::FollowInsideRoot;
+        zip::create_zip_from_folder(&parent_path, self.name(), &root, follow_links, progress)
+            .map_err(Into::into)
     }
 
-    pub async fn zip(&self) -> Result<(), AppErrorKind> {
-        let parent_path = self.parent()?;
-        let folder_name = self.name().to_owned();
-        match web::block(move || zip::create_zip_from_folder(&parent_path.to_path(), &folder_name)).await {
+    /// Zips the named folders and files in this folder into an archive here, where the
+    /// policy decides what happens if the archive's name is taken
+    pub fn zip_entities(&self, names: &[String], archive_name: &str, policy: ConflictPolicy, user: &str, progress: &Progress) -> Result<Outcome, AppErrorKind> {
+        let folder_path = self.entities_path(names)?;
+        let (archive_path, outcome) = match policy.resolve(&self.join(archive_name)?, None, user)? {
+            Resolution::Write(archive, outcome) => (archive.to_path()?, outcome),
+            Resolution::Skip => return Ok(Outcome::Skipped)
+        };
+        let root = Folder::canonical_root()?;
+        let follow_links = SymlinkPolicy::from_env() == SymlinkPolicy::FollowInsideRoot;
+        zip::create_zip_from_entries(&folder_path, names, Path::new(&archive_path), &root, follow_links, progress)
+            .map(|_| outcome)
+            .map_err(Into::into)
+    }
+
+    /// Zips the named folders and files in this folder into an archive on disk, for downloading,
+    /// so a large selection is never held in memory. The archive is written to the folder named
+    /// by FS_DOWNLOAD_FOLDER, defaulting to private/downloads, and its name is removed right away,
+    /// so it is gone once the returned file is closed
+    pub async fn zip_to_file(&self, names: Vec<String>) -> Result<fs::File, AppErrorKind> {
+        let folder_path = self.entities_path(&names)?;
+        let root = Folder::canonical_root()?;
+        let follow_links = SymlinkPolicy::from_env() == SymlinkPolicy::FollowInsideRoot;
+        let zipped = web::block(move || {
+            let downloads = PathBuf::from(env::var(DOWNLOAD_FOLDER_ENV).unwrap_or_else(|_| DEFAULT_DOWNLOAD_FOLDER.to_owned()));
+            fs::create_dir_all(&downloads)?;
+            let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or_default();
+            let archive_path = downloads.join(format!("{}-{}.zip", std::process::id(), nanos));
+            let archive = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&archive_path)?;
+            if let Err(err) = fs::remove_file(&archive_path) {
+                log::error!("could not remove download '{}', it stays until removed by hand: {}", archive_path.display(), err);
+            }
+            let mut archive = zip::zip_entries(&folder_path, &names, &root, follow_links, &Progress::default(), archive)?;
+            archive.seek(SeekFrom::Start(0))?;
+            Ok::<fs::File, Error>(archive)
+        }).await;
+        match zipped {
             Ok(result) => result.map_err(Into::into),
             Err(_e) => Err(AppErrorKind::FailedToZipFolder)
         }
     }
 
-    pub async fn unzip_file(&self, file_name: &str) -> Result<(), AppErrorKind> {
-        let file_path = self.join(file_name)?.to_path();
-        match web::block(move || zip::extract_zip(&file_path)).await {
-            Ok(result) => result.map_err(Into::into),
-            Err(_e) => Err(AppErrorKind::FailedToUnzipFile)
+    /// Returns where this folder is on disk once every name in it has been checked the same way
+    fn entities_path(&self, names: &[String]) -> Result<PathBuf, AppErrorKind> {
+        for name in names {
+            self.join(name)?.to_path()?;
         }
+        Ok(PathBuf::from(self.to_path()?))
     }
 
-    fn common_details(&self, file_name: Option<&str>) -> Result<serde_json::Value, AppErrorKind> {
+    /// Extracts a zip into a folder named after it, where the policy decides what happens
+    /// to each file already there
+    pub fn unzip_file(&self, file_name: &str, policy: ConflictPolicy, user: &str, progress: &Progress) -> Result<Vec<ItemOutcome>, AppErrorKind> {
+        let file_path = self.join(file_name)?.to_path()?;
+        let extracted = self.join(&file_name.replace(".tar.bz2", "").replace(".zip", ""))?;
+        let root = Folder::canonical_root()?;
+        zip::extract_zip(&file_path, &extracted, &root, policy, user, progress)
+            .map_err(Into::into)
+    }
+
+    fn common_details(&self, file_name: Option<&str>, usage: Option<&Usage>) -> Result<serde_json::Value, AppErrorKind> {
         let (data, path) = if let Some(name) = file_name {
-            let p = self.join(name)?.to_path();
+            let p = self.join(name)?.to_path()?;
             (fs::metadata(&p)?, fs::canonicalize(&p)?)
         }
         else {
-            (fs::metadata(self.to_path())?, fs::canonicalize(self.to_path())?)
+            (fs::metadata(self.to_path()?)?, fs::canonicalize(self.to_path()?)?)
         };
-        Ok(json!([
+        let size = usage.map(|usage| usage.size).unwrap_or(data.len());
+        let mut details = json!([
             {
                 "name": "Extension", 
                 "value": path.extension().unwrap_or(OsStr::new("Unknown")).to_str().unwrap_or("unknown")
             },
             {
                 "name": "Size", 
-                "value": data.len()
+                "value": format!("{} ({} bytes)", format_size(size), size)
             },
             {
                 "name": "Readonly", 
@@ -344,6 +810,11 @@ impl Folder {
                 "name": "Accessed", 
                 "value": time_format::format_time(data.accessed().unwrap_or(SystemTime::UNIX_EPOCH), None)
             }
-        ]))
+        ]);
+        if let (Some(usage), Some(list)) = (usage, details.as_array_mut()) {
+            list.push(json!({ "name": "Files", "value": usage.files }));
+            list.push(json!({ "name": "Folders", "value": usage.folders }));
+        }
+        Ok(details)
     }
 }
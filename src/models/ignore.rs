@@ -0,0 +1,79 @@
+use std::{env, fs, path::{Path, PathBuf}};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+const IGNORE_ENV: &str = "FS_IGNORE";
+const DEFAULT_IGNORE: &str = ".git,__pycache__,.ppfsignore";
+/// A file of patterns, one per line, ignored in the folder it is in and all folders below
+pub const IGNORE_FILE: &str = ".ppfsignore";
+const PRIVATE_FOLDER: &str = "private";
+
+/// Glob patterns for files and folders that are never listed and cannot be opened.
+/// A pattern without a '/' matches a name anywhere, one with a '/' matches a path
+/// relative to the folder the pattern comes from. The server's own private folder
+/// is always ignored in case the root folder contains it
+#[derive(Clone)]
+pub struct IgnoreRules {
+    /// Each set of patterns with how many names deep the folder it applies from is
+    sets: Vec<(usize, GlobSet)>,
+    private: Option<PathBuf>,
+}
+
+impl IgnoreRules {
+    /// The patterns from the comma separated FS_IGNORE environment variable.
+    /// Defaults to .git, __pycache__ and .ppfsignore
+    pub fn global() -> Self {
+        let patterns = env::var(IGNORE_ENV).unwrap_or_else(|_| DEFAULT_IGNORE.to_owned());
+        let mut rules = Self {
+            sets: Vec::new(),
+            private: fs::canonicalize(PRIVATE_FOLDER).ok()
+        };
+        rules.add(0, patterns.split(','), IGNORE_ENV);
+        rules
+    }
+
+    /// Adds the patterns of the .ppfsignore in a folder, if there is one.
+    /// Depth is how many names below the root folder the folder is
+    pub fn add_folder(&mut self, folder_path: &Path, depth: usize) {
+        let ignore_file = folder_path.join(IGNORE_FILE);
+        if let Ok(contents) = fs::read_to_string(&ignore_file) {
+            self.add(depth, contents.lines(), &ignore_file.to_string_lossy());
+        }
+    }
+
+    /// Returns if the entry at path, with the names leading to it from the root folder, is ignored
+    pub fn is_ignored(&self, names: &[String], path: &Path) -> bool {
+        let name = match names.last() {
+            Some(name) => name,
+            None => return false
+        };
+        let matches = self.sets.iter()
+            .filter(|(depth, _)| *depth < names.len())
+            .any(|(depth, set)| set.is_match(name) || set.is_match(names[*depth..].join("/")));
+        matches || self.private.as_ref().is_some_and(|private| {
+            private.file_name().is_some_and(|private_name| private_name == name.as_str())
+                && fs::canonicalize(path).is_ok_and(|path| &path == private)
+        })
+    }
+
+    fn add<'a>(&mut self, depth: usize, patterns: impl Iterator<Item = &'a str>, source: &str) {
+        let mut builder = GlobSetBuilder::new();
+        let mut count = 0;
+        for pattern in patterns.map(str::trim).filter(|p| !p.is_empty() && !p.starts_with('#')) {
+            match GlobBuilder::new(pattern.trim_matches('/')).literal_separator(true).build() {
+                Ok(glob) => {
+                    builder.add(glob);
+                    count += 1;
+                },
+                Err(err) => log::error!("invalid ignore pattern '{}' in {}: {}", pattern, source, err)
+            }
+        }
+        if count == 0 {
+            return;
+        }
+        match builder.build() {
+            Ok(set) => self.sets.push((depth, set)),
+            Err(err) => log::error!("invalid ignore patterns in {}: {}", source, err)
+        }
+    }
+}
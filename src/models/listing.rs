@@ -0,0 +1,309 @@
+use std::{cmp::Ordering, env, fs::Metadata, iter::Peekable, str::Chars, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::{models::folder::{url_name, Folder}, util::time_format};
+
+/// Session key the chosen sort of the files view is kept under
+pub const SORT_SESSION_KEY: &str = "sort";
+/// Shown as the type of a folder instead of a MIME type
+const FOLDER_TYPE: &str = "folder";
+const PAGE_SIZE_ENV: &str = "FS_PAGE_SIZE";
+const DEFAULT_PAGE_SIZE: usize = 200;
+
+/// The column the files view is sorted by
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+    Type,
+    Permissions,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// How the entries of a folder are ordered. Folders always come before files
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sort {
+    #[serde(default)]
+    pub key: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+impl SortKey {
+    pub const ALL: [SortKey; 5] = [SortKey::Name, SortKey::Size, SortKey::Modified, SortKey::Type, SortKey::Permissions];
+}
+
+impl Sort {
+    /// The header of each column of the files view, with the order clicking it sorts
+    /// in. Clicking the column already sorted by flips the order
+    pub fn columns(&self) -> serde_json::Value {
+        SortKey::ALL.iter().map(|key| {
+            let current = *key == self.key;
+            let next = if current && self.order == SortOrder::Asc { SortOrder::Desc } else { SortOrder::Asc };
+            json!({
+                "key": key,
+                "label": format!("{:?}", key),
+                "order": next,
+                "arrow": match (current, self.order) {
+                    (false, _) => "",
+                    (true, SortOrder::Asc) => "\u{25B2}",
+                    (true, SortOrder::Desc) => "\u{25BC}"
+                }
+            })
+        }).collect()
+    }
+
+    /// Sorts by the key, then by name so entries that tie keep a stable order
+    pub fn sort(&self, entries: &mut [Entry]) {
+        entries.sort_by(|a, b| {
+            let ordering = match self.key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+                SortKey::Type => a.mime.cmp(&b.mime),
+                SortKey::Permissions => a.permissions.cmp(&b.permissions),
+            }.then_with(|| natural_cmp(&a.name, &b.name));
+            match self.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        });
+    }
+}
+
+/// Which entries of a folder to list. A page starts after the entry named by the
+/// cursor if there is one, which keeps paging stable while entries are added or
+/// removed, otherwise at the offset
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    pub offset: usize,
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self { offset: 0, after: None, limit: page_size() }
+    }
+}
+
+impl Page {
+    /// Every entry on one page, for the folder pickers
+    pub fn all() -> Self {
+        Self { offset: 0, after: None, limit: usize::MAX }
+    }
+
+    /// Returns the range of the entries, named in listing order, on this page.
+    /// A cursor naming an entry that no longer exists starts from the offset
+    pub fn range<'a>(&self, mut names: impl Iterator<Item = &'a str>, total: usize) -> (usize, usize) {
+        let start = self.after.as_deref()
+            .and_then(|after| names.position(|name| name == after))
+            .map(|position| position + 1)
+            .unwrap_or(self.offset)
+            .min(total);
+        (start, start.saturating_add(self.limit).min(total))
+    }
+}
+
+/// Returns how many entries a page of the files view has. Defaults to 200
+/// but can be changed with the FS_PAGE_SIZE environment variable
+pub fn page_size() -> usize {
+    env::var(PAGE_SIZE_ENV).ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+}
+
+/// One page of a folder's entries
+pub struct Listing {
+    pub folders: Vec<Entry>,
+    pub files: Vec<Entry>,
+    /// How many entries the folder has on every page
+    pub total: usize,
+    /// Where in the whole listing this page starts
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Listing {
+    pub fn len(&self) -> usize {
+        self.folders.len() + self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cursor for the next page, if there is one
+    pub fn next(&self) -> Option<&str> {
+        if self.offset + self.len() >= self.total {
+            return None;
+        }
+        self.files.last().or(self.folders.last()).map(|entry| entry.name.as_str())
+    }
+
+    pub fn folders_json(&self) -> Vec<serde_json::Value> {
+        self.folders.iter().map(Entry::to_json).collect()
+    }
+
+    pub fn files_json(&self) -> Vec<serde_json::Value> {
+        self.files.iter().map(Entry::to_json).collect()
+    }
+
+    /// Offsets of the previous and next pages and where this one starts and ends, for the page links
+    pub fn pages_json(&self) -> serde_json::Value {
+        json!({
+            "first": self.offset + 1,
+            "last": self.offset + self.len(),
+            "total": self.total,
+            "previous": (self.offset > 0).then(|| self.offset.saturating_sub(self.limit)),
+            "next": self.next().map(|_| self.offset + self.len()),
+            "paged": self.offset > 0 || self.next().is_some()
+        })
+    }
+}
+
+/// One row of the files view
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub folder: Folder,
+    pub name: String,
+    pub is_folder: bool,
+    /// Size in bytes, 0 for folders
+    pub size: u64,
+    /// Seconds since the unix epoch
+    pub modified: i64,
+    pub mime: String,
+    /// Unix style, ex. "rwxr-xr-x"
+    pub permissions: String,
+}
+
+impl Entry {
+    /// Builds the row for a folder or file from its metadata. Folders and files
+    /// whose metadata cannot be read are still listed, just without the details
+    pub fn new(entity: Folder, is_folder: bool, data: Option<&Metadata>) -> Self {
+        let name = entity.name().to_owned();
+        let mime = if is_folder {
+            FOLDER_TYPE.to_owned()
+        }
+        else {
+            mime_guess::from_path(&name).first_or_octet_stream().essence_str().to_owned()
+        };
+        Self {
+            folder: entity,
+            name,
+            is_folder,
+            size: data.filter(|_| !is_folder).map(Metadata::len).unwrap_or(0),
+            modified: data.and_then(|data| data.modified().ok())
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(0),
+            mime,
+            permissions: data.map(permissions).unwrap_or_default(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "path": self.folder.url(),
+            "name": self.name,
+            "url_name": url_name(&self.name),
+            "size": self.size,
+            "size_display": if self.is_folder { String::new() } else { format_size(self.size) },
+            "modified": self.modified,
+            "modified_display": OffsetDateTime::from_unix_timestamp(self.modified)
+                .map(|t| time_format::format_time(t, Some("[year]-[month]-[day] [hour]:[minute]")))
+                .unwrap_or_else(|_| String::from("Unknown")),
+            "mime": self.mime,
+            "permissions": self.permissions
+        })
+    }
+}
+
+/// Returns a size in bytes the way people read it, ex. "1.5 KB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    }
+    else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(unix)]
+fn permissions(data: &Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = data.permissions().mode();
+    (0..9).rev().map(|bit| {
+        if mode & (1 << bit) == 0 {
+            '-'
+        }
+        else {
+            ['x', 'w', 'r'][bit % 3]
+        }
+    }).collect()
+}
+
+#[cfg(not(unix))]
+fn permissions(data: &Metadata) -> String {
+    if data.permissions().readonly() { "r--" } else { "rw-" }.to_owned()
+}
+
+/// Compares names the way people expect, with runs of digits compared by their value
+/// so "img_2" comes before "img_10", and letters compared ignoring case first
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x_digits, y_digits) = (digits(&mut a_chars), digits(&mut b_chars));
+                let (x_value, y_value) = (x_digits.trim_start_matches('0'), y_digits.trim_start_matches('0'));
+                let ordering = x_value.len().cmp(&y_value.len())
+                    .then_with(|| x_value.cmp(y_value))
+                    .then_with(|| x_digits.len().cmp(&y_digits.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            },
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+fn digits(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        digits.push(c);
+    }
+    digits
+}
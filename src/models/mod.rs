@@ -1,2 +1,13 @@
 pub mod folder;
-pub mod user;
\ No newline at end of file
+pub mod user;
+pub mod access;
+pub mod token;
+pub mod totp;
+pub mod ignore;
+pub mod listing;
+pub mod usage;
+pub mod search;
+pub mod content;
+pub mod trash;
+pub mod conflict;
+pub mod rename;
@@ -0,0 +1,169 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::{models::{folder::Folder, listing::natural_cmp}, util::{error::AppErrorKind, time_format}};
+
+/// The most digits a number of a numbering sequence is padded to
+pub const MAX_DIGITS: usize = 6;
+/// Entries are renamed to a name starting with this first, so names can be swapped
+const TEMPORARY_PREFIX: &str = ".ppfs-rename-";
+
+/// How the names of a bulk rename change, each step in the order of the fields
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RenamePattern {
+    /// Text to find in each name, every time it is found is replaced
+    #[serde(default)]
+    pub find: String,
+    #[serde(default)]
+    pub replace: String,
+    /// If find is a regular expression, then replace can use its groups like $1
+    #[serde(default)]
+    pub regex: bool,
+    /// Puts the day each entry was last modified in front of its name, ex. "2022-06-01_photo.jpg"
+    #[serde(default)]
+    pub date_prefix: bool,
+    /// Numbers the names in order after the name and before the extension, ex. "photo_001.jpg"
+    #[serde(default)]
+    pub numbering: bool,
+    /// The first number, 1 if not set
+    pub start: Option<String>,
+    /// How many digits the numbers are padded to with zeros
+    pub digits: Option<usize>,
+}
+
+/// One name of a bulk rename and what it becomes
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedRename {
+    pub from: String,
+    pub to: String,
+    /// Why it cannot be renamed, None if it can
+    pub problem: Option<String>,
+}
+
+impl PlannedRename {
+    /// If it can be renamed and its name changes
+    pub fn is_ready(&self) -> bool {
+        self.problem.is_none() && self.from != self.to
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "from": self.from,
+            "to": self.to,
+            "changed": self.from != self.to,
+            "problem": self.problem
+        })
+    }
+}
+
+impl RenamePattern {
+    /// Works out the new name of each of the named entries of folder without renaming anything.
+    /// Names are numbered in name order, and checked against each other and the rest of the folder
+    pub fn plan(&self, folder: &Folder, names: &[String]) -> Result<Vec<PlannedRename>, AppErrorKind> {
+        let regex = match self.regex && !self.find.is_empty() {
+            true => Some(Regex::new(&self.find).map_err(|err| AppErrorKind::RenamePatternInvalid(err.to_string()))?),
+            false => None
+        };
+        let start = match self.start.as_deref().map(str::trim).filter(|start| !start.is_empty()) {
+            Some(start) => start.parse::<u64>().map_err(|_| AppErrorKind::RenamePatternInvalid(format!("'{}' is not a whole number", start)))?,
+            None => 1
+        };
+        let digits = self.digits.unwrap_or(1).clamp(1, MAX_DIGITS);
+        let folder_path = folder.to_path()?;
+        let mut names = names.to_vec();
+        names.sort_by(|a, b| natural_cmp(a, b));
+        let mut plans: Vec<PlannedRename> = names.iter().enumerate().map(|(index, name)| {
+            let path = Path::new(&folder_path).join(name);
+            let mut to = match &regex {
+                Some(regex) => regex.replace_all(name, self.replace.as_str()).into_owned(),
+                None if !self.find.is_empty() => name.replace(&self.find, &self.replace),
+                None => name.clone()
+            };
+            if self.date_prefix {
+                if let Some(date) = modified_date(&path) {
+                    to = format!("{}_{}", date, to);
+                }
+            }
+            if self.numbering {
+                to = with_number(&to, start + index as u64, digits, path.is_dir());
+            }
+            let problem = folder.join(&to).and_then(|entity| entity.to_path()).err().map(|err| err.to_string());
+            PlannedRename { from: name.clone(), to, problem }
+        }).collect();
+        for index in 0..plans.len() {
+            if plans[index].problem.is_none() && plans.iter().filter(|plan| plan.to == plans[index].to).count() > 1 {
+                plans[index].problem = Some(format!("another selected name also becomes '{}'", plans[index].to));
+            }
+        }
+        // a name taken by an entry that is renamed too is free, unless that rename cannot happen
+        loop {
+            let freed: HashSet<String> = plans.iter().filter(|plan| plan.is_ready()).map(|plan| plan.from.clone()).collect();
+            let taken = plans.iter().position(|plan| plan.is_ready() && !freed.contains(&plan.to)
+                && fs::symlink_metadata(Path::new(&folder_path).join(&plan.to)).is_ok());
+            match taken {
+                Some(index) => plans[index].problem = Some(AppErrorKind::EntityAlreadyExists(plans[index].to.clone()).to_string()),
+                None => break
+            }
+        }
+        Ok(plans)
+    }
+}
+
+/// A planned rename with how renaming it went
+pub type RenameResult = (PlannedRename, Result<(), AppErrorKind>);
+
+/// Renames every planned entry that is ready, returning each with how it went. Each is first
+/// renamed to a temporary name, so names can be swapped or shifted along in one go
+pub fn apply(folder: &Folder, plans: &[PlannedRename]) -> Result<Vec<RenameResult>, AppErrorKind> {
+    let folder_path = folder.to_path()?;
+    let folder_path = Path::new(&folder_path);
+    let id: String = OsRng.sample_iter(&Alphanumeric).take(8).map(char::from).collect();
+    let mut results = Vec::new();
+    let mut moved = Vec::new();
+    for (index, plan) in plans.iter().filter(|plan| plan.is_ready()).enumerate() {
+        let temporary = folder_path.join(format!("{}{}-{}", TEMPORARY_PREFIX, id, index));
+        match folder.join(&plan.from).and_then(|entity| entity.to_path()) {
+            Ok(path) => match fs::rename(path, &temporary) {
+                Ok(()) => moved.push((plan, temporary)),
+                Err(err) => results.push((plan.clone(), Err(err.into())))
+            },
+            Err(err) => results.push((plan.clone(), Err(err)))
+        }
+    }
+    for (plan, temporary) in moved {
+        let target = folder_path.join(&plan.to);
+        let result = match fs::symlink_metadata(&target) {
+            // something took the name since the plan was made, so it goes back to its old name
+            Ok(_) => fs::rename(&temporary, folder_path.join(&plan.from))
+                .map_err(Into::into)
+                .and(Err(AppErrorKind::EntityAlreadyExists(plan.to.clone()))),
+            Err(_) => fs::rename(&temporary, &target).map_err(Into::into)
+        };
+        if let Err(err) = &result {
+            if temporary.exists() {
+                log::error!("could not rename '{}' to '{}', it is left as '{}': {}", plan.from, plan.to, temporary.display(), err);
+            }
+        }
+        results.push((plan.clone(), result));
+    }
+    Ok(results)
+}
+
+fn modified_date(path: &Path) -> Option<String> {
+    let modified = fs::symlink_metadata(path).ok()?.modified().ok()?;
+    Some(time_format::format_time(OffsetDateTime::from(modified), Some("[year]-[month]-[day]")))
+}
+
+/// Adds the number to the end of the name, before the extension of a file
+fn with_number(name: &str, number: u64, digits: usize, is_folder: bool) -> String {
+    let number = format!("{:0digits$}", number, digits = digits);
+    match name.rfind('.').filter(|&dot| !is_folder && dot > 0) {
+        Some(dot) => format!("{}_{}{}", &name[..dot], number, &name[dot..]),
+        None => format!("{}_{}", name, number)
+    }
+}
@@ -0,0 +1,372 @@
+use std::{collections::VecDeque, env, fs::{self, Metadata}, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::{format_description, Date, OffsetDateTime, UtcOffset};
+
+use crate::{models::{access::{Access, AccessList}, content::{self, ContentHits, ContentMatcher}, folder::{Folder, SymlinkPolicy}, ignore::{IgnoreRules, IGNORE_FILE}, listing::format_size}, util::{error::AppErrorKind, forward::{self, ForwardTo}, time_format}};
+
+const SEARCH_LIMIT_ENV: &str = "FS_SEARCH_LIMIT";
+const DEFAULT_SEARCH_LIMIT: usize = 1000;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// How the name typed into the search is compared with each name
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// The name contains the text, ignoring case
+    #[default]
+    Substring,
+    /// The whole name matches a glob like "img_*.jpg", ignoring case
+    Glob,
+    /// The name matches a regular expression anywhere in it
+    Regex,
+}
+
+/// What kind of entries a search finds. The media kinds go by the MIME type of the file name
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    #[default]
+    Any,
+    Folder,
+    File,
+    Image,
+    Video,
+    Audio,
+    Text,
+}
+
+impl EntryKind {
+    pub const ALL: [EntryKind; 7] = [
+        EntryKind::Any, EntryKind::Folder, EntryKind::File, EntryKind::Image,
+        EntryKind::Video, EntryKind::Audio, EntryKind::Text
+    ];
+}
+
+/// The search form. Sizes are numbers of bytes optionally followed by K, M or G,
+/// dates look like 2022-12-31 and empty fields match everything. Filling in content
+/// only finds text files with a line containing it
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SearchQuery {
+    /// Only set once the form has been sent
+    pub q: Option<String>,
+    #[serde(default)]
+    pub mode: MatchMode,
+    #[serde(default)]
+    pub kind: EntryKind,
+    pub min_size: Option<String>,
+    pub max_size: Option<String>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+    pub content: Option<String>,
+    /// If content is a regular expression rather than text to find ignoring case
+    #[serde(default)]
+    pub content_regex: bool,
+    /// How many lines around each line found in a file are shown
+    pub context: Option<usize>,
+}
+
+enum Matcher {
+    Everything,
+    Substring(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+/// A search query checked and compiled
+pub struct Search {
+    matcher: Matcher,
+    kind: EntryKind,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// Unix times the modified time must be within
+    after: Option<i64>,
+    before: Option<i64>,
+    content: Option<ContentMatcher>,
+    context: usize,
+}
+
+impl Search {
+    pub fn new(query: &SearchQuery) -> Result<Self, AppErrorKind> {
+        let text = query.q.as_deref().unwrap_or("").trim();
+        let matcher = if text.is_empty() {
+            Matcher::Everything
+        }
+        else {
+            match query.mode {
+                MatchMode::Substring => Matcher::Substring(text.to_lowercase()),
+                MatchMode::Glob => GlobBuilder::new(text).case_insensitive(true).literal_separator(true).build()
+                    .map(|glob| Matcher::Glob(glob.compile_matcher()))
+                    .map_err(|err| AppErrorKind::SearchInvalid(err.to_string()))?,
+                MatchMode::Regex => Regex::new(text)
+                    .map(Matcher::Regex)
+                    .map_err(|err| AppErrorKind::SearchInvalid(err.to_string()))?,
+            }
+        };
+        let filled = |field: &Option<String>| field.as_deref().map(str::trim).filter(|value| !value.is_empty()).map(str::to_owned);
+        Ok(Self {
+            matcher,
+            kind: query.kind,
+            min_size: filled(&query.min_size).map(|size| parse_size(&size)).transpose()?,
+            max_size: filled(&query.max_size).map(|size| parse_size(&size)).transpose()?,
+            after: filled(&query.after).map(|date| parse_date(&date)).transpose()?,
+            before: filled(&query.before).map(|date| parse_date(&date).map(|time| time + SECONDS_PER_DAY)).transpose()?,
+            content: query.content.as_deref().filter(|content| !content.is_empty())
+                .map(|content| ContentMatcher::new(content, query.content_regex)).transpose()?,
+            context: query.context.unwrap_or(0).min(content::MAX_CONTEXT),
+        })
+    }
+
+    /// Returns if the search looks inside files, which the index cannot answer
+    pub fn has_content(&self) -> bool {
+        self.content.is_some()
+    }
+
+    /// The lines of a file matching the content searched for, if it is a text file that has any
+    pub fn content_hits(&self, path: &Path) -> Option<ContentHits> {
+        let matcher = self.content.as_ref()?;
+        matcher.hits(&content::read_text(path)?, self.context)
+    }
+
+    /// Returns if the name matches, without looking at the metadata
+    pub fn matches_name(&self, name: &str) -> bool {
+        match &self.matcher {
+            Matcher::Everything => true,
+            Matcher::Substring(text) => name.to_lowercase().contains(text),
+            Matcher::Glob(glob) => glob.is_match(name),
+            Matcher::Regex(regex) => regex.is_match(name),
+        }
+    }
+
+    pub fn matches(&self, name: &str, is_folder: bool, size: u64, modified: i64) -> bool {
+        let kind = match self.kind {
+            EntryKind::Any => true,
+            EntryKind::Folder => is_folder,
+            EntryKind::File => !is_folder,
+            media => !is_folder && mime_guess::from_path(name).first().is_some_and(|mime| {
+                serde_json::to_value(media).ok().is_some_and(|value| value.as_str() == Some(mime.type_().as_str()))
+            }),
+        };
+        let sized = (self.min_size.is_none() && self.max_size.is_none())
+            || (!is_folder && self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max));
+        kind && sized
+            && (self.content.is_none() || !is_folder)
+            && self.after.is_none_or(|after| modified >= after)
+            && self.before.is_none_or(|before| modified < before)
+            && self.matches_name(name)
+    }
+}
+
+/// Parses a size like "512", "10K", "1.5MB" or "2 G" into bytes
+pub fn parse_size(size: &str) -> Result<u64, AppErrorKind> {
+    let size = size.trim().to_uppercase();
+    let number_end = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+    let (number, unit) = size.split_at(number_end);
+    let multiplier: u64 = match unit.trim().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(AppErrorKind::SearchInvalid(format!("'{}' is not a size", size)))
+    };
+    number.parse::<f64>().ok()
+        .filter(|number| number.is_finite() && *number >= 0.0)
+        .map(|number| (number * multiplier as f64) as u64)
+        .ok_or_else(|| AppErrorKind::SearchInvalid(format!("'{}' is not a size", size)))
+}
+
+/// Parses a date like "2022-12-31" into the unix time it starts at, in local time
+fn parse_date(date: &str) -> Result<i64, AppErrorKind> {
+    let invalid = || AppErrorKind::SearchInvalid(format!("'{}' is not a date like 2022-12-31", date));
+    let format = format_description::parse("[year]-[month]-[day]").map_err(|_| invalid())?;
+    let date = Date::parse(date, &format).map_err(|_| invalid())?;
+    let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+    Ok(date.midnight().assume_offset(offset).unix_timestamp())
+}
+
+/// Returns the most results a search shows. Defaults to 1000 but can be
+/// changed with the FS_SEARCH_LIMIT environment variable
+pub fn search_limit() -> usize {
+    env::var(SEARCH_LIMIT_ENV).ok()
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .filter(|limit| *limit > 0)
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+}
+
+/// A folder or file found by a search
+pub struct SearchResult {
+    /// The folder the result is in
+    pub folder: Folder,
+    pub name: String,
+    pub is_folder: bool,
+    pub size: u64,
+    pub modified: i64,
+    /// The matching lines, for searches inside files
+    pub hits: Option<ContentHits>,
+}
+
+impl SearchResult {
+    pub fn to_json(&self) -> serde_json::Value {
+        let location = if self.is_folder {
+            forward::location(&ForwardTo::Folder(self.folder.join(&self.name).unwrap_or_default()))
+        }
+        else {
+            forward::location(&ForwardTo::FileDetail(self.folder.clone(), self.name.clone()))
+        };
+        json!({
+            "name": self.name,
+            "location": location,
+            "is_folder": self.is_folder,
+            "folder": format!("/{}", self.folder.relative_path()),
+            "folder_location": forward::location(&ForwardTo::Folder(self.folder.clone())),
+            "size_display": if self.is_folder { String::new() } else { format_size(self.size) },
+            "modified_display": OffsetDateTime::from_unix_timestamp(self.modified)
+                .map(|t| time_format::format_time(t, Some("[year]-[month]-[day] [hour]:[minute]")))
+                .unwrap_or_else(|_| String::from("Unknown")),
+            "hits": self.hits.as_ref().map(|hits| hits.to_json(&location))
+        })
+    }
+}
+
+/// A search in progress through every folder below the one it started in, closest folders first.
+/// Only what the user could reach by browsing is searched: ignored, denied and, unless they
+/// show hidden files, hidden folders are skipped, and symbolic links are never followed into
+pub struct SearchWalk {
+    search: Search,
+    access: AccessList,
+    show_hidden: bool,
+    policy: SymlinkPolicy,
+    root: PathBuf,
+    /// Folders left to search with their location on disk and the ignore rules inside them
+    pending: VecDeque<(Folder, PathBuf, Arc<IgnoreRules>)>,
+    found: usize,
+    limit: usize,
+}
+
+impl SearchWalk {
+    pub fn new(folder: &Folder, search: Search, access: AccessList, show_hidden: bool) -> Result<Self, AppErrorKind> {
+        let (path, rules) = folder.resolve()?;
+        Ok(Self {
+            search,
+            access,
+            show_hidden,
+            policy: SymlinkPolicy::from_env(),
+            root: Folder::canonical_root()?,
+            pending: VecDeque::from([(folder.clone(), path, Arc::new(rules))]),
+            found: 0,
+            limit: search_limit(),
+        })
+    }
+
+    /// Searches until at least count more results are found or there is nothing left to search.
+    /// Returns nothing once the search is done
+    pub fn next_batch(&mut self, count: usize) -> Vec<SearchResult> {
+        let mut results = Vec::new();
+        while results.len() < count && !self.is_done() {
+            if let Some((folder, path, rules)) = self.pending.pop_front() {
+                self.search_folder(&folder, &path, &rules, &mut results);
+            }
+        }
+        results
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty() || self.is_truncated()
+    }
+
+    /// Returns if the search stopped at the most results it shows
+    pub fn is_truncated(&self) -> bool {
+        self.found >= self.limit
+    }
+
+    pub fn found(&self) -> usize {
+        self.found
+    }
+
+    fn search_folder(&mut self, folder: &Folder, path: &Path, rules: &Arc<IgnoreRules>, results: &mut Vec<SearchResult>) {
+        let dir = match fs::read_dir(path) {
+            Ok(dir) => dir,
+            Err(err) => {
+                log::debug!("search could not read '{}': {}", path.display(), err);
+                return;
+            }
+        };
+        for dir_entry in dir.flatten() {
+            if self.is_truncated() {
+                return;
+            }
+            let (name, file_type) = match (dir_entry.file_name().into_string(), dir_entry.file_type()) {
+                (Ok(name), Ok(file_type)) => (name, file_type),
+                _ => continue
+            };
+            let entity = match folder.join(&name) {
+                Ok(entity) => entity,
+                Err(_) => continue
+            };
+            if (!self.show_hidden && entity.is_hidden())
+                || self.access.access(&entity.relative_path()) == Access::Deny
+                || rules.is_ignored(entity.names(), &dir_entry.path()) {
+                continue;
+            }
+            let data = if file_type.is_symlink() {
+                match self.link_metadata(&dir_entry.path()) {
+                    Some(data) => data,
+                    None => continue
+                }
+            }
+            else {
+                match dir_entry.metadata() {
+                    Ok(data) => data,
+                    Err(_) => continue
+                }
+            };
+            let is_folder = data.is_dir();
+            if is_folder && !file_type.is_symlink() {
+                let child_path = dir_entry.path();
+                let child_rules = if child_path.join(IGNORE_FILE).is_file() {
+                    let mut child_rules = IgnoreRules::clone(rules);
+                    child_rules.add_folder(&child_path, entity.names().len());
+                    Arc::new(child_rules)
+                }
+                else {
+                    Arc::clone(rules)
+                };
+                self.pending.push_back((entity.clone(), child_path, child_rules));
+            }
+            let modified = data.modified().ok()
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(0);
+            if !self.search.matches(&name, is_folder, data.len(), modified) {
+                continue;
+            }
+            let hits = if self.search.has_content() {
+                // read through the entity so links are checked the same as when the file is opened
+                match entity.to_path().ok().and_then(|path| self.search.content_hits(Path::new(&path))) {
+                    Some(hits) => Some(hits),
+                    None => continue
+                }
+            }
+            else {
+                None
+            };
+            self.found += 1;
+            results.push(SearchResult { folder: folder.clone(), name, is_folder, size: data.len(), modified, hits });
+        }
+    }
+
+    /// The metadata of what a link points to, if links like it are listed
+    fn link_metadata(&self, path: &Path) -> Option<Metadata> {
+        match self.policy {
+            SymlinkPolicy::Never => None,
+            SymlinkPolicy::ShowButDeny => fs::symlink_metadata(path).ok(),
+            SymlinkPolicy::FollowInsideRoot => fs::canonicalize(path).ok()
+                .filter(|target| target.starts_with(&self.root))
+                .and_then(|target| fs::metadata(target).ok())
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use rand::{Rng, distributions::Alphanumeric, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::util::time_format;
+
+const TOKEN_PREFIX: &str = "ppfs";
+const TOKEN_ID_LENGTH: usize = 8;
+const TOKEN_SECRET_LENGTH: usize = 32;
+
+/// Limits what a request authenticated with an API token can do
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TokenScope {
+    pub read_only: bool,
+    /// Folder relative to the root folder the token is limited to
+    pub folder: Option<String>,
+}
+
+/// A revocable API token. Only a hash of the secret is stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    secret_hash: String,
+    #[serde(flatten)]
+    pub scope: TokenScope,
+    pub created: i64,
+    pub expires: Option<i64>,
+}
+
+impl ApiToken {
+    /// Creates a new token and returns it along with the plain text value to give to the user.
+    /// The plain text value looks like "ppfs_<id>_<secret>" and cannot be recovered later
+    pub fn generate(name: &str, scope: TokenScope, expires_in_days: Option<i64>) -> (Self, String) {
+        let id = random_string(TOKEN_ID_LENGTH);
+        let secret = random_string(TOKEN_SECRET_LENGTH);
+        let created = OffsetDateTime::now_utc().unix_timestamp();
+        let token = Self {
+            id: id.clone(),
+            name: name.to_owned(),
+            secret_hash: hash_secret(&secret),
+            scope,
+            created,
+            expires: expires_in_days.map(|days| created + days * 24 * 60 * 60)
+        };
+        (token, format!("{}_{}_{}", TOKEN_PREFIX, id, secret))
+    }
+
+    /// Splits a plain text token into its id and secret
+    pub fn parse(token: &str) -> Option<(&str, &str)> {
+        let mut parts = token.splitn(3, '_');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(TOKEN_PREFIX), Some(id), Some(secret)) if !id.is_empty() && !secret.is_empty() => Some((id, secret)),
+            _ => None
+        }
+    }
+
+    pub fn verify(&self, secret: &str) -> bool {
+        !self.is_expired() && constant_time_eq(self.secret_hash.as_bytes(), hash_secret(secret).as_bytes())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| OffsetDateTime::now_utc().unix_timestamp() >= expires)
+    }
+
+    /// Returns the token without its hash for displaying in templates
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "name": self.name,
+            "read_only": self.scope.read_only,
+            "folder": self.scope.folder,
+            "created": format_timestamp(self.created),
+            "expires": self.expires.map(format_timestamp),
+            "is_expired": self.is_expired()
+        })
+    }
+}
+
+/// Tokens are long and random so a fast hash is enough, unlike passwords
+pub fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares without returning early so the time taken does not leak how much matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn random_string(length: usize) -> String {
+    OsRng.sample_iter(&Alphanumeric).take(length).map(char::from).collect()
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .map(|t| time_format::format_time(t, None))
+        .unwrap_or_else(|_| String::from("Unknown"))
+}
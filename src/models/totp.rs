@@ -0,0 +1,130 @@
+use hmac::{Hmac, Mac};
+use qrcode::{QrCode, render::svg};
+use rand::{Rng, RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use time::OffsetDateTime;
+
+use crate::models::token::{constant_time_eq, hash_secret};
+
+const ISSUER: &str = "plug_and_play_fs";
+const SECRET_BYTES: usize = 20;
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from one step before or after are accepted to allow for clock drift
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// A user's RFC 6238 second factor. Recovery codes are stored hashed
+/// and each one can only be used once
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Totp {
+    secret: String,
+    recovery_hashes: Vec<String>,
+    /// Last time step a code was accepted for so a code cannot be replayed
+    #[serde(default)]
+    last_step: u64,
+}
+
+impl Totp {
+    /// Enables TOTP with a secret the user already proved they can generate codes for.
+    /// Returns the recovery codes to show the user once
+    pub fn new(secret: String) -> (Self, Vec<String>) {
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+        let totp = Self {
+            secret,
+            recovery_hashes: codes.iter().map(|code| hash_secret(code)).collect(),
+            last_step: current_step()
+        };
+        (totp, codes)
+    }
+
+    /// Checks a six digit code and remembers its step so it cannot be used again
+    pub fn verify_code(&mut self, code: &str) -> bool {
+        match verify(&self.secret, code, self.last_step) {
+            Some(step) => {
+                self.last_step = step;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Checks a recovery code and removes it if it matches
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hash = hash_secret(&normalize_recovery_code(code));
+        let count = self.recovery_hashes.len();
+        self.recovery_hashes.retain(|stored| !constant_time_eq(stored.as_bytes(), hash.as_bytes()));
+        self.recovery_hashes.len() < count
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery_hashes.len()
+    }
+}
+
+/// Returns a new random secret encoded in base32 like authenticator apps expect
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes)
+}
+
+/// Returns the step a code matched if it is valid for now and newer than last_step
+pub fn verify(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let code = code.trim().replace(' ', "");
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let now = current_step();
+    (now.saturating_sub(ALLOWED_DRIFT_STEPS)..=now + ALLOWED_DRIFT_STEPS)
+        .filter(|step| *step > last_step)
+        .find(|step| code_at(secret, *step)
+            .is_some_and(|expected| constant_time_eq(format!("{:0width$}", expected, width = DIGITS as usize).as_bytes(), code.as_bytes())))
+}
+
+/// The HOTP value of RFC 4226 for a time step
+pub fn code_at(secret: &str, step: u64) -> Option<u32> {
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    Some(binary % 10u32.pow(DIGITS))
+}
+
+/// The link authenticator apps read from the QR code
+pub fn otpauth_url(username: &str, secret: &str) -> String {
+    format!("otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&digits={DIGITS}&period={STEP_SECS}",
+        issuer = ISSUER, username = username.replace(|c: char| !c.is_ascii_alphanumeric(), "_"), secret = secret)
+}
+
+/// Renders the otpauth url as an svg QR code to embed in a page
+pub fn qr_svg(url: &str) -> Option<String> {
+    let code = QrCode::new(url.as_bytes()).ok()?;
+    Some(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+fn current_step() -> u64 {
+    OffsetDateTime::now_utc().unix_timestamp() as u64 / STEP_SECS
+}
+
+fn generate_recovery_code() -> String {
+    const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let code: String = (0..RECOVERY_CODE_LENGTH)
+        .map(|_| CHARSET[OsRng.gen_range(0..CHARSET.len())] as char)
+        .collect();
+    format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..])
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    let code: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase();
+    if code.len() == RECOVERY_CODE_LENGTH {
+        format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..])
+    }
+    else {
+        code
+    }
+}
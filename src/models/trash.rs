@@ -0,0 +1,307 @@
+use std::{env, fs, io, path::{Path, PathBuf}, sync::Mutex, thread, time::Duration};
+
+use actix_web::web;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::{models::{folder::{self, Folder}, listing::format_size, search::parse_size, usage}, util::{audit, error::AppErrorKind, time_format}};
+
+const TRASH_FOLDER_ENV: &str = "FS_TRASH_FOLDER";
+const DEFAULT_TRASH_FOLDER: &str = "private/trash";
+const TRASH_DAYS_ENV: &str = "FS_TRASH_DAYS";
+const DEFAULT_TRASH_DAYS: i64 = 30;
+const TRASH_MAX_SIZE_ENV: &str = "FS_TRASH_MAX_SIZE";
+const ID_LENGTH: usize = 16;
+/// Each item is kept in the trash folder under its id, described by a file named after it
+const ITEM_EXTENSION: &str = "json";
+/// How often the trash is checked for items past the retention period
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Only one change to the trash at a time, so purging never removes an item being restored
+static TRASH_LOCK: Mutex<()> = Mutex::new(());
+
+/// A deleted folder or file waiting in the trash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrashItem {
+    pub id: String,
+    pub name: String,
+    /// The folder it was deleted from, relative to the root folder
+    pub folder: String,
+    pub is_folder: bool,
+    /// Size in bytes, of everything in it for folders
+    pub size: u64,
+    pub deleted_by: String,
+    pub deleted_at: i64,
+}
+
+impl TrashItem {
+    /// Where the item was before it was deleted, relative to the root folder
+    pub fn original_path(&self) -> String {
+        if self.folder.is_empty() { self.name.clone() } else { format!("{}/{}", self.folder, self.name) }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let format = |time: i64| OffsetDateTime::from_unix_timestamp(time)
+            .map(|t| time_format::format_time(t, Some("[year]-[month]-[day] [hour]:[minute]")))
+            .unwrap_or_else(|_| String::from("Unknown"));
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "path": format!("/{}", self.original_path()),
+            "is_folder": self.is_folder,
+            "size_display": format_size(self.size),
+            "deleted_by": self.deleted_by,
+            "deleted_at": format(self.deleted_at),
+            "expires": retention_days().map(|days| format(self.deleted_at + days * SECONDS_PER_DAY))
+        })
+    }
+}
+
+/// Returns the folder deleted items are kept in, named by FS_TRASH_FOLDER and defaulting
+/// to private/trash. It should be outside the root folder, or ignored, so the trash cannot be browsed
+pub fn trash_folder() -> PathBuf {
+    PathBuf::from(env::var(TRASH_FOLDER_ENV).unwrap_or_else(|_| DEFAULT_TRASH_FOLDER.to_owned()))
+}
+
+/// Returns how many days items stay in the trash. Defaults to 30 but can be changed with
+/// the FS_TRASH_DAYS environment variable, where 0 keeps items until they are purged
+pub fn retention_days() -> Option<i64> {
+    let days = env::var(TRASH_DAYS_ENV).ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_TRASH_DAYS);
+    (days > 0).then_some(days)
+}
+
+/// Returns the most the trash can hold, from the FS_TRASH_MAX_SIZE environment variable
+/// (ex. 10G). Past it the oldest items are purged first. Unset means no limit
+pub fn max_size() -> Option<u64> {
+    env::var(TRASH_MAX_SIZE_ENV).ok()
+        .filter(|size| !size.trim().is_empty())
+        .and_then(|size| parse_size(&size).ok())
+}
+
+fn lock() -> std::sync::MutexGuard<'static, ()> {
+    TRASH_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn item_path(id: &str) -> PathBuf {
+    trash_folder().join(id)
+}
+
+fn record_path(id: &str) -> PathBuf {
+    trash_folder().join(format!("{}.{}", id, ITEM_EXTENSION))
+}
+
+/// Moves a folder or file into the trash instead of deleting it. Something larger than
+/// the trash may get is not deleted at all, rather than being purged right away
+pub fn move_to_trash(entity: &Folder, deleted_by: &str) -> Result<TrashItem, AppErrorKind> {
+    let (path, item) = new_item(entity, deleted_by)?;
+    let item = measured(&path, item);
+    if max_size().is_some_and(|max| item.size > max) {
+        return Err(AppErrorKind::TooLargeForTrash);
+    }
+    store(&path, &item)?;
+    purge_old(Some(&item.id));
+    Ok(item)
+}
+
+/// Like move_to_trash, but measures and moves the item on the blocking threadpool. Both take
+/// a while for a large folder, and longer still when the trash is on another drive
+pub async fn move_to_trash_blocking(entity: &Folder, deleted_by: &str) -> Result<TrashItem, AppErrorKind> {
+    let (path, item) = new_item(entity, deleted_by)?;
+    let measure_path = path.clone();
+    let item = web::block(move || measured(&measure_path, item)).await
+        .map_err(|_| AppErrorKind::FailedToDelete)?;
+    if max_size().is_some_and(|max| item.size > max) {
+        return Err(AppErrorKind::TooLargeForTrash);
+    }
+    let stored = web::block(move || {
+        store(&path, &item)?;
+        purge_old(Some(&item.id));
+        Ok::<TrashItem, io::Error>(item)
+    }).await;
+    match stored {
+        Ok(result) => result.map_err(Into::into),
+        Err(_e) => Err(AppErrorKind::FailedToDelete)
+    }
+}
+
+/// The item the entity becomes in the trash, and where it is on disk. A folder's size
+/// is only known once it is measured
+fn new_item(entity: &Folder, deleted_by: &str) -> Result<(PathBuf, TrashItem), AppErrorKind> {
+    if entity.is_root() {
+        return Err(AppErrorKind::CannotDeleteRoot);
+    }
+    let path = PathBuf::from(entity.to_path()?);
+    let data = fs::symlink_metadata(&path)?;
+    let item = TrashItem {
+        id: OsRng.sample_iter(&Alphanumeric).take(ID_LENGTH).map(char::from).collect(),
+        name: entity.name().to_owned(),
+        folder: entity.parent()?.relative_path(),
+        is_folder: data.is_dir(),
+        size: data.len(),
+        deleted_by: deleted_by.to_owned(),
+        deleted_at: audit::now(),
+    };
+    Ok((path, item))
+}
+
+/// The item with the size of everything in it, if it is a folder
+fn measured(path: &Path, mut item: TrashItem) -> TrashItem {
+    if item.is_folder {
+        item.size = usage::measure(path).map(|usage| usage.size).unwrap_or(0);
+    }
+    item
+}
+
+/// Moves what is at path into the trash folder along with the record of the item
+fn store(path: &Path, item: &TrashItem) -> io::Result<()> {
+    let _lock = lock();
+    fs::create_dir_all(trash_folder())?;
+    folder::move_path(path, &item_path(&item.id))?;
+    let record = serde_json::to_string(item).map_err(io::Error::other)
+        .and_then(|record| fs::write(record_path(&item.id), record));
+    if let Err(err) = record {
+        // without its record the item could never be restored, so it is put back
+        let _ = folder::move_path(&item_path(&item.id), path);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Every item in the trash, newest first
+pub fn list() -> Vec<TrashItem> {
+    let dir = match fs::read_dir(trash_folder()) {
+        Ok(dir) => dir,
+        Err(_) => return Vec::new()
+    };
+    let mut items: Vec<TrashItem> = dir.flatten()
+        .filter(|dir_entry| dir_entry.path().extension().is_some_and(|extension| extension == ITEM_EXTENSION))
+        .filter_map(|dir_entry| fs::read_to_string(dir_entry.path()).ok())
+        .filter_map(|record| serde_json::from_str::<TrashItem>(&record).ok())
+        .filter(|item| item_path(&item.id).symlink_metadata().is_ok())
+        .collect();
+    items.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then_with(|| a.id.cmp(&b.id)));
+    items
+}
+
+pub fn get(id: &str) -> Result<TrashItem, AppErrorKind> {
+    if id.len() != ID_LENGTH || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(AppErrorKind::TrashItemNotFound);
+    }
+    fs::read_to_string(record_path(id)).ok()
+        .and_then(|record| serde_json::from_str(&record).ok())
+        .ok_or(AppErrorKind::TrashItemNotFound)
+}
+
+/// Returns the item to where it was deleted from, creating the folders it was in if they
+/// are gone. Fails if something else has taken its place since. Copying it back from a
+/// trash on another drive can take a while, so it is done on the blocking threadpool
+pub async fn restore(item: &TrashItem) -> Result<Folder, AppErrorKind> {
+    let parent = Folder::from_relative_path(&item.folder)?;
+    let entity = parent.join(&item.name)?;
+    let (parent_path, target) = (PathBuf::from(parent.to_path()?), PathBuf::from(entity.to_path()?));
+    let id = item.id.clone();
+    let restored = web::block(move || {
+        let _lock = lock();
+        if target.symlink_metadata().is_ok() {
+            return Ok(false);
+        }
+        fs::create_dir_all(parent_path)?;
+        folder::move_path(&item_path(&id), &target)?;
+        remove_record(&id);
+        Ok::<bool, io::Error>(true)
+    }).await;
+    match restored {
+        Ok(Ok(true)) => Ok(entity),
+        Ok(Ok(false)) => Err(AppErrorKind::EntityAlreadyExists(item.original_path())),
+        Ok(Err(err)) => Err(err.into()),
+        Err(_e) => Err(AppErrorKind::FailedToRestore)
+    }
+}
+
+/// Deletes an item for good, on the blocking threadpool since a large folder takes a while
+pub async fn purge(item: &TrashItem) -> Result<(), AppErrorKind> {
+    let id = item.id.clone();
+    let purged = web::block(move || {
+        let _lock = lock();
+        remove_item(&id)
+    }).await;
+    match purged {
+        Ok(result) => result.map_err(Into::into),
+        Err(_e) => Err(AppErrorKind::FailedToDelete)
+    }
+}
+
+fn remove_item(id: &str) -> io::Result<()> {
+    let path = item_path(id);
+    let result = match path.symlink_metadata() {
+        Ok(data) if data.is_dir() => fs::remove_dir_all(&path),
+        Ok(_) => fs::remove_file(&path),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err)
+    };
+    result?;
+    remove_record(id);
+    Ok(())
+}
+
+fn remove_record(id: &str) {
+    if let Err(err) = fs::remove_file(record_path(id)) {
+        log::error!("could not remove trash record {}: {}", id, err);
+    }
+}
+
+/// Purges the items kept past the retention period, then the oldest items until the
+/// trash fits in its size limit
+pub fn purge_expired() {
+    purge_old(None);
+}
+
+/// Like purge_expired, but never purges the item with the id, which was just trashed
+fn purge_old(keep: Option<&str>) {
+    let items = list();
+    let _lock = lock();
+    let expired_before = retention_days().map(|days| audit::now() - days * SECONDS_PER_DAY);
+    let mut size: u64 = items.iter().map(|item| item.size).sum();
+    let max_size = max_size();
+    // oldest first
+    for item in items.iter().rev() {
+        let expired = expired_before.is_some_and(|before| item.deleted_at < before);
+        let over_size = max_size.is_some_and(|max| size > max);
+        if (!expired && !over_size) || keep == Some(item.id.as_str()) {
+            continue;
+        }
+        match remove_item(&item.id) {
+            Ok(()) => {
+                size = size.saturating_sub(item.size);
+                log::info!("purged '{}' from the trash, deleted by '{}'", item.original_path(), item.deleted_by);
+            },
+            Err(err) => log::error!("could not purge '{}' from the trash: {}", item.original_path(), err)
+        }
+    }
+}
+
+/// Starts the thread that purges expired items from the trash every hour
+pub fn start_purging() -> Result<(), io::Error> {
+    thread::Builder::new().name(String::from("trash")).spawn(|| loop {
+        purge_expired();
+        thread::sleep(PURGE_INTERVAL);
+    })?;
+    Ok(())
+}
+
+/// Returns if the trash folder is somewhere in the root folder users could browse to
+pub fn is_exposed(root: &Path) -> bool {
+    let trash = match fs::canonicalize(trash_folder()) {
+        Ok(trash) => trash,
+        Err(_) => return false
+    };
+    trash.strip_prefix(root).ok()
+        .and_then(|relative| relative.to_str())
+        .and_then(|relative| Folder::from_relative_path(&relative.replace(std::path::MAIN_SEPARATOR, "/")).ok())
+        .is_some_and(|folder| folder.to_path().is_ok())
+}
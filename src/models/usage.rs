@@ -0,0 +1,167 @@
+use std::{collections::BTreeMap, fs, io, ops::AddAssign, path::{Path, PathBuf}, sync::Mutex, time::SystemTime};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::models::{folder::Folder, listing::{format_size, natural_cmp}};
+
+/// Past this many folders the cache is cleared, so folders that no longer exist do not pile up
+const MAX_CACHED_FOLDERS: usize = 100_000;
+
+/// Every folder measured so far, so only folders modified since have to be read again
+static USAGE_CACHE: Mutex<BTreeMap<PathBuf, Measured>> = Mutex::new(BTreeMap::new());
+
+/// What is directly in one folder, as of its modified time
+#[derive(Clone)]
+struct Measured {
+    modified: SystemTime,
+    size: u64,
+    files: u64,
+    subfolders: Vec<PathBuf>,
+}
+
+/// The total size of the files below a folder and how many files and folders there are
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub size: u64,
+    pub files: u64,
+    pub folders: u64,
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.size += other.size;
+        self.files += other.files;
+        self.folders += other.folders;
+    }
+}
+
+impl Usage {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "size": self.size,
+            "size_display": format_size(self.size),
+            "files": self.files,
+            "folders": self.folders
+        })
+    }
+}
+
+/// The free and total space of the filesystem a folder is on, in bytes
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct DiskSpace {
+    pub free: u64,
+    pub total: u64,
+}
+
+impl DiskSpace {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "free_display": format_size(self.free),
+            "total_display": format_size(self.total),
+            "used_display": format_size(self.total.saturating_sub(self.free)),
+            "used_percent": percent(self.total.saturating_sub(self.free), self.total)
+        })
+    }
+}
+
+/// One row of the disk usage page
+pub struct UsageEntry {
+    pub folder: Folder,
+    pub is_folder: bool,
+    pub usage: Usage,
+}
+
+impl UsageEntry {
+    /// Total is the size of the folder the row is in, for the bar showing its share
+    pub fn to_json(&self, total: u64) -> serde_json::Value {
+        json!({
+            "path": self.folder.url(),
+            "name": self.folder.name(),
+            "url_name": crate::models::folder::url_name(self.folder.name()),
+            "is_folder": self.is_folder,
+            "usage": self.usage.to_json(),
+            "percent": percent(self.usage.size, total)
+        })
+    }
+}
+
+/// Sorts the rows of the disk usage page, largest first
+pub fn sort_largest(entries: &mut [UsageEntry]) {
+    entries.sort_by(|a, b| b.usage.size.cmp(&a.usage.size).then_with(|| natural_cmp(a.folder.name(), b.folder.name())));
+}
+
+fn percent(part: u64, whole: u64) -> u64 {
+    if whole == 0 { 0 } else { (part as u128 * 100 / whole as u128) as u64 }
+}
+
+/// Adds up everything below a folder. Symbolic links below it are not followed so
+/// nothing is counted twice, and subfolders that cannot be read count as empty.
+/// A folder's modified time only changes when something is added, removed or renamed
+/// directly in it, so a file rewritten in place keeps its old size until then
+pub fn measure(path: &Path) -> io::Result<Usage> {
+    let modified = fs::metadata(path)?.modified()?;
+    let cached = USAGE_CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(path)
+        .filter(|measured| measured.modified == modified)
+        .cloned();
+    let measured = match cached {
+        Some(measured) => measured,
+        None => {
+            let measured = measure_folder(path, modified)?;
+            let mut cache = USAGE_CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if cache.len() >= MAX_CACHED_FOLDERS {
+                cache.clear();
+            }
+            cache.insert(path.to_path_buf(), measured.clone());
+            measured
+        }
+    };
+    let mut usage = Usage { size: measured.size, files: measured.files, folders: measured.subfolders.len() as u64 };
+    for subfolder in &measured.subfolders {
+        if let Ok(subfolder_usage) = measure(subfolder) {
+            usage += subfolder_usage;
+        }
+    }
+    Ok(usage)
+}
+
+fn measure_folder(path: &Path, modified: SystemTime) -> io::Result<Measured> {
+    let mut measured = Measured { modified, size: 0, files: 0, subfolders: Vec::new() };
+    for dir_entry in fs::read_dir(path)?.flatten() {
+        let data = match dir_entry.metadata() {
+            Ok(data) => data,
+            Err(_) => continue
+        };
+        if data.is_dir() {
+            measured.subfolders.push(dir_entry.path());
+        }
+        else if data.is_file() {
+            measured.size += data.len();
+            measured.files += 1;
+        }
+    }
+    Ok(measured)
+}
+
+/// Returns the free and total space of the filesystem the path is on
+#[cfg(unix)]
+pub fn disk_space(path: &Path) -> Option<DiskSpace> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a valid nul terminated string and stat is a valid statvfs to write to
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let fragment_size = stat.f_frsize as u64;
+    Some(DiskSpace {
+        free: stat.f_bavail as u64 * fragment_size,
+        total: stat.f_blocks as u64 * fragment_size,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn disk_space(_path: &Path) -> Option<DiskSpace> {
+    None
+}
@@ -1,22 +1,44 @@
-use std::{collections::HashMap, cmp::Ordering};
+use std::{env, fs, path::Path, sync::{Mutex, OnceLock}};
 
 use actix_session::Session;
+use actix_web::web;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use rand::{Rng, distributions::Alphanumeric, rngs::OsRng};
 use serde::{Deserialize, Serialize};
 
-use crate::util::error::AppErrorKind;
+use crate::{models::{access::{Access, AccessList, AccessRule}, token::{ApiToken, TokenScope}, totp::Totp}, util::{auth::Permission, error::AppErrorKind}};
 
-const USER_SESSION_KEY: &str = "user";
+pub const USER_SESSION_KEY: &str = "user";
+const USERS_FILE_ENV: &str = "FS_USERS_FILE";
+const DEFAULT_USERS_FILE: &str = "private/users.json";
+const ADMIN_PASSWORD_ENV: &str = "FS_ADMIN_PASSWORD";
+const DEFAULT_ADMIN_USERNAME: &str = "admin";
+pub const MIN_PASSWORD_LENGTH: usize = 8;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Serializes every read-modify-write of the users file
+static USERS_FILE_LOCK: Mutex<()> = Mutex::new(());
+/// Checked against when a username does not exist so the response takes as
+/// long as a wrong password and does not reveal which usernames exist
+static DUMMY_PASSWORD_HASH: OnceLock<String> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub enum UserAuthority {
+    #[default]
     Guest,
     User,
     Admin,
 }
 
-impl Default for UserAuthority {
-    fn default() -> Self {
-        UserAuthority::Guest
+impl UserAuthority {
+    /// Maps each authority to the operations it may perform.
+    /// Guests can only browse and download, users can also change files
+    /// and admins can do everything including timelapse and user management
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            UserAuthority::Guest => matches!(permission, Permission::Read | Permission::Download),
+            UserAuthority::User => matches!(permission, Permission::Read | Permission::Download | Permission::Write | Permission::Delete),
+            UserAuthority::Admin => true
+        }
     }
 }
 
@@ -24,31 +46,205 @@ impl Default for UserAuthority {
 pub struct User {
     pub username: String,
     pub authority: UserAuthority,
+    /// Folder relative to the root folder the user is jailed to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acl: Vec<AccessRule>,
+    /// Lists files and folders starting with a '.'
+    #[serde(default)]
+    pub show_hidden: bool,
+    /// Set when the user authenticated with an API token instead of logging in
+    #[serde(skip)]
+    pub token_scope: Option<TokenScope>,
+    /// Filled in from the users file, the TOTP secret itself never leaves it
+    #[serde(skip)]
+    pub totp_enabled: bool,
 }
 
 impl Default for User {
-    fn default() -> Self { 
+    fn default() -> Self {
         Self {
             username: "Guest".to_string(),
-            authority: UserAuthority::Guest
+            authority: UserAuthority::Guest,
+            home: None,
+            acl: Vec::new(),
+            show_hidden: false,
+            token_scope: None,
+            totp_enabled: false
         }
     }
 }
 
+/// A user as it is stored in the users file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UserRecord {
+    #[serde(flatten)]
+    user: User,
+    password_hash: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tokens: Vec<ApiToken>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp: Option<Totp>,
+}
+
 impl User {
     pub async fn fetch(username: &str, password: &str) -> Result<Self, AppErrorKind> {
-        // TODO Fetch user from db with username and password
-        Self::test_only_get_user(username, password)
+        let record = Self::find_record(username)?;
+        let password = password.to_owned();
+        let (record, verified) = web::block(move || {
+            let verified = match &record {
+                Some(record) => verify_password(&password, &record.password_hash),
+                None => {
+                    let dummy = DUMMY_PASSWORD_HASH.get_or_init(|| argon2_hash(&generate_password()).unwrap_or_default());
+                    verify_password(&password, dummy);
+                    false
+                }
+            };
+            (record, verified)
+        }).await.map_err(|_| AppErrorKind::InvalidUserCredentials)?;
+        match record {
+            Some(record) if verified => Ok(record.user),
+            _ => Err(AppErrorKind::InvalidUserCredentials)
+        }
+    }
+
+    /// Returns the stored user with the given username
+    pub fn find(username: &str) -> Result<Self, AppErrorKind> {
+        Self::find_record(username)?
+            .map(|record| record.user)
+            .ok_or(AppErrorKind::UserNotFound)
+    }
+
+    /// Returns every stored user sorted by username
+    pub fn list() -> Result<Vec<Self>, AppErrorKind> {
+        let _lock = USERS_FILE_LOCK.lock().map_err(|err| AppErrorKind::UserStore(err.to_string()))?;
+        let mut users: Vec<Self> = Self::read_records()?.into_iter().map(|record| record.user).collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    /// Adds a new user to the users file. Fails if the username is already taken, or could
+    /// not be used as one segment of the /admin/users/{username} urls
+    pub async fn create(username: &str, password: &str, authority: UserAuthority) -> Result<Self, AppErrorKind> {
+        let username = username.trim().to_owned();
+        if !is_valid_username(&username) {
+            return Err(AppErrorKind::UsernameInvalid);
+        }
+        let password_hash = hash_password_blocking(password).await?;
+        let user = Self { username, authority, ..Default::default() };
+        let record = UserRecord { user: user.clone(), password_hash, tokens: Vec::new(), totp: None };
+        Self::update_records(move |records| {
+            if records.iter().any(|r| r.user.username == record.user.username) {
+                return Err(AppErrorKind::UserAlreadyExists);
+            }
+            records.push(record);
+            Ok(())
+        })?;
+        Ok(user)
+    }
+
+    /// Checks the user's authority and, when using an API token, the token's scope
+    pub fn allows(&self, permission: Permission) -> bool {
+        let read_only = self.token_scope.as_ref().is_some_and(|scope| scope.read_only);
+        self.authority.allows(permission) && (!read_only || Access::Read.permits(permission))
+    }
+
+    pub fn access_list(&self) -> AccessList {
+        let access = AccessList::new(self.home.as_deref(), &self.acl);
+        match &self.token_scope {
+            Some(scope) => access.restrict(
+                scope.folder.as_deref(),
+                if scope.read_only { Access::Read } else { Access::Write }
+            ),
+            None => access
+        }
     }
 
+    /// Saves changes to the user's authority, home folder and access rules
     pub fn save(&self) -> Result<(), AppErrorKind> {
-        // TODO Save user changes to a db
-        Ok(())
+        let user = self.clone();
+        Self::update_records(move |records| {
+            let record = records.iter_mut().find(|r| r.user.username == user.username)
+                .ok_or(AppErrorKind::UserNotFound)?;
+            record.user = user;
+            Ok(())
+        })
+    }
+
+    /// Replaces the user's password with a new salted hash
+    pub async fn set_password(&self, password: &str) -> Result<(), AppErrorKind> {
+        let password_hash = hash_password_blocking(password).await?;
+        let username = self.username.clone();
+        Self::update_records(move |records| {
+            let record = records.iter_mut().find(|r| r.user.username == username)
+                .ok_or(AppErrorKind::UserNotFound)?;
+            record.password_hash = password_hash;
+            Ok(())
+        })
     }
 
     pub fn delete(&self) -> Result<(), AppErrorKind> {
-        // TODO Delete user from a db
-        Ok(())
+        let username = self.username.clone();
+        Self::update_records(move |records| {
+            let count = records.len();
+            records.retain(|r| r.user.username != username);
+            if records.len() == count {
+                return Err(AppErrorKind::UserNotFound);
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the user owning a plain text API token if it is valid and not expired
+    pub fn from_token(token: &str) -> Result<Self, AppErrorKind> {
+        let (id, secret) = ApiToken::parse(token).ok_or(AppErrorKind::InvalidApiToken)?;
+        let _lock = USERS_FILE_LOCK.lock().map_err(|err| AppErrorKind::UserStore(err.to_string()))?;
+        for record in Self::read_records()? {
+            if let Some(api_token) = record.tokens.iter().find(|t| t.id == id) {
+                if !api_token.verify(secret) {
+                    return Err(AppErrorKind::InvalidApiToken);
+                }
+                let mut user = record.user;
+                user.token_scope = Some(api_token.scope.clone());
+                return Ok(user);
+            }
+        }
+        Err(AppErrorKind::InvalidApiToken)
+    }
+
+    pub fn tokens(&self) -> Result<Vec<ApiToken>, AppErrorKind> {
+        Self::find_record(&self.username)?
+            .map(|record| record.tokens)
+            .ok_or(AppErrorKind::UserNotFound)
+    }
+
+    /// Creates an API token for the user and returns its plain text value
+    pub fn create_token(&self, name: &str, scope: TokenScope, expires_in_days: Option<i64>) -> Result<String, AppErrorKind> {
+        let (api_token, token) = ApiToken::generate(name, scope, expires_in_days);
+        let username = self.username.clone();
+        Self::update_records(move |records| {
+            let record = records.iter_mut().find(|r| r.user.username == username)
+                .ok_or(AppErrorKind::UserNotFound)?;
+            record.tokens.push(api_token);
+            Ok(())
+        })?;
+        Ok(token)
+    }
+
+    pub fn revoke_token(&self, id: &str) -> Result<(), AppErrorKind> {
+        let username = self.username.clone();
+        let id = id.to_owned();
+        Self::update_records(move |records| {
+            let record = records.iter_mut().find(|r| r.user.username == username)
+                .ok_or(AppErrorKind::UserNotFound)?;
+            let count = record.tokens.len();
+            record.tokens.retain(|t| t.id != id);
+            if record.tokens.len() == count {
+                return Err(AppErrorKind::InvalidApiToken);
+            }
+            Ok(())
+        })
     }
 
     pub fn insert(&self, session: Session) -> Result<(), AppErrorKind> {
@@ -70,38 +266,190 @@ impl User {
     }
 
     pub fn remove(session: Session) -> Result<(), AppErrorKind> {
-        if let Some(_) = session.remove(USER_SESSION_KEY) {
+        if session.remove(USER_SESSION_KEY).is_some() {
             Ok(())
         }
         else {
             Err(AppErrorKind::Session("could not logout user from session".to_owned(), Some(session)))
         }
     }
+}
+
+/// Handles the TOTP second factor
+impl User {
+    /// Permissions only an Admin has need a second factor so a stolen
+    /// password alone cannot manage users or run the camera
+    pub fn needs_second_factor(&self, permission: Permission) -> bool {
+        !self.totp_enabled && !UserAuthority::User.allows(permission)
+    }
+
+    /// Turns on TOTP with a secret the user has already confirmed a code for.
+    /// Returns the recovery codes which are only shown this once
+    pub fn enable_totp(&self, secret: String) -> Result<Vec<String>, AppErrorKind> {
+        let username = self.username.clone();
+        Self::update_records(move |records| {
+            let record = records.iter_mut().find(|r| r.user.username == username)
+                .ok_or(AppErrorKind::UserNotFound)?;
+            if record.totp.is_some() {
+                return Err(AppErrorKind::TotpAlreadyEnabled);
+            }
+            let (totp, codes) = Totp::new(secret);
+            record.totp = Some(totp);
+            Ok(codes)
+        })
+    }
 
-    fn test_only_get_user(username: &str, password: &str) -> Result<User, AppErrorKind> {
-        let mut test_users: HashMap<&str, (&str, User)> = HashMap::new();
-        // test_users.insert("admin", ("admin123", User {
-        //     username: "admin".to_string(),
-        //     authority: UserAuthority::Admin
-        // }));
-        test_users.insert("nick", ("testing", User {
-            username: "nick".to_string(),
-            authority: UserAuthority::User
-        }));
-        // test_users.insert("guest", ("guest", User {
-        //     username: "guest".to_string(),
-        //     authority: UserAuthority::Guest
-        // }));
-        if let Some((real_password, user)) = test_users.get(username) {
-            if real_password.cmp(&password) != Ordering::Equal {
-                Err(AppErrorKind::InvalidUserCredentials)
+    /// Checks an authenticator code or a recovery code. When a recovery code
+    /// is used it returns how many are left
+    pub fn verify_second_factor(&self, code: &str) -> Result<Option<usize>, AppErrorKind> {
+        let username = self.username.clone();
+        let code = code.to_owned();
+        Self::update_records(move |records| {
+            let totp = records.iter_mut().find(|r| r.user.username == username)
+                .ok_or(AppErrorKind::UserNotFound)?
+                .totp.as_mut()
+                .ok_or(AppErrorKind::TotpNotEnabled)?;
+            if totp.verify_code(&code) {
+                Ok(None)
+            }
+            else if totp.use_recovery_code(&code) {
+                Ok(Some(totp.recovery_codes_left()))
             }
             else {
-                Ok(user.to_owned())
+                Err(AppErrorKind::TotpCodeInvalid)
             }
+        })
+    }
+
+    pub fn disable_totp(&self) -> Result<(), AppErrorKind> {
+        let username = self.username.clone();
+        Self::update_records(move |records| {
+            let record = records.iter_mut().find(|r| r.user.username == username)
+                .ok_or(AppErrorKind::UserNotFound)?;
+            record.totp.take().ok_or(AppErrorKind::TotpNotEnabled)?;
+            Ok(())
+        })
+    }
+}
+
+/// Handles reading and writing the users file
+impl User {
+
+    /// Returns the location of the users file. Defaults to private/users.json
+    /// but can be changed with the FS_USERS_FILE environment variable
+    pub fn users_file() -> String {
+        env::var(USERS_FILE_ENV).unwrap_or_else(|_| DEFAULT_USERS_FILE.to_owned())
+    }
+
+    /// Creates the users file with a single admin account if it does not exist yet.
+    /// The admin password is taken from FS_ADMIN_PASSWORD or generated and logged once.
+    pub fn init_store() -> Result<(), AppErrorKind> {
+        let _lock = USERS_FILE_LOCK.lock().map_err(|err| AppErrorKind::UserStore(err.to_string()))?;
+        if Path::new(&Self::users_file()).exists() {
+            return Ok(());
         }
-        else {
-            Err(AppErrorKind::InvalidUserCredentials)
+        let password = match env::var(ADMIN_PASSWORD_ENV) {
+            Ok(password) => password,
+            Err(_) => {
+                let password = generate_password();
+                log::warn!("created user '{}' with password '{}'. Change it from the /user page after logging in", DEFAULT_ADMIN_USERNAME, password);
+                password
+            }
+        };
+        let record = UserRecord {
+            user: User {
+                username: DEFAULT_ADMIN_USERNAME.to_owned(),
+                authority: UserAuthority::Admin,
+                ..Default::default()
+            },
+            password_hash: hash_password(&password)?,
+            tokens: Vec::new(),
+            totp: None
+        };
+        Self::write_records(&[record])
+    }
+
+    fn find_record(username: &str) -> Result<Option<UserRecord>, AppErrorKind> {
+        let _lock = USERS_FILE_LOCK.lock().map_err(|err| AppErrorKind::UserStore(err.to_string()))?;
+        Ok(Self::read_records()?.into_iter().find(|r| r.user.username == username))
+    }
+
+    fn update_records<F, T>(update: F) -> Result<T, AppErrorKind>
+        where F: FnOnce(&mut Vec<UserRecord>) -> Result<T, AppErrorKind>
+    {
+        let _lock = USERS_FILE_LOCK.lock().map_err(|err| AppErrorKind::UserStore(err.to_string()))?;
+        let mut records = Self::read_records()?;
+        let result = update(&mut records)?;
+        Self::write_records(&records)?;
+        Ok(result)
+    }
+
+    fn read_records() -> Result<Vec<UserRecord>, AppErrorKind> {
+        let contents = match fs::read_to_string(Self::users_file()) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(AppErrorKind::Io(err))
+        };
+        let mut records: Vec<UserRecord> = serde_json::from_str(&contents).map_err(|err| AppErrorKind::UserStore(err.to_string()))?;
+        for record in records.iter_mut() {
+            record.user.totp_enabled = record.totp.is_some();
+        }
+        Ok(records)
+    }
+
+    /// Writes to a temporary file first so a crash never leaves a half written users file
+    fn write_records(records: &[UserRecord]) -> Result<(), AppErrorKind> {
+        let users_file = Self::users_file();
+        let tmp_file = format!("{}.tmp", users_file);
+        let contents = serde_json::to_string_pretty(records).map_err(|err| AppErrorKind::UserStore(err.to_string()))?;
+        fs::write(&tmp_file, contents).map_err(AppErrorKind::Io)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp_file, fs::Permissions::from_mode(0o600)).map_err(AppErrorKind::Io)?;
         }
+        fs::rename(&tmp_file, &users_file).map_err(AppErrorKind::Io)
     }
 }
+
+/// A username is put into urls as it is, so it cannot be empty or hold a character that
+/// ends or escapes a path segment
+fn is_valid_username(username: &str) -> bool {
+    !username.is_empty() && !username.chars().any(|c| matches!(c, '/' | '?' | '#' | '%') || c.is_control())
+}
+
+/// Hashes a password with argon2 and a random salt
+pub fn hash_password(password: &str) -> Result<String, AppErrorKind> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppErrorKind::PasswordTooShort);
+    }
+    argon2_hash(password).map_err(|err| AppErrorKind::UserStore(err.to_string()))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false
+    }
+}
+
+fn argon2_hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt).map(|hash| hash.to_string())
+}
+
+/// Hashing is deliberately slow so it is run on the blocking threadpool
+async fn hash_password_blocking(password: &str) -> Result<String, AppErrorKind> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppErrorKind::PasswordTooShort);
+    }
+    let password = password.to_owned();
+    match web::block(move || argon2_hash(&password)).await {
+        Ok(result) => result.map_err(|err| AppErrorKind::UserStore(err.to_string())),
+        Err(err) => Err(AppErrorKind::UserStore(err.to_string()))
+    }
+}
+
+fn generate_password() -> String {
+    OsRng.sample_iter(&Alphanumeric).take(16).map(char::from).collect()
+}
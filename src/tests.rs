@@ -1,8 +1,93 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use actix_web::{body::to_bytes, dev::Service, http, test, App, Error};
+    use std::{collections::HashMap, ffi::{OsStr, OsString}, path::PathBuf, sync::{Arc, Mutex, MutexGuard}};
+
+    use actix_session::{SessionMiddleware, storage::{CookieSessionStore, SessionStore}};
+    use actix_web::{cookie::Key, dev::Service, http, test, web, App, Error, HttpResponse};
+    use actix_web_flash_messages::{FlashMessagesFramework, storage::CookieMessageStore};
     
     use crate::app_config::config_app;
+    use crate::models::access::{Access, AccessList, AccessRule};
+    use crate::models::conflict::{ConflictPolicy, ItemOutcome, Outcome};
+    use crate::models::rename::{self, PlannedRename, RenamePattern};
+    use crate::models::folder::Folder;
+    use crate::models::usage::{self, Usage};
+    use crate::models::content::{ContentMatcher, HitLine};
+    use crate::models::trash;
+    use crate::models::search::{self, EntryKind, MatchMode, Search, SearchQuery, SearchWalk};
+    use crate::models::listing::{natural_cmp, format_size, Entry, Page, Sort, SortKey, SortOrder};
+    use crate::util::error::AppErrorKind;
+    use crate::util::zip;
+    use crate::util::jobs::{self, Job, JobQueue, JobState, Progress};
+    use crate::models::token::{ApiToken, TokenScope};
+    use crate::models::totp::{self, Totp};
+    use crate::models::user::{hash_password, verify_password, User, UserAuthority};
+    use crate::util::audit::{self, AuditEntry, AuditFilter, Auditor, Operation};
+    use crate::util::csrf::CsrfProtection;
+    use crate::util::index::{self, FileIndex};
+    use crate::util::live::{ChangeKind, FolderFeed, LiveUpdates};
+    use crate::util::sessions::{ServerSessionStore, SessionRegistry};
+    use crate::util::throttle::{AttemptKey, LoginThrottle};
+
+    /// Tests that change the environment, like pointing FS_ROOT_FOLDER at their own folder, take turns
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A folder under target for one test and the environment variables it changed, holding
+    /// ENV_LOCK. Dropping it puts the variables back and removes the folder, also when an
+    /// assert failed, so one failing test does not break the ones after it
+    struct TestEnv {
+        base: PathBuf,
+        saved: Vec<(&'static str, Option<OsString>)>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl TestEnv {
+        /// Waits for the other tests changing the environment, then makes an empty target/ppfs-name-pid
+        fn new(name: &str) -> Self {
+            let lock = ENV_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let base = std::env::current_dir().unwrap().join("target").join(format!("ppfs-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&base);
+            std::fs::create_dir_all(&base).unwrap();
+            Self { base, saved: Vec::new(), _lock: lock }
+        }
+
+        /// Points FS_ROOT_FOLDER at a folder in the test folder, or at the test folder itself for ""
+        fn root_folder(&mut self, relative: &str) {
+            let root = if relative.is_empty() { self.base.clone() } else { self.base.join(relative) };
+            std::fs::create_dir_all(&root).unwrap();
+            let root = root.strip_prefix(std::env::current_dir().unwrap()).unwrap().to_owned();
+            self.set_var("FS_ROOT_FOLDER", root);
+        }
+
+        fn set_var(&mut self, key: &'static str, value: impl AsRef<OsStr>) {
+            self.save(key);
+            std::env::set_var(key, value);
+        }
+
+        fn remove_var(&mut self, key: &'static str) {
+            self.save(key);
+            std::env::remove_var(key);
+        }
+
+        fn save(&mut self, key: &'static str) {
+            if !self.saved.iter().any(|(saved, _)| *saved == key) {
+                self.saved.push((key, std::env::var_os(key)));
+            }
+        }
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            for (key, value) in self.saved.drain(..).rev() {
+                match value {
+                    Some(value) => std::env::set_var(key, value),
+                    None => std::env::remove_var(key)
+                }
+            }
+            let _ = std::fs::remove_dir_all(&self.base);
+        }
+    }
 
     #[actix_web::test]
     async fn test_index() -> Result<(), Error> {
@@ -12,11 +97,756 @@ mod tests {
         let req = test::TestRequest::get().uri("/").to_request();
         let resp = app.call(req).await?;
 
-        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(resp.status(), http::StatusCode::PERMANENT_REDIRECT);
 
         // let response_body = resp.into_body();
         // assert_eq!(to_bytes(response_body).await?, r##"Hello world!"##);
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[actix_web::test]
+    async fn test_password_hash() {
+        let hash = hash_password("correct horse").unwrap();
+        assert_ne!(hash, "correct horse");
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(hash_password("short").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_username_rejects_url_characters() {
+        for username in ["", "  ", "a/b", "x?y", "z#", "100%", "tab\tname"] {
+            assert!(matches!(User::create(username, "correct horse", UserAuthority::User).await, Err(AppErrorKind::UsernameInvalid)), "{:?}", username);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_access_list() {
+        let acl = vec![
+            AccessRule::parse("read shared").unwrap(),
+            AccessRule::parse("deny photos/private").unwrap(),
+        ];
+        let access = AccessList::new(Some("photos"), &acl);
+        assert_eq!(access.access(""), Access::Traverse);
+        assert_eq!(access.access("other"), Access::Deny);
+        assert_eq!(access.access("shared/notes.txt"), Access::Read);
+        assert_eq!(access.access("photos/2022"), Access::Write);
+        assert_eq!(access.access("photos/private/a.jpg"), Access::Deny);
+        assert_eq!(access.subtree_access("photos"), Access::Deny);
+        assert_eq!(access.subtree_access("photos/2022"), Access::Write);
+        assert!(AccessRule::parse("read ../etc").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_api_token() {
+        let (api_token, token) = ApiToken::generate("script", TokenScope::default(), Some(1));
+        let (id, secret) = ApiToken::parse(&token).unwrap();
+        assert_eq!(id, api_token.id);
+        assert!(api_token.verify(secret));
+        assert!(!api_token.verify("wrong"));
+        assert!(ApiToken::parse("not_a_token").is_none());
+
+        let access = AccessList::new(None, &[]).restrict(Some("photos"), Access::Read);
+        assert_eq!(access.access(""), Access::Traverse);
+        assert_eq!(access.access("photos/2022"), Access::Read);
+        assert_eq!(access.access("music"), Access::Deny);
+    }
+
+    #[actix_web::test]
+    async fn test_login_throttle() {
+        let mut throttle = LoginThrottle::new();
+        for _ in 0..4 {
+            throttle.record_failure("10.0.0.2", "bob");
+        }
+        assert!(throttle.check("10.0.0.2", "bob").is_ok());
+        throttle.record_failure("10.0.0.2", "bob");
+        assert!(throttle.check("10.0.0.2", "bob").is_err());
+        assert!(throttle.check("10.0.0.3", "bob").is_err());
+        assert!(throttle.check("10.0.0.2", "carol").is_ok());
+        assert_eq!(throttle.lockouts().len(), 1);
+        assert!(throttle.unlock(&AttemptKey::Username(String::from("bob"))));
+        assert!(throttle.check("10.0.0.3", "bob").is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_totp() {
+        // RFC 6238 test secret "12345678901234567890" at 59 seconds
+        assert_eq!(totp::code_at("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", 1), Some(287082));
+        assert_eq!(totp::code_at("not base32!", 1), None);
+
+        let (mut totp, codes) = Totp::new(totp::generate_secret());
+        assert_eq!(codes.len(), totp.recovery_codes_left());
+        assert!(totp.use_recovery_code(&codes[0].to_uppercase()));
+        assert!(!totp.use_recovery_code(&codes[0]));
+        assert!(!totp.verify_code("12345"));
+    }
+
+    #[actix_web::test]
+    async fn test_csrf_rejects_forged_post() -> Result<(), Error> {
+        let key = Key::generate();
+        let app = App::new()
+            .wrap(CsrfProtection)
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), key.clone()))
+            .wrap(FlashMessagesFramework::builder(CookieMessageStore::builder(key).build()).build())
+            .route("/change", web::post().to(HttpResponse::Ok));
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post().uri("/change")
+            .set_form([("csrf_token", "forged")])
+            .to_request();
+        let resp = app.call(req).await?;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+
+        let req = test::TestRequest::post().uri("/change")
+            .insert_header((http::header::AUTHORIZATION, "Bearer ppfs_id_secret"))
+            .to_request();
+        let resp = app.call(req).await?;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_session_store_revoke() {
+        let mut env = TestEnv::new("sessions");
+        let file = env.base.join("sessions.json");
+        env.set_var("FS_SESSIONS_FILE", &file);
+        let registry = Arc::new(Mutex::new(SessionRegistry::load()));
+        let store = ServerSessionStore::new(registry.clone());
+        let ttl = registry.lock().unwrap().cookie_ttl();
+        let mut state = HashMap::new();
+        state.insert(String::from("user"), String::from(r#"{"username":"bob","authority":"Guest"}"#));
+        state.insert(String::from("session_info"), String::from(r#"{"id":"abc","ip":"10.0.0.2","user_agent":"test"}"#));
+        state.insert(String::from("totp_setup"), String::from(r#""JBSWY3DPEHPK3PXP""#));
+        let key = store.save(state, &ttl).await.unwrap();
+
+        let stored = std::fs::read_to_string(&file).unwrap();
+        assert!(stored.contains("session_info") && !stored.contains("totp_setup"));
+        assert_eq!(SessionRegistry::load().sessions_of("bob").len(), 1);
+        assert!(store.load(&key).await.unwrap().is_some());
+        assert_eq!(registry.lock().unwrap().sessions_of("bob").len(), 1);
+        assert!(registry.lock().unwrap().revoke("carol", "abc").is_err());
+        assert!(registry.lock().unwrap().is_active("abc"));
+        assert_eq!(registry.lock().unwrap().revoke_all("bob"), 1);
+        assert!(store.load(&key).await.unwrap().is_none());
+        assert!(!registry.lock().unwrap().is_active("abc"));
+    }
+
+    #[actix_web::test]
+    async fn test_session_store_limits_anonymous() {
+        let mut env = TestEnv::new("anonymous-sessions");
+        env.set_var("FS_SESSIONS_FILE", "");
+        env.set_var("FS_MAX_ANONYMOUS_SESSIONS", "3");
+        let registry = Arc::new(Mutex::new(SessionRegistry::load()));
+        let store = ServerSessionStore::new(registry.clone());
+        let ttl = registry.lock().unwrap().cookie_ttl();
+        let mut keys = Vec::new();
+        for token in 0..5 {
+            let mut state = HashMap::new();
+            state.insert(String::from("csrf_token"), format!(r#""{}""#, token));
+            keys.push(store.save(state, &ttl).await.unwrap());
+        }
+        let mut state = HashMap::new();
+        state.insert(String::from("user"), String::from(r#"{"username":"bob","authority":"Guest"}"#));
+        let logged_in = store.save(state, &ttl).await.unwrap();
+
+        for (index, key) in keys.iter().enumerate() {
+            assert_eq!(store.load(key).await.unwrap().is_some(), index >= 2, "session {}", index);
+        }
+        assert!(store.load(&logged_in).await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_audit_log() {
+        let mut env = TestEnv::new("audit");
+        let file = env.base.join("audit.log");
+        env.set_var("FS_AUDIT_FILE", &file);
+        let entry = |user: &str, operation, path: &str, result: &str| AuditEntry {
+            time: audit::now(),
+            user: user.to_owned(),
+            ip: String::from("10.0.0.2"),
+            operation,
+            path: path.to_owned(),
+            target: None,
+            result: result.to_owned()
+        };
+        audit::record(&entry("bob", Operation::Upload, "a.txt", "ok"));
+        audit::record(&entry("carol", Operation::Delete, "sub/b.txt", "permission denied"));
+        audit::record(&entry("bob", Operation::Delete, "sub/c.txt", "ok"));
+
+        let (entries, more) = audit::read(&AuditFilter::default(), 0, 2).unwrap();
+        assert!(more);
+        assert_eq!(entries[0].path, "sub/c.txt");
+        let filter = AuditFilter { user: Some(String::from("bob")), operation: Some(String::from("delete")), ..Default::default() };
+        assert_eq!(audit::read(&filter, 0, 10).unwrap().0.len(), 1);
+        let filter = AuditFilter { path: Some(String::from("sub")), result: Some(String::from("failed")), ..Default::default() };
+        let (entries, _) = audit::read(&filter, 0, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].user, "carol");
+    }
+
+    #[actix_web::test]
+    async fn test_folder_path_encoding() {
+        assert!(Folder::default().join("a/b").is_err());
+        let folder = Folder::default().join("c++ notes").unwrap().join("50% off?#&").unwrap();
+        assert_eq!(folder.to_string(), "root+c%2B%2B%20notes+50%25%20off%3F%23%26");
+        assert_eq!(folder.relative_path(), "c++ notes/50% off?#&");
+        assert_eq!(folder.name(), "50% off?#&");
+        assert_eq!(Folder::new(&folder.to_string()).unwrap(), folder);
+        assert_eq!(folder.url(), "root+c%252B%252B%2520notes+50%2525%2520off%253F%2523%2526");
+        assert_eq!(folder.parent().unwrap().name(), "c++ notes");
+
+        assert!(Folder::new("root+..+etc").is_err());
+        assert!(Folder::new("root+%2E%2E").is_err());
+        assert!(Folder::new("root+a%2Fb").is_err());
+        assert!(Folder::new("home+a").is_err());
+        assert!(Folder::new("root").unwrap().is_root());
+
+        let notes = folder.parent().unwrap();
+        assert_eq!(folder.move_destination("..", "").unwrap(), notes);
+        assert_eq!(notes.move_destination("sub", "").unwrap(), notes.join("sub").unwrap());
+        assert_eq!(notes.move_destination("sub", &folder.to_string()).unwrap(), folder);
+        assert!(matches!(notes.move_destination("", ""), Err(AppErrorKind::DestinationMissing)));
+        assert!(matches!(Folder::default().move_destination("..", ""), Err(AppErrorKind::CannotMoveAboveRoot)));
+    }
+
+    #[actix_web::test]
+    async fn test_root_jail() {
+        let mut env = TestEnv::new("jail");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("root/inside")).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(base.join("root/inside/a.txt"), "a").unwrap();
+        std::fs::write(base.join("outside/secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(base.join("outside"), base.join("root/escape")).unwrap();
+        std::os::unix::fs::symlink(base.join("root/inside"), base.join("root/link")).unwrap();
+        env.root_folder("root");
+        let names = |folder: &Folder| folder.entities(false).unwrap().0.iter().map(|f| f.name().to_owned()).collect::<Vec<String>>();
+        let root = Folder::default();
+
+        env.remove_var("FS_SYMLINKS");
+        assert!(matches!(Folder::new("root+escape+secret.txt").unwrap().to_path(), Err(AppErrorKind::PathEscapesRoot)));
+        assert!(Folder::new("root+link+a.txt").unwrap().to_path().unwrap().ends_with("root/inside/a.txt"));
+        assert_eq!(names(&root), ["inside", "link"]);
+
+        env.set_var("FS_SYMLINKS", "deny");
+        assert!(matches!(Folder::new("root+link+a.txt").unwrap().to_path(), Err(AppErrorKind::SymlinkDenied)));
+        assert_eq!(names(&root), ["escape", "inside", "link"]);
+
+        env.set_var("FS_SYMLINKS", "never");
+        assert_eq!(names(&root), ["inside"]);
+    }
+
+    #[actix_web::test]
+    async fn test_ignore_rules() {
+        let mut env = TestEnv::new("ignore");
+        let base = env.base.clone();
+        for folder in [".git", "__pycache__", "sub/deep", "sub/keep"] {
+            std::fs::create_dir_all(base.join(folder)).unwrap();
+        }
+        for file in [".hidden", "notes.txt", "sub/a.tmp", "sub/deep/b.tmp", "sub/keep/c.txt"] {
+            std::fs::write(base.join(file), "").unwrap();
+        }
+        std::fs::write(base.join("sub/.ppfsignore"), "# scratch files\n*.tmp\nkeep/c.txt\n").unwrap();
+        env.root_folder("");
+        env.remove_var("FS_IGNORE");
+        let names = |folder: &Folder| {
+            let (folders, files) = folder.entities(false).unwrap();
+            folders.iter().chain(files.iter()).map(|f| f.name().to_owned()).collect::<Vec<String>>()
+        };
+
+        assert_eq!(names(&Folder::default()), ["sub", ".hidden", "notes.txt"]);
+        assert_eq!(names(&Folder::new("root+sub").unwrap()), ["deep", "keep"]);
+        assert!(names(&Folder::new("root+sub+deep").unwrap()).is_empty());
+        assert!(names(&Folder::new("root+sub+keep").unwrap()).is_empty());
+        for ignored in ["root+.git", "root+__pycache__+x.pyc", "root+sub+.ppfsignore", "root+sub+deep+b.tmp", "root+sub+keep+c.txt"] {
+            assert!(matches!(Folder::new(ignored).unwrap().to_path(), Err(AppErrorKind::PathIgnored)), "{}", ignored);
+        }
+        assert!(Folder::new("root+.hidden").unwrap().to_path().is_ok());
+
+        env.set_var("FS_IGNORE", "*.txt");
+        assert_eq!(names(&Folder::default()), [".git", "__pycache__", "sub", ".hidden"]);
+    }
+
+    #[actix_web::test]
+    async fn test_listing_sort() {
+        let mut names = vec!["img_10.jpg", "IMG_2.jpg", "img_1.jpg", "img_02.jpg", "b", "A", "10", "9"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["9", "10", "A", "b", "img_1.jpg", "IMG_2.jpg", "img_02.jpg", "img_10.jpg"]);
+        assert_eq!(natural_cmp("a", "A"), std::cmp::Ordering::Greater);
+
+        let entry = |name: &str, size: u64| {
+            let mut entry = Entry::new(Folder::default().join(name).unwrap(), false, None);
+            entry.size = size;
+            entry
+        };
+        let mut entries = vec![entry("track_10.mp3", 5), entry("cover.png", 20), entry("track_9.mp3", 5)];
+        let names = |entries: &[Entry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<String>>();
+        Sort::default().sort(&mut entries);
+        assert_eq!(names(&entries), ["cover.png", "track_9.mp3", "track_10.mp3"]);
+        Sort { key: SortKey::Size, order: SortOrder::Desc }.sort(&mut entries);
+        assert_eq!(names(&entries), ["cover.png", "track_10.mp3", "track_9.mp3"]);
+        Sort { key: SortKey::Type, order: SortOrder::Asc }.sort(&mut entries);
+        assert_eq!(names(&entries), ["track_9.mp3", "track_10.mp3", "cover.png"]);
+        assert_eq!(entries[2].mime, "image/png");
+
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+
+    #[actix_web::test]
+    async fn test_listing_pages() {
+        let mut env = TestEnv::new("pages");
+        let base = env.base.clone();
+        for folder in ["album_1", "album_2"] {
+            std::fs::create_dir_all(base.join(folder)).unwrap();
+        }
+        for (i, file) in ["img_1.jpg", "img_2.jpg", "img_3.jpg", "img_10.jpg", "img_20.jpg"].iter().enumerate() {
+            std::fs::write(base.join(file), vec![0; i]).unwrap();
+        }
+        env.root_folder("");
+        let access = AccessList::new(None, &[]);
+        let list = |sort: Sort, page: Page| {
+            let listing = Folder::default().entity_list(false, &access, false, sort, &page).unwrap();
+            let names = listing.folders.iter().chain(listing.files.iter()).map(|e| e.name.clone()).collect::<Vec<String>>();
+            (names, listing.next().map(str::to_owned), listing.total)
+        };
+        let page = |offset: usize, after: Option<&str>| Page { offset, after: after.map(str::to_owned), limit: 3 };
+
+        assert_eq!(list(Sort::default(), page(0, None)), (vec!["album_1".to_owned(), "album_2".to_owned(), "img_1.jpg".to_owned()], Some("img_1.jpg".to_owned()), 7));
+        assert_eq!(list(Sort::default(), page(0, Some("img_1.jpg"))).0, ["img_2.jpg", "img_3.jpg", "img_10.jpg"]);
+        assert_eq!(list(Sort::default(), page(6, None)), (vec!["img_20.jpg".to_owned()], None, 7));
+        assert_eq!(list(Sort::default(), page(3, Some("deleted.jpg"))).0, ["img_2.jpg", "img_3.jpg", "img_10.jpg"]);
+        assert!(list(Sort::default(), page(10, None)).0.is_empty());
+        assert_eq!(list(Sort { key: SortKey::Name, order: SortOrder::Desc }, page(0, None)).0, ["album_2", "album_1", "img_20.jpg"]);
+        assert_eq!(list(Sort { key: SortKey::Size, order: SortOrder::Desc }, page(0, Some("album_1"))).0, ["img_20.jpg", "img_10.jpg", "img_3.jpg"]);
+        assert_eq!(list(Sort::default(), Page::all()).0.len(), 7);
+    }
+
+    #[actix_web::test]
+    async fn test_folder_usage() {
+        let env = TestEnv::new("usage");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("photos/2022")).unwrap();
+        std::fs::write(base.join("notes.txt"), vec![0; 100]).unwrap();
+        std::fs::write(base.join("photos/a.jpg"), vec![0; 1000]).unwrap();
+        std::fs::write(base.join("photos/2022/b.jpg"), vec![0; 2000]).unwrap();
+
+        assert_eq!(usage::measure(&base).unwrap(), Usage { size: 3100, files: 3, folders: 2 });
+        assert_eq!(usage::measure(&base.join("photos")).unwrap(), Usage { size: 3000, files: 2, folders: 1 });
+
+        // only the folder that changed is read again, its parents come from the cache
+        std::fs::write(base.join("photos/2022/c.jpg"), vec![0; 500]).unwrap();
+        assert_eq!(usage::measure(&base).unwrap(), Usage { size: 3600, files: 4, folders: 2 });
+        std::fs::remove_dir_all(base.join("photos/2022")).unwrap();
+        assert_eq!(usage::measure(&base).unwrap(), Usage { size: 1100, files: 2, folders: 1 });
+
+        let space = usage::disk_space(&base).unwrap();
+        assert!(space.total > 0 && space.free <= space.total);
+    }
+
+    #[actix_web::test]
+    async fn test_search() {
+        let mut env = TestEnv::new("search");
+        let base = env.base.clone();
+        for folder in ["photos/2022", "photos/.cache", "private_notes", "scratch"] {
+            std::fs::create_dir_all(base.join(folder)).unwrap();
+        }
+        for (file, size) in [("photos/IMG_1.jpg", 10), ("photos/2022/img_2.JPG", 5000), ("photos/.cache/img_3.jpg", 1),
+                ("private_notes/img_4.jpg", 1), ("scratch/img_5.jpg", 1), ("notes.txt", 100)] {
+            std::fs::write(base.join(file), vec![0; size]).unwrap();
+        }
+        std::fs::write(base.join("scratch/.ppfsignore"), "*.jpg\n").unwrap();
+        env.root_folder("");
+        let access = AccessList::new(None, &[AccessRule::parse("deny private_notes").unwrap()]);
+        let find = |query: SearchQuery| {
+            let mut walk = SearchWalk::new(&Folder::default(), Search::new(&query).unwrap(), access.clone(), false).unwrap();
+            let mut names: Vec<String> = walk.next_batch(usize::MAX).into_iter().map(|result| format!("{}/{}", result.folder.relative_path(), result.name)).collect();
+            names.sort();
+            names
+        };
+        let query = |q: &str, mode: MatchMode| SearchQuery { q: Some(q.to_owned()), mode, ..Default::default() };
+
+        assert_eq!(find(query("img", MatchMode::Substring)), ["photos/2022/img_2.JPG", "photos/IMG_1.jpg"]);
+        assert_eq!(find(query("img_?.jpg", MatchMode::Glob)), ["photos/2022/img_2.JPG", "photos/IMG_1.jpg"]);
+        assert_eq!(find(query("^img_\\d\\.jpg$", MatchMode::Regex)), Vec::<String>::new());
+        assert_eq!(find(query("(?i)^img_\\d\\.jpg$", MatchMode::Regex)), ["photos/2022/img_2.JPG", "photos/IMG_1.jpg"]);
+        assert_eq!(find(SearchQuery { kind: EntryKind::Folder, ..query("", MatchMode::Substring) }), ["/photos", "/scratch", "photos/2022"]);
+        assert_eq!(find(SearchQuery { kind: EntryKind::Image, min_size: Some("1k".to_owned()), ..query("", MatchMode::Substring) }), ["photos/2022/img_2.JPG"]);
+        assert_eq!(find(SearchQuery { before: Some("2000-01-01".to_owned()), ..query("", MatchMode::Substring) }), Vec::<String>::new());
+        assert!(matches!(Search::new(&query("(", MatchMode::Regex)), Err(AppErrorKind::SearchInvalid(_))));
+        assert!(matches!(Search::new(&SearchQuery { max_size: Some("lots".to_owned()), ..Default::default() }), Err(AppErrorKind::SearchInvalid(_))));
+        assert_eq!(search::parse_size("1.5 MB").unwrap(), 1536 * 1024);
+    }
+
+    #[actix_web::test]
+    async fn test_file_index() {
+        let mut env = TestEnv::new("index");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("photos/.cache")).unwrap();
+        std::fs::create_dir_all(base.join("scratch")).unwrap();
+        for (file, contents) in [("photos/a.jpg", "same"), ("photos/b.jpg", "same"), ("photos/c.jpg", "diff"),
+                ("photos/.cache/d.jpg", "same"), ("scratch/e.jpg", "same")] {
+            std::fs::write(base.join(file), contents).unwrap();
+        }
+        std::fs::write(base.join("scratch/.ppfsignore"), "*.jpg\n").unwrap();
+        env.root_folder("");
+        env.set_var("FS_INDEX_FILE", "");
+        let file_index = Arc::new(Mutex::new(FileIndex::new()));
+        index::start(file_index.clone(), Arc::new(Mutex::new(LiveUpdates::new()))).unwrap();
+        let wait_for = |done: &dyn Fn(&FileIndex) -> bool| {
+            for _ in 0..100 {
+                if done(&file_index.lock().unwrap()) {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            panic!("the index did not catch up");
+        };
+        wait_for(&|index| index.is_ready());
+        let access = AccessList::new(None, &[]);
+        let photos = Folder::from_relative_path("photos").unwrap();
+        {
+            let index = file_index.lock().unwrap();
+            assert_eq!(index.usage(&Folder::default()), Usage { size: 16, files: 4, folders: 3 });
+            let search = Search::new(&SearchQuery { q: Some(".jpg".to_owned()), ..Default::default() }).unwrap();
+            let (found, truncated) = index.search(&Folder::default(), &search, &access, false, 10);
+            let mut names: Vec<&str> = found.iter().map(|result| result.name.as_str()).collect();
+            names.sort();
+            assert_eq!((names, truncated), (vec!["a.jpg", "b.jpg", "c.jpg"], false));
+            let same_size: Vec<usize> = index.same_size(&photos, &access, false).iter().map(Vec::len).collect();
+            assert_eq!(same_size, [3]);
+        }
+
+        std::fs::write(base.join("photos/new.jpg"), "newest file").unwrap();
+        wait_for(&|index| index.breakdown(&photos).contains_key("new.jpg"));
+        assert!(file_index.lock().unwrap().recent(&photos, &access, false, 10).iter().any(|result| result.name == "new.jpg"));
+        std::fs::remove_file(base.join("photos/a.jpg")).unwrap();
+        wait_for(&|index| !index.breakdown(&photos).contains_key("a.jpg"));
+        assert_eq!(index::hash_file(&base.join("photos/b.jpg")).unwrap(), index::hash_file(&base.join("scratch/e.jpg")).unwrap());
+
+        drop(file_index);
+    }
+
+    #[actix_web::test]
+    async fn test_live_updates() {
+        let mut env = TestEnv::new("live");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("photos")).unwrap();
+        std::fs::write(base.join("photos/a.jpg"), "alpha").unwrap();
+        env.root_folder("");
+        env.set_var("FS_INDEX_FILE", "");
+        let file_index = Arc::new(Mutex::new(FileIndex::new()));
+        let live = Arc::new(Mutex::new(LiveUpdates::new()));
+        index::start(file_index.clone(), live.clone()).unwrap();
+        for _ in 0..100 {
+            if file_index.lock().unwrap().is_ready() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        let queue = Mutex::new(JobQueue::new());
+        let photos = Folder::from_relative_path("photos").unwrap();
+        let user = User { username: "ann".to_owned(), authority: UserAuthority::User, ..Default::default() };
+        let mut feed = FolderFeed::new(photos.clone(), user, None, &live, &queue);
+        assert!(feed.poll(&live, &queue).starts_with(": connected"));
+        // every entry change and notice the feed sends, until it has sent count of them
+        let mut wait_for = |count: usize| {
+            let mut seen = Vec::new();
+            for _ in 0..100 {
+                for block in feed.poll(&live, &queue).split("\n\n") {
+                    let data = block.lines().find_map(|line| line.strip_prefix("data: "));
+                    let data: serde_json::Value = match (block.lines().next(), data) {
+                        (Some("event: entry" | "event: notice"), Some(data)) => serde_json::from_str(data).unwrap(),
+                        _ => continue
+                    };
+                    seen.push(match data["kind"].as_str() {
+                        Some(kind) => format!("{} {} {}", kind, data["name"].as_str().unwrap(), data["new_name"].as_str().unwrap_or("-")),
+                        None => format!("{} {}", data["level"].as_str().unwrap(), data["message"].as_str().unwrap())
+                    });
+                }
+                if seen.len() >= count {
+                    seen.sort();
+                    return seen;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            panic!("the feed did not catch up, got {:?}", seen);
+        };
+
+        std::fs::write(base.join("photos/b.jpg"), "bravo").unwrap();
+        std::fs::write(base.join("photos/.hidden"), "hidden").unwrap();
+        std::fs::write(base.join("elsewhere.jpg"), "elsewhere").unwrap();
+        std::fs::rename(base.join("photos/a.jpg"), base.join("photos/renamed.jpg")).unwrap();
+        assert_eq!(wait_for(2), ["added b.jpg -", "renamed a.jpg renamed.jpg"]);
+        std::fs::remove_file(base.join("photos/b.jpg")).unwrap();
+        assert_eq!(wait_for(1), ["removed b.jpg -"]);
+
+        let id = queue.lock().unwrap().submit(Job::new(Operation::Zip, "ann", "photos".to_owned(), None, photos.clone()), |_| Ok(Vec::new()));
+        queue.lock().unwrap().cancel(id).unwrap();
+        assert_eq!(wait_for(1), ["info zip of /photos was cancelled"]);
+
+        // a recheck reloads the user, whose access now denies the folder
+        let mut feed = FolderFeed::new(photos, User { username: "ann".to_owned(), ..Default::default() }, None, &live, &queue);
+        assert_eq!((0..20).filter(|_| feed.recheck_due()).count(), 2);
+        feed.set_user(User { username: "ann".to_owned(), acl: vec![AccessRule::parse("deny photos").unwrap()], ..Default::default() });
+        std::fs::write(base.join("photos/c.jpg"), "charlie").unwrap();
+        live.lock().unwrap().publish(ChangeKind::Added, "photos/c.jpg", None);
+        assert!(!feed.poll(&live, &queue).contains("c.jpg"));
+
+        drop(file_index);
+    }
+
+    #[actix_web::test]
+    async fn test_content_search() {
+        let matcher = ContentMatcher::new("error", false).unwrap();
+        let hits = matcher.hits("start\nERROR one\nok\nok\nok\nok\nerror two\nend", 1).unwrap();
+        let numbers: Vec<Vec<(usize, bool)>> = hits.groups.iter()
+            .map(|group| group.iter().map(|line| (line.number, line.matched)).collect())
+            .collect();
+        assert_eq!(numbers, [vec![(1, false), (2, true), (3, false)], vec![(6, false), (7, true), (8, false)]]);
+        assert_eq!(matcher.hits("error\nerror", 1).unwrap().groups, [vec![
+            HitLine { number: 1, text: "error".to_owned(), matched: true },
+            HitLine { number: 2, text: "error".to_owned(), matched: true }
+        ]]);
+        assert!(matcher.hits("all good", 2).is_none());
+        assert!(matches!(ContentMatcher::new("(", true), Err(AppErrorKind::SearchInvalid(_))));
+
+        let mut env = TestEnv::new("content");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("logs")).unwrap();
+        std::fs::write(base.join("notes.md"), "# Notes\nTODO: water plants\n").unwrap();
+        std::fs::write(base.join("logs/app.log"), "boot\ntodo later\n").unwrap();
+        std::fs::write(base.join("logs/core.bin"), b"TODO\0binary").unwrap();
+        std::fs::write(base.join("big.txt"), format!("TODO\n{}", "x".repeat(100))).unwrap();
+        env.root_folder("");
+        env.set_var("FS_TEXT_MAX_SIZE", "50");
+        let find = |query: SearchQuery| {
+            let mut walk = SearchWalk::new(&Folder::default(), Search::new(&query).unwrap(), AccessList::new(None, &[]), false).unwrap();
+            let mut found: Vec<(String, usize)> = walk.next_batch(usize::MAX).into_iter()
+                .map(|result| (result.name, result.hits.unwrap().groups[0][0].number))
+                .collect();
+            found.sort();
+            found
+        };
+
+        assert_eq!(find(SearchQuery { content: Some("todo".to_owned()), ..Default::default() }), [("app.log".to_owned(), 2), ("notes.md".to_owned(), 2)]);
+        assert_eq!(find(SearchQuery { content: Some("^TODO".to_owned()), content_regex: true, ..Default::default() }), [("notes.md".to_owned(), 2)]);
+        assert_eq!(find(SearchQuery { q: Some(".log".to_owned()), content: Some("todo".to_owned()), context: Some(1), ..Default::default() }), [("app.log".to_owned(), 1)]);
+    }
+
+    #[actix_web::test]
+    async fn test_trash() {
+        let mut env = TestEnv::new("trash");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("files/docs")).unwrap();
+        std::fs::write(base.join("files/docs/a.txt"), "alpha").unwrap();
+        std::fs::write(base.join("files/b.txt"), "bravo").unwrap();
+        env.root_folder("files");
+        env.set_var("FS_TRASH_FOLDER", base.join("trash"));
+        let root = Folder::default();
+
+        let docs = root.join("docs").unwrap();
+        let trashed_docs = docs.remove("alice").await.unwrap();
+        let trashed_b = root.remove_file("b.txt", "bob").await.unwrap();
+        assert!(!base.join("files/docs").exists() && !base.join("files/b.txt").exists());
+        assert_eq!((trashed_docs.folder.as_str(), trashed_docs.size, trashed_docs.is_folder), ("", 5, true));
+        assert_eq!(trash::list().len(), 2);
+        assert_eq!(trash::get(&trashed_b.id).unwrap(), trashed_b);
+        assert!(matches!(trash::get("../../etc/passwd"), Err(AppErrorKind::TrashItemNotFound)));
+        assert!(matches!(root.remove("alice").await, Err(AppErrorKind::CannotDeleteRoot)));
+
+        trash::restore(&trashed_docs).await.unwrap();
+        assert_eq!(std::fs::read_to_string(base.join("files/docs/a.txt")).unwrap(), "alpha");
+        std::fs::write(base.join("files/b.txt"), "new").unwrap();
+        assert!(matches!(trash::restore(&trashed_b).await, Err(AppErrorKind::EntityAlreadyExists(_))));
+        trash::purge(&trashed_b).await.unwrap();
+        assert!(trash::list().is_empty());
+
+        env.set_var("FS_TRASH_MAX_SIZE", "4");
+        assert!(matches!(docs.remove("alice").await, Err(AppErrorKind::TooLargeForTrash)));
+        assert!(base.join("files/docs/a.txt").exists());
+        root.remove_file("b.txt", "bob").await.unwrap();
+        std::fs::write(base.join("files/c.txt"), "sea").unwrap();
+        let trashed_c = root.remove_file("c.txt", "bob").await.unwrap();
+        assert_eq!(trash::list(), [trashed_c]);
+    }
+
+    #[actix_web::test]
+    async fn test_conflict_policy() {
+        let mut env = TestEnv::new("conflict");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("files/docs")).unwrap();
+        std::fs::write(base.join("files/a.txt"), "moved").unwrap();
+        std::fs::write(base.join("files/docs/a.txt"), "kept").unwrap();
+        env.root_folder("files");
+        env.set_var("FS_TRASH_FOLDER", base.join("trash"));
+        let root = Folder::default();
+        let docs = root.join("docs").unwrap();
+
+        assert_eq!(ConflictPolicy::parse("keep_newer"), Some(ConflictPolicy::KeepNewer));
+        assert!(matches!(root.move_entity("a.txt", &docs, ConflictPolicy::Fail, "alice"), Err(AppErrorKind::EntityAlreadyExists(_))));
+        assert_eq!(root.move_entity("a.txt", &docs, ConflictPolicy::Skip, "alice").unwrap(), Outcome::Skipped);
+        // the file being moved is older than the one already there
+        std::fs::File::options().write(true).open(base.join("files/a.txt")).unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(60)).unwrap();
+        assert_eq!(root.move_entity("a.txt", &docs, ConflictPolicy::KeepNewer, "alice").unwrap(), Outcome::Skipped);
+        assert_eq!(std::fs::read_to_string(base.join("files/docs/a.txt")).unwrap(), "kept");
+        assert_eq!(root.move_entity("a.txt", &docs, ConflictPolicy::Rename, "alice").unwrap(), Outcome::Renamed(String::from("a(2).txt")));
+        assert_eq!(std::fs::read_to_string(base.join("files/docs/a(2).txt")).unwrap(), "moved");
+
+        std::fs::write(base.join("files/a.txt"), "newest").unwrap();
+        assert_eq!(root.move_entity("a.txt", &docs, ConflictPolicy::Overwrite, "alice").unwrap(), Outcome::Overwritten);
+        assert_eq!(std::fs::read_to_string(base.join("files/docs/a.txt")).unwrap(), "newest");
+        assert_eq!(trash::list()[0].name, "a.txt");
+
+        let files = Folder::canonical_root().unwrap();
+        zip::create_zip_from_folder(files.to_str().unwrap(), "docs", &files, false, &Progress::default()).unwrap();
+        let archive = files.join("docs.zip");
+        let unzip = |policy| zip::extract_zip(archive.to_str().unwrap(), &docs, &files, policy, "alice", &Progress::default()).unwrap();
+        std::fs::write(base.join("files/docs/a.txt"), "changed").unwrap();
+        let outcomes = unzip(ConflictPolicy::Skip);
+        assert!(outcomes.contains(&ItemOutcome::new("a.txt", Outcome::Skipped)));
+        assert_eq!(std::fs::read_to_string(base.join("files/docs/a.txt")).unwrap(), "changed");
+        assert!(matches!(unzip(ConflictPolicy::Fail)[0].outcome, Outcome::Failed(_)));
+        let outcomes = unzip(ConflictPolicy::Rename);
+        assert!(outcomes.contains(&ItemOutcome::new("a.txt", Outcome::Renamed(String::from("a(3).txt")))));
+    }
+
+    #[actix_web::test]
+    async fn test_copy_into() {
+        let mut env = TestEnv::new("copy");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("files/docs/notes")).unwrap();
+        std::fs::create_dir_all(base.join("files/photos/2022")).unwrap();
+        std::fs::write(base.join("files/docs/notes/a.txt"), "alpha").unwrap();
+        env.root_folder("files");
+        let root = Folder::default();
+        let docs = root.join("docs").unwrap();
+        let deep = Folder::new("root+photos+2022").unwrap();
+        let progress = Progress::default();
+
+        assert_eq!(root.copy_into("docs", &deep, ConflictPolicy::Fail, "alice", &progress).unwrap(), Outcome::Done);
+        assert_eq!(std::fs::read_to_string(base.join("files/photos/2022/docs/notes/a.txt")).unwrap(), "alpha");
+        assert!(matches!(root.copy_into("docs", &deep, ConflictPolicy::Fail, "alice", &progress), Err(AppErrorKind::EntityAlreadyExists(_))));
+        assert!(matches!(root.copy_into("docs", &docs.join("notes").unwrap(), ConflictPolicy::Rename, "alice", &progress), Err(AppErrorKind::CannotCopyFolderIntoItself)));
+        // copying into the folder it is in gives the copy a new name whatever the policy
+        assert_eq!(root.copy_into("docs", &root, ConflictPolicy::Fail, "alice", &progress).unwrap(), Outcome::Renamed(String::from("docs(2)")));
+        assert!(deep.is_inside(&root.join("photos").unwrap()) && !docs.is_inside(&deep));
+
+        let notes = docs.join("notes").unwrap();
+        assert_eq!(notes.move_entity("a.txt", &deep, ConflictPolicy::Fail, "alice").unwrap(), Outcome::Done);
+        assert!(base.join("files/photos/2022/a.txt").exists() && !base.join("files/docs/notes/a.txt").exists());
+    }
+
+    #[actix_web::test]
+    async fn test_bulk_rename_and_zip() {
+        let mut env = TestEnv::new("bulk");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("files/album")).unwrap();
+        for (name, content) in [("a.jpg", "a"), ("b.jpg", "b"), ("img_10.jpg", "10"), ("img_2.jpg", "2"), ("taken_1.jpg", "t")] {
+            std::fs::write(base.join("files").join(name), content).unwrap();
+        }
+        env.root_folder("files");
+        let root = Folder::default();
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+
+        // swapping two names goes through temporary names
+        let plans = [("a.jpg", "b.jpg"), ("b.jpg", "a.jpg")].map(|(from, to)| PlannedRename { from: from.to_owned(), to: to.to_owned(), problem: None });
+        let results = rename::apply(&root, &plans).unwrap();
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert_eq!(std::fs::read_to_string(base.join("files/a.jpg")).unwrap(), "b");
+        assert!(!std::fs::read_dir(base.join("files")).unwrap().any(|entry| entry.unwrap().file_name().to_string_lossy().starts_with(".ppfs")));
+        let prefix = RenamePattern { find: String::from("^(a|b)"), replace: String::from("x$1"), regex: true, ..Default::default() };
+        let plans = prefix.plan(&root, &names(&["b.jpg", "a.jpg"])).unwrap();
+        assert_eq!(plans.iter().map(|plan| plan.to.as_str()).collect::<Vec<&str>>(), ["xa.jpg", "xb.jpg"]);
+
+        // numbered in name order, with a name taken by something not selected left alone
+        let numbering = RenamePattern { find: String::from("img"), replace: String::from("taken"), numbering: true, digits: Some(1), ..Default::default() };
+        let plans = numbering.plan(&root, &names(&["img_10.jpg", "img_2.jpg"])).unwrap();
+        assert_eq!((plans[0].from.as_str(), plans[0].to.as_str()), ("img_2.jpg", "taken_2_1.jpg"));
+        assert!(plans.iter().all(|plan| plan.problem.is_none()));
+        let clash = RenamePattern { find: String::from("img_2"), replace: String::from("taken_1"), ..Default::default() };
+        assert!(clash.plan(&root, &names(&["img_2.jpg"])).unwrap()[0].problem.is_some());
+        let same = RenamePattern { find: String::from("img_\\d+"), replace: String::from("one"), regex: true, ..Default::default() };
+        assert!(same.plan(&root, &names(&["img_10.jpg", "img_2.jpg"])).unwrap().iter().all(|plan| plan.problem.is_some()));
+        let invalid = RenamePattern { find: String::from("("), regex: true, ..Default::default() };
+        assert!(matches!(invalid.plan(&root, &names(&["a.jpg"])), Err(AppErrorKind::RenamePatternInvalid(_))));
+
+        let files = base.join("files").canonicalize().unwrap();
+        let bytes = zip::zip_entries(&files, &names(&["album", "img_2.jpg"]), &files, false, &Progress::default(), std::io::Cursor::new(Vec::new())).unwrap().into_inner();
+        let archive = ::zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+        let mut entries: Vec<&str> = archive.file_names().collect();
+        entries.sort();
+        assert_eq!(entries, ["album/", "img_2.jpg"]);
+    }
+
+    #[actix_web::test]
+    async fn test_job_queue() {
+        let mut env = TestEnv::new("jobs");
+        let base = env.base.clone();
+        std::fs::create_dir_all(base.join("files/docs/notes")).unwrap();
+        std::fs::create_dir_all(base.join("files/copies")).unwrap();
+        std::fs::write(base.join("files/docs/a.txt"), "alpha").unwrap();
+        std::fs::write(base.join("files/docs/notes/b.txt"), "beta").unwrap();
+        std::fs::write(base.join("files/big.bin"), vec![7u8; 4 * 1024 * 1024]).unwrap();
+        env.root_folder("files");
+        let root = Folder::default();
+        let copies = root.join("copies").unwrap();
+        let queue = Arc::new(Mutex::new(JobQueue::new()));
+        jobs::start(queue.clone()).unwrap();
+        let wait = |id: u64, until: fn(JobState) -> bool| {
+            for _ in 0..500 {
+                let state = queue.lock().unwrap().get(id).unwrap().state;
+                if until(state) {
+                    return state;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("job {} did not get there in time", id);
+        };
+
+        let (source, target) = (root.clone(), copies.clone());
+        let docs = base.join("files/docs");
+        let id = queue.lock().unwrap().submit(Job::new(Operation::Copy, "alice", String::from("docs"), Some(String::from("copies")), copies.clone()), move |progress| {
+            jobs::measure(&docs, progress);
+            let outcome = source.copy_into("docs", &target, ConflictPolicy::Fail, "alice", progress)?;
+            Ok(vec![ItemOutcome::new("docs", outcome)])
+        });
+        assert_eq!(wait(id, JobState::is_finished), JobState::Done);
+        assert_eq!(std::fs::read_to_string(base.join("files/copies/docs/notes/b.txt")).unwrap(), "beta");
+        let progress = queue.lock().unwrap().get(id).unwrap().progress.to_json();
+        assert_eq!((progress["files_done"].as_u64(), progress["percent"].as_u64()), (Some(2), Some(100)));
+
+        env.set_var("FS_AUDIT_FILE", base.join("audit.log"));
+        let auditor = Auditor { user: String::from("alice"), ip: String::from("127.0.0.1") };
+        let task = jobs::move_task(auditor, root.clone(), vec![String::from("docs"), String::from("missing")], copies.clone(), ConflictPolicy::Rename);
+        let id = queue.lock().unwrap().submit(Job::new(Operation::Move, "alice", String::new(), Some(String::from("copies")), copies.clone()), task);
+        assert_eq!(wait(id, JobState::is_finished), JobState::Done);
+        assert_eq!(std::fs::read_to_string(base.join("files/copies/docs(2)/a.txt")).unwrap(), "alpha");
+        assert!(!base.join("files/docs").exists());
+        let outcomes: Vec<Outcome> = queue.lock().unwrap().get(id).unwrap().outcomes.iter().map(|item| item.outcome.clone()).collect();
+        assert!(matches!(outcomes.as_slice(), [Outcome::Renamed(name), Outcome::Failed(_)] if name == "docs(2)"));
+
+        // a running job stops at the next piece and leaves nothing half copied
+        let (source, target) = (root.clone(), copies.clone());
+        let id = queue.lock().unwrap().submit(Job::new(Operation::Copy, "alice", String::from("big.bin"), None, copies.clone()), move |progress| {
+            while !progress.is_cancelled() {
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
+            source.copy_into("big.bin", &target, ConflictPolicy::Fail, "alice", progress).map(|_| Vec::new())
+        });
+        wait(id, |state| state == JobState::Running);
+        queue.lock().unwrap().cancel(id).unwrap();
+        assert_eq!(wait(id, JobState::is_finished), JobState::Cancelled);
+        assert!(!base.join("files/copies/big.bin").exists());
+        assert!(matches!(queue.lock().unwrap().cancel(id), Err(AppErrorKind::JobFinished)));
+
+        let mut idle = JobQueue::new();
+        let id = idle.submit(Job::new(Operation::Zip, "alice", String::from("docs"), None, root.clone()), |_| Ok(Vec::new()));
+        idle.cancel(id).unwrap();
+        assert_eq!(idle.get(id).unwrap().state, JobState::Cancelled);
+    }
+}
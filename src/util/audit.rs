@@ -0,0 +1,195 @@
+use std::{env, fs::{self, OpenOptions}, io::{BufRead, BufReader, Write}, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{models::{conflict::Outcome, folder::Folder}, util::{error::AppErrorKind, time_format}};
+
+const AUDIT_FILE_ENV: &str = "FS_AUDIT_FILE";
+const DEFAULT_AUDIT_FILE: &str = "private/audit.log";
+
+/// Keeps lines from two requests from interleaving
+static AUDIT_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Every kind of change to the files and folders under the root folder
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Upload,
+    CreateFolder,
+    Rename,
+    Copy,
+    Move,
+    Unzip,
+    Zip,
+    Delete,
+    Restore,
+    Purge,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 10] = [
+        Operation::Upload, Operation::CreateFolder, Operation::Rename, Operation::Copy,
+        Operation::Move, Operation::Unzip, Operation::Zip, Operation::Delete,
+        Operation::Restore, Operation::Purge
+    ];
+}
+
+/// One line of the audit log. Paths are relative to the root folder
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub time: i64,
+    pub user: String,
+    pub ip: String,
+    pub operation: Operation,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// "ok" or the error message
+    pub result: String,
+}
+
+impl AuditEntry {
+    pub fn succeeded(&self) -> bool {
+        self.result == "ok"
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "time": OffsetDateTime::from_unix_timestamp(self.time)
+                .map(|t| time_format::format_time(t, None))
+                .unwrap_or_else(|_| String::from("Unknown")),
+            "user": self.user,
+            "ip": self.ip,
+            "operation": self.operation,
+            "path": self.path,
+            "target": self.target,
+            "result": self.result,
+            "succeeded": self.succeeded()
+        })
+    }
+}
+
+/// Narrows down the entries shown on the audit page. Empty fields match everything
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub operation: Option<String>,
+    pub path: Option<String>,
+    pub result: Option<String>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let filled = |field: &Option<String>| field.as_deref().map(str::trim).filter(|value| !value.is_empty()).map(str::to_owned);
+        filled(&self.user).is_none_or(|user| entry.user == user)
+            && filled(&self.operation).is_none_or(|operation| {
+                serde_json::to_value(entry.operation).ok().and_then(|value| value.as_str().map(|op| op == operation)).unwrap_or(false)
+            })
+            && filled(&self.path).is_none_or(|path| {
+                entry.path.contains(&path) || entry.target.as_ref().is_some_and(|target| target.contains(&path))
+            })
+            && filled(&self.result).is_none_or(|result| match result.as_str() {
+                "ok" => entry.succeeded(),
+                "failed" => !entry.succeeded(),
+                _ => true
+            })
+    }
+}
+
+/// Returns the location of the audit log. Defaults to private/audit.log
+/// but can be changed with the FS_AUDIT_FILE environment variable
+pub fn audit_file() -> String {
+    env::var(AUDIT_FILE_ENV).unwrap_or_else(|_| DEFAULT_AUDIT_FILE.to_owned())
+}
+
+/// Appends an entry to the audit log. The log is only ever appended to, a failure
+/// to write it is logged but does not fail the operation that was already done
+pub fn record(entry: &AuditEntry) {
+    let line = match serde_json::to_string(entry) {
+        Ok(line) => line,
+        Err(err) => {
+            log::error!("could not serialize audit entry: {}", err);
+            return;
+        }
+    };
+    let _lock = AUDIT_FILE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let result = OpenOptions::new().create(true).append(true).open(audit_file())
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(err) = result {
+        log::error!("could not write audit log {}: {}", audit_file(), err);
+    }
+}
+
+/// Who is making changes and from where. Kept by background jobs so what they change
+/// is recorded for the user who started them
+#[derive(Debug, Clone)]
+pub struct Auditor {
+    pub user: String,
+    pub ip: String,
+}
+
+impl Auditor {
+    /// Writes a filesystem change to the audit log and passes its result through
+    pub fn audit<T, E: std::fmt::Display>(&self, operation: Operation, path: String, target: Option<String>, result: Result<T, E>) -> Result<T, E> {
+        record(&AuditEntry {
+            time: now(),
+            user: self.user.clone(),
+            ip: self.ip.clone(),
+            operation,
+            path,
+            target,
+            result: match &result {
+                Ok(_) => String::from("ok"),
+                Err(err) => err.to_string()
+            }
+        });
+        result
+    }
+
+    /// Audits moving the item called name into target_folder, recording where it ended up.
+    /// Nothing is recorded when it was skipped, since nothing changed
+    pub fn audit_outcome(&self, operation: Operation, path: String, target_folder: &Folder, name: &str, result: Result<Outcome, AppErrorKind>) -> Result<Outcome, AppErrorKind> {
+        if matches!(result, Ok(Outcome::Skipped)) {
+            return result;
+        }
+        let target = match &result {
+            Ok(outcome) => outcome.written_name(name),
+            Err(_) => Some(name)
+        }.map(|name| self::path(target_folder, name));
+        self.audit(operation, path, target, result)
+    }
+}
+
+/// Returns the entries matching the filter, newest first, skipping the first skip of them.
+/// Also returns if there are more after the ones returned
+pub fn read(filter: &AuditFilter, skip: usize, limit: usize) -> Result<(Vec<AuditEntry>, bool), AppErrorKind> {
+    let file = match fs::File::open(audit_file()) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), false)),
+        Err(err) => return Err(AppErrorKind::Io(err))
+    };
+    let mut entries: Vec<AuditEntry> = BufReader::new(file).lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<AuditEntry>(&line).ok())
+        .filter(|entry| filter.matches(entry))
+        .collect();
+    entries.reverse();
+    let more = entries.len() > skip + limit;
+    Ok((entries.into_iter().skip(skip).take(limit).collect(), more))
+}
+
+/// Path of an entry in a folder, relative to the root folder
+pub fn path(folder: &Folder, name: &str) -> String {
+    let folder_path = folder.relative_path();
+    if folder_path.is_empty() {
+        name.to_owned()
+    }
+    else {
+        format!("{}/{}", folder_path, name)
+    }
+}
+
+pub fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,196 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use actix_session::Session;
+use actix_web::{body::MessageBody, dev::{Payload, ServiceResponse}, http::header::{self, ContentType}, FromRequest, HttpRequest, HttpResponse};
+
+use crate::{models::{conflict::Outcome, folder::Folder, user::User}, util::{audit::{Auditor, Operation}, error::AppErrorKind, sessions::{self, SessionRegistry}}};
+
+/// Operations a handler can ask permission for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Read,
+    Download,
+    Write,
+    Delete,
+    Timelapse,
+    ManageUsers,
+}
+
+/// Extracts who is making the request so handlers can check what they are allowed to do.
+/// Requests can authenticate with the login session or an "Authorization: Bearer" API token
+pub struct Auth {
+    session: Session,
+    bearer: Option<String>,
+    ip: String
+}
+
+/// Returns the API token of a request that uses bearer authentication
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+}
+
+impl FromRequest for Auth {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let session = Session::from_request(req, payload);
+        let bearer = bearer_token(req);
+        let ip = req.peer_addr().map_or_else(|| String::from("unknown"), |addr| addr.ip().to_string());
+        Box::pin(async move {
+            Ok(Self { session: session.await?, bearer, ip })
+        })
+    }
+}
+
+impl Auth {
+    /// Returns the logged in user. The user is reloaded from the users file so
+    /// authority changes and deleted accounts take effect immediately
+    pub fn user(&self) -> Result<User, AppErrorKind> {
+        if let Some(token) = &self.bearer {
+            return User::from_token(token).inspect_err(|_| {
+                log::warn!("request with an invalid API token was rejected");
+            });
+        }
+        let user = User::get(self.session.clone())?;
+        match User::find(&user.username) {
+            Ok(user) => Ok(user),
+            Err(AppErrorKind::UserNotFound) => {
+                let _ = User::remove(self.session.clone());
+                Err(AppErrorKind::Session("user no longer exists".to_owned(), Some(self.session.clone())))
+            },
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Returns the logged in user if their authority allows the permission
+    pub fn require(&self, permission: Permission) -> Result<User, AppErrorKind> {
+        allowed(self.user()?, permission)
+    }
+
+    /// Returns the logged in user if they are allowed the permission on the folder or file
+    pub fn require_in(&self, permission: Permission, folder: &Folder) -> Result<User, AppErrorKind> {
+        allowed_in(self.require(permission)?, permission, folder)
+    }
+
+    /// Like require_in but also checks everything below the folder. Used for
+    /// operations that take a whole folder with them like move, copy and zip
+    pub fn require_all_in(&self, permission: Permission, folder: &Folder) -> Result<User, AppErrorKind> {
+        let user = self.require(permission)?;
+        if !user.access_list().subtree_access(&folder.relative_path()).permits(permission) {
+            log::warn!("user '{}' was denied {:?} in all of '{}'", user.username, permission, folder);
+            return Err(AppErrorKind::PermissionDenied);
+        }
+        Ok(user)
+    }
+
+    /// Who is logged in, for responses like event streams that stay open long after the request.
+    /// The session cannot be used for that, it is emptied once the response starts
+    pub fn login(&self) -> Result<Login, AppErrorKind> {
+        if let Some(token) = &self.bearer {
+            return Ok(Login::Token(token.clone()));
+        }
+        let username = User::get(self.session.clone())?.username;
+        match sessions::current_id(&self.session) {
+            Some(id) => Ok(Login::Session { id, username }),
+            None => Err(AppErrorKind::Session("user must login again".to_owned(), Some(self.session.clone())))
+        }
+    }
+
+    /// Returns the user logged in with a session. Used for account changes
+    /// like passwords and tokens that an API token should not be able to make
+    pub fn session_user(&self) -> Result<User, AppErrorKind> {
+        if self.bearer.is_some() {
+            return Err(AppErrorKind::PermissionDenied);
+        }
+        self.user()
+    }
+
+    pub fn session(&self) -> Session {
+        self.session.clone()
+    }
+
+    /// Who is making the request, for the audit log
+    pub fn auditor(&self) -> Auditor {
+        let user = match &self.bearer {
+            Some(token) => User::from_token(token).map(|user| user.username),
+            None => User::get(self.session.clone()).map(|user| user.username)
+        };
+        Auditor {
+            user: user.unwrap_or_else(|_| String::from("unknown")),
+            ip: self.ip.clone()
+        }
+    }
+
+    /// Writes a filesystem change to the audit log and passes its result through,
+    /// so a handler can wrap the Folder call that makes the change
+    pub fn audit<T, E: std::fmt::Display>(&self, operation: Operation, path: String, target: Option<String>, result: Result<T, E>) -> Result<T, E> {
+        self.auditor().audit(operation, path, target, result)
+    }
+
+    /// Audits moving the item called name into target_folder, see Auditor::audit_outcome
+    pub fn audit_outcome(&self, operation: Operation, path: String, target_folder: &Folder, name: &str, result: Result<Outcome, AppErrorKind>) -> Result<Outcome, AppErrorKind> {
+        self.auditor().audit_outcome(operation, path, target_folder, name, result)
+    }
+}
+
+/// A login kept apart from its request, see Auth::login
+pub enum Login {
+    Session { id: String, username: String },
+    Token(String),
+}
+
+impl Login {
+    /// Like Auth::require_in. A session must also still be active in the registry,
+    /// so logging out or being logged out ends whatever the login kept open
+    pub fn require_in(&self, permission: Permission, folder: &Folder, registry: &Mutex<SessionRegistry>) -> Result<User, AppErrorKind> {
+        let user = match self {
+            Self::Session { id, username } => {
+                if !sessions::lock(registry).is_active(id) {
+                    return Err(AppErrorKind::Session("session has ended".to_owned(), None));
+                }
+                User::find(username)?
+            },
+            Self::Token(token) => User::from_token(token)?
+        };
+        allowed_in(allowed(user, permission)?, permission, folder)
+    }
+}
+
+fn allowed(user: User, permission: Permission) -> Result<User, AppErrorKind> {
+    if !user.allows(permission) {
+        log::warn!("user '{}' was denied {:?}", user.username, permission);
+        return Err(AppErrorKind::PermissionDenied);
+    }
+    if user.needs_second_factor(permission) {
+        log::warn!("user '{}' was denied {:?} without two-factor authentication", user.username, permission);
+        return Err(AppErrorKind::TwoFactorRequired);
+    }
+    Ok(user)
+}
+
+fn allowed_in(user: User, permission: Permission, folder: &Folder) -> Result<User, AppErrorKind> {
+    if !user.access_list().access(&folder.relative_path()).permits(permission) {
+        log::warn!("user '{}' was denied {:?} in '{}'", user.username, permission, folder);
+        return Err(AppErrorKind::PermissionDenied);
+    }
+    Ok(user)
+}
+
+/// Requests using an API token get the error as plain text with a status code
+/// instead of a redirect with a flash message, which a script could not follow
+pub fn api_response<B: MessageBody + 'static>(res: ServiceResponse<B>) -> ServiceResponse {
+    let (status, message) = match res.response().error() {
+        Some(err) => (err.as_response_error().status_code(), err.to_string()),
+        None => return res.map_into_boxed_body()
+    };
+    let response = HttpResponse::build(status)
+        .content_type(ContentType::plaintext())
+        .body(message);
+    res.into_response(response)
+}
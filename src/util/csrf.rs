@@ -0,0 +1,115 @@
+use std::{future::{ready, Future, Ready}, pin::Pin, rc::Rc};
+
+use actix_session::{Session, SessionExt};
+use actix_web::{body::EitherBody, dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform}, http::{header, Method}, web::Bytes, FromRequest};
+use rand::{Rng, distributions::Alphanumeric, rngs::OsRng};
+use serde::Deserialize;
+
+use crate::{models::token::constant_time_eq, util::{auth::bearer_token, error::{AppError, AppErrorKind}}};
+
+const CSRF_SESSION_KEY: &str = "csrf_token";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+const CSRF_TOKEN_LENGTH: usize = 32;
+
+/// The hidden form field, also used as the query parameter
+#[derive(Deserialize)]
+struct CsrfField {
+    csrf_token: Option<String>
+}
+
+/// Returns the session's CSRF token, creating it on first use. Every page that
+/// has a form passes it to its template for the csrf partial
+pub fn token(session: &Session) -> String {
+    if let Ok(Some(token)) = session.get::<String>(CSRF_SESSION_KEY) {
+        return token;
+    }
+    let token: String = OsRng.sample_iter(&Alphanumeric).take(CSRF_TOKEN_LENGTH).map(char::from).collect();
+    if let Err(err) = session.insert(CSRF_SESSION_KEY, &token) {
+        log::error!("could not store csrf token in session: {}", err);
+    }
+    token
+}
+
+/// Rejects state changing requests that do not carry the session's CSRF token.
+/// The token is read from the X-CSRF-Token header, the csrf_token query parameter
+/// (used by multipart upload forms) or the csrf_token field of a urlencoded form.
+/// Requests using an API token are not sent by browsers so they are exempt
+pub struct CsrfProtection;
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) || bearer_token(req.request()).is_some() {
+                return service.call(req).await.map(ServiceResponse::map_into_left_body);
+            }
+            let (req, sent) = sent_token(req).await?;
+            let expected = req.get_session().get::<String>(CSRF_SESSION_KEY).ok().flatten();
+            match (sent, expected) {
+                (Some(sent), Some(expected)) if constant_time_eq(sent.as_bytes(), expected.as_bytes()) => {
+                    service.call(req).await.map(ServiceResponse::map_into_left_body)
+                },
+                _ => {
+                    log::warn!("rejected {} {} with a missing or invalid csrf token", req.method(), req.path());
+                    Ok(req.error_response(AppError::root(AppErrorKind::CsrfTokenInvalid)).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+/// Finds the token sent with the request. Reading a urlencoded body consumes
+/// it so the body is put back for the handler's Form extractor
+async fn sent_token(req: ServiceRequest) -> Result<(ServiceRequest, Option<String>), actix_web::Error> {
+    if let Some(token) = req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok()) {
+        let token = token.to_owned();
+        return Ok((req, Some(token)));
+    }
+    if let Ok(field) = serde_urlencoded::from_str::<CsrfField>(req.query_string()) {
+        if field.csrf_token.is_some() {
+            return Ok((req, field.csrf_token));
+        }
+    }
+    let is_form = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((req, None));
+    }
+    let (http_req, mut payload) = req.into_parts();
+    let body = Bytes::from_request(&http_req, &mut payload).await?;
+    let token = serde_urlencoded::from_bytes::<CsrfField>(&body).ok().and_then(|field| field.csrf_token);
+    let (_, mut restored) = actix_http::h1::Payload::create(true);
+    restored.unread_data(body);
+    Ok((ServiceRequest::from_parts(http_req, Payload::from(restored)), token))
+}
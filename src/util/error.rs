@@ -1,10 +1,10 @@
 use std::{rc::Rc, fmt::{Debug, Formatter, Result}};
 
 use actix_session::Session;
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use actix_web_flash_messages::FlashMessage;
 
-use crate::util::forward::{self, ForwardTo};
+use crate::{models::user::MIN_PASSWORD_LENGTH, util::forward::{self, ForwardTo}};
 
 #[derive(Debug)]
 pub struct AppError {
@@ -15,6 +15,9 @@ pub struct AppError {
 pub enum AppErrorKind {
     FolderPathInvalid,
     FolderPathNotFound,
+    PathEscapesRoot,
+    SymlinkDenied,
+    PathIgnored,
     FileNotFound,
     CannotGetParentOfRoot,
     CannotRenameRoot,
@@ -24,10 +27,44 @@ pub enum AppErrorKind {
     CannotZipRoot,
     CannotMoveAboveRoot,
     CannotMoveFolderIntoItself,
+    CannotCopyFolderIntoItself,
+    AlreadyInFolder,
+    DestinationMissing,
+    SelectionInvalid,
     FailedToReadFile,
     FailedToZipFolder,
     FailedToUnzipFile,
+    FailedToMeasureFolder,
+    FailedToDelete,
+    FailedToRestore,
     InvalidUserCredentials,
+    LoginLocked(i64),
+    UsernameInvalid,
+    UserAlreadyExists,
+    UserNotFound,
+    PasswordTooShort,
+    PasswordsDoNotMatch,
+    PermissionDenied,
+    CsrfTokenInvalid,
+    CannotDeleteSelf,
+    CannotChangeOwnAuthority,
+    UserStore(String),
+    AccessRuleInvalid(String),
+    InvalidApiToken,
+    TwoFactorRequired,
+    TotpCodeInvalid,
+    TotpAlreadyEnabled,
+    TotpNotEnabled,
+    TotpLoginExpired,
+    SessionNotFound,
+    TokenExpiryInvalid,
+    SearchInvalid(String),
+    RenamePatternInvalid(String),
+    TrashItemNotFound,
+    TooLargeForTrash,
+    JobNotFound,
+    JobFinished,
+    EntityAlreadyExists(String),
     Io(std::io::Error),
     Session(String, Option<Session>)
 }
@@ -51,11 +88,48 @@ fn match_error_kind(kind: &AppErrorKind, f: &mut std::fmt::Formatter) -> std::fm
         AppErrorKind::CannotDeleteRoot => write!(f, "cannot delete root folder"),
         AppErrorKind::CannotMoveAboveRoot => write!(f, "cannot move above root"),
         AppErrorKind::CannotMoveFolderIntoItself => write!(f, "cannot move folder into itself"),
+        AppErrorKind::CannotCopyFolderIntoItself => write!(f, "cannot copy folder into itself"),
+        AppErrorKind::AlreadyInFolder => write!(f, "it is already in that folder"),
+        AppErrorKind::DestinationMissing => write!(f, "choose a folder to move to"),
+        AppErrorKind::PathEscapesRoot => write!(f, "path leads outside of the root folder"),
+        AppErrorKind::SymlinkDenied => write!(f, "symbolic links cannot be opened"),
+        AppErrorKind::PathIgnored => write!(f, "path is ignored"),
+        AppErrorKind::SelectionInvalid => write!(f, "selected files and folders are invalid"),
         AppErrorKind::FailedToReadFile => write!(f, "failed to read file"),
         AppErrorKind::FailedToZipFolder => write!(f, "failed to zip folder"),
         AppErrorKind::FailedToUnzipFile => write!(f, "failed to unzip file"),
+        AppErrorKind::FailedToMeasureFolder => write!(f, "failed to measure folder size"),
+        AppErrorKind::FailedToDelete => write!(f, "failed to delete"),
+        AppErrorKind::FailedToRestore => write!(f, "failed to restore from the trash"),
         AppErrorKind::InvalidUserCredentials => write!(f, "failed to login username or password invalid"),
-        AppErrorKind::Io(io_err) => write!(f, "{}", io_err.to_string()),
+        AppErrorKind::LoginLocked(seconds) => write!(f, "too many failed login attempts, try again in {} seconds", seconds),
+        AppErrorKind::UsernameInvalid => write!(f, "username cannot be empty or contain '/', '?', '#', '%' or control characters"),
+        AppErrorKind::UserAlreadyExists => write!(f, "a user with that username already exists"),
+        AppErrorKind::UserNotFound => write!(f, "user could not be found"),
+        AppErrorKind::PasswordTooShort => write!(f, "password must be at least {} characters", MIN_PASSWORD_LENGTH),
+        AppErrorKind::PasswordsDoNotMatch => write!(f, "passwords do not match"),
+        AppErrorKind::PermissionDenied => write!(f, "you do not have permission to do that"),
+        AppErrorKind::CsrfTokenInvalid => write!(f, "form expired or was sent from another site, reload the page and try again"),
+        AppErrorKind::CannotDeleteSelf => write!(f, "cannot delete your own account"),
+        AppErrorKind::CannotChangeOwnAuthority => write!(f, "cannot change your own authority"),
+        AppErrorKind::AccessRuleInvalid(rule) => write!(f, "access rule '{}' must look like 'read photos/2022' using deny, read or write", rule),
+        AppErrorKind::InvalidApiToken => write!(f, "API token is invalid, expired or revoked"),
+        AppErrorKind::TwoFactorRequired => write!(f, "set up two-factor authentication on your user page to do that"),
+        AppErrorKind::TotpCodeInvalid => write!(f, "two-factor code is invalid or was already used"),
+        AppErrorKind::TotpAlreadyEnabled => write!(f, "two-factor authentication is already enabled"),
+        AppErrorKind::TotpNotEnabled => write!(f, "two-factor authentication is not enabled"),
+        AppErrorKind::TotpLoginExpired => write!(f, "two-factor login timed out, login again"),
+        AppErrorKind::SessionNotFound => write!(f, "session could not be found, it may have already ended"),
+        AppErrorKind::TokenExpiryInvalid => write!(f, "token expiry must be a whole number of days"),
+        AppErrorKind::SearchInvalid(reason) => write!(f, "search is invalid: {}", reason),
+        AppErrorKind::RenamePatternInvalid(reason) => write!(f, "rename pattern is invalid: {}", reason),
+        AppErrorKind::TrashItemNotFound => write!(f, "item is not in the trash, it may have already been restored or purged"),
+        AppErrorKind::TooLargeForTrash => write!(f, "too large to keep in the trash, so it was not deleted"),
+        AppErrorKind::JobNotFound => write!(f, "job could not be found, it may have finished a while ago"),
+        AppErrorKind::JobFinished => write!(f, "job has already finished"),
+        AppErrorKind::EntityAlreadyExists(path) => write!(f, "'{}' already exists", path),
+        AppErrorKind::UserStore(store_err) => write!(f, "user store error: {}", store_err),
+        AppErrorKind::Io(io_err) => write!(f, "{}", io_err),
         AppErrorKind::Session(session_err,_) => write!(f, "{}", session_err),
     }
 }
@@ -94,9 +168,36 @@ impl AppError {
     pub fn login(kind: AppErrorKind) -> Self {
         Self::new(kind, ForwardTo::Login)
     }
+
+    /// For pages that forward back to themselves on error. A permission error
+    /// would redirect forever so it forwards to root instead
+    pub fn page(kind: AppErrorKind, forward: ForwardTo) -> Self {
+        match kind {
+            AppErrorKind::PermissionDenied => Self::root(kind),
+            AppErrorKind::TwoFactorRequired => Self::new(kind, ForwardTo::User),
+            _ => Self::new(kind, forward)
+        }
+    }
 }
 
 impl ResponseError for AppError {
+    /// Only used for API token requests, pages always get a redirect
+    fn status_code(&self) -> StatusCode {
+        match self.kind {
+            AppErrorKind::Session(..) | AppErrorKind::InvalidUserCredentials | AppErrorKind::InvalidApiToken => StatusCode::UNAUTHORIZED,
+            AppErrorKind::PermissionDenied | AppErrorKind::TwoFactorRequired | AppErrorKind::CsrfTokenInvalid
+                | AppErrorKind::PathEscapesRoot | AppErrorKind::SymlinkDenied => StatusCode::FORBIDDEN,
+            AppErrorKind::LoginLocked(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppErrorKind::FolderPathNotFound | AppErrorKind::FileNotFound | AppErrorKind::PathIgnored | AppErrorKind::UserNotFound
+                | AppErrorKind::SessionNotFound | AppErrorKind::TrashItemNotFound | AppErrorKind::JobNotFound => StatusCode::NOT_FOUND,
+            AppErrorKind::EntityAlreadyExists(_) | AppErrorKind::JobFinished => StatusCode::CONFLICT,
+            AppErrorKind::Io(_) | AppErrorKind::UserStore(_) | AppErrorKind::FailedToReadFile
+                | AppErrorKind::FailedToZipFolder | AppErrorKind::FailedToUnzipFile | AppErrorKind::FailedToMeasureFolder
+                | AppErrorKind::FailedToDelete | AppErrorKind::FailedToRestore => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST
+        }
+    }
+
     fn error_response(&self) -> HttpResponse {
         log::debug!("{}", self);
         FlashMessage::error(self.to_string()).send();
@@ -2,17 +2,25 @@ use std::{rc::Rc, fmt::{Formatter, Debug, Result}};
 use actix_session::Session;
 use actix_web::{HttpResponse, http};
 
-use crate::models::folder::Folder;
+use crate::models::folder::{url_name, Folder};
 
 #[derive(Clone)]
 pub enum ForwardTo {
     Root,
     Login,
+    LoginTotp,
     Timelapse,
+    User,
+    Users,
+    Audit,
+    Index,
+    Trash,
+    Jobs,
     LoginRedirect(Rc<ForwardTo>, Session),
     Folder(Folder),
     FolderDetail(Folder),
-    FileDetail(Folder, String)
+    FileDetail(Folder, String),
+    Search(Folder)
 }
 
 impl Debug for ForwardTo {
@@ -37,11 +45,32 @@ pub fn location(forward: &ForwardTo) -> String {
         ForwardTo::Login => {
             "/login".to_string()
         },
+        ForwardTo::LoginTotp => {
+            "/login/totp".to_string()
+        },
         ForwardTo::Timelapse => {
             "/timelapse".to_string()
         },
+        ForwardTo::User => {
+            "/user".to_string()
+        },
+        ForwardTo::Users => {
+            "/admin/users".to_string()
+        },
+        ForwardTo::Audit => {
+            "/admin/audit".to_string()
+        },
+        ForwardTo::Index => {
+            "/admin/index".to_string()
+        },
+        ForwardTo::Trash => {
+            "/trash".to_string()
+        },
+        ForwardTo::Jobs => {
+            "/jobs".to_string()
+        },
         ForwardTo::LoginRedirect(redirect, session) => {
-            match session.insert("redirect", location(&redirect)) {
+            match session.insert("redirect", location(redirect)) {
                 Ok(()) => (),
                 Err(err) => {
                     log::error!("ForwardTo::LoginRedirect SessionInsertError: {}", err);
@@ -50,13 +79,16 @@ pub fn location(forward: &ForwardTo) -> String {
             location(&ForwardTo::Login)
         },
         ForwardTo::Folder(folder) => {
-            format!("/fs/{}/files", folder.to_string())
+            format!("/fs/{}/files", folder.url())
         },
         ForwardTo::FolderDetail(folder) => {
-            format!("/fs/{}", folder.to_string())
+            format!("/fs/{}", folder.url())
         },
         ForwardTo::FileDetail(folder, file_name) => {
-            format!("/fs/{}/files/{}", folder.to_string(), file_name)
+            format!("/fs/{}/files/{}", folder.url(), url_name(file_name))
+        },
+        ForwardTo::Search(folder) => {
+            format!("/fs/{}/search", folder.url())
         }
     }
 }
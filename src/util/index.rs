@@ -0,0 +1,792 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, env, fs::{self, File}, io, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard}, thread, time::{Duration, Instant, SystemTime}};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{models::{access::{Access, AccessList}, folder::Folder, ignore::{IgnoreRules, IGNORE_FILE}, search::{Search, SearchResult}, usage::Usage}, util::{audit, live::{ChangeKind, LiveUpdates}, time_format}};
+
+const INDEX_FILE_ENV: &str = "FS_INDEX_FILE";
+const DEFAULT_INDEX_FILE: &str = "private/index.json";
+/// How often the indexer looks for changes and requests
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long changes wait before the index file is rewritten
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Without inotify, how often folders are checked for changes
+#[cfg(not(target_os = "linux"))]
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// What the index knows about one folder or file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub is_folder: bool,
+    /// Size in bytes, 0 for folders
+    pub size: u64,
+    /// Seconds since the unix epoch
+    pub modified: i64,
+    /// SHA-256 of the contents, worked out the first time a file might be a duplicate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct StoredIndex {
+    built: i64,
+    /// Keyed by path relative to the root folder, "" is the root folder itself
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexState {
+    /// Nothing to answer with yet, searches walk the folders instead
+    Loading,
+    Ready,
+}
+
+/// Every folder and file under the root folder that is not ignored, kept current by
+/// a background thread. Symbolic links are left out. Queries only use it once it is
+/// ready and fall back to reading the disk until then
+pub struct FileIndex {
+    entries: BTreeMap<String, IndexEntry>,
+    state: IndexState,
+    /// When the last full scan finished
+    built: i64,
+    /// If a full scan is running
+    scanning: bool,
+    /// How many folders are being watched for changes
+    watched: usize,
+    watch_error: Option<String>,
+    dirty: bool,
+    rebuild_requested: bool,
+    file: Option<String>,
+}
+
+impl FileIndex {
+    /// An empty index saved to the file named by FS_INDEX_FILE, defaulting to
+    /// private/index.json. Setting it to an empty string keeps the index in memory only
+    pub fn new() -> Self {
+        let file = match env::var(INDEX_FILE_ENV) {
+            Ok(file) if file.is_empty() => None,
+            Ok(file) => Some(file),
+            Err(_) => Some(DEFAULT_INDEX_FILE.to_owned())
+        };
+        Self {
+            entries: BTreeMap::new(),
+            state: IndexState::Loading,
+            built: 0,
+            scanning: false,
+            watched: 0,
+            watch_error: None,
+            dirty: false,
+            rebuild_requested: false,
+            file,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.state == IndexState::Ready
+    }
+
+    /// Asks the indexer to scan everything again
+    pub fn request_rebuild(&mut self) {
+        self.rebuild_requested = true;
+    }
+
+    /// The state of the index for the admin page
+    pub fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "state": self.state,
+            "scanning": self.scanning || self.rebuild_requested,
+            "folders": self.entries.values().filter(|entry| entry.is_folder).count(),
+            "files": self.entries.values().filter(|entry| !entry.is_folder).count(),
+            "built": if self.built == 0 { String::from("never") } else {
+                time::OffsetDateTime::from_unix_timestamp(self.built)
+                    .map(|t| time_format::format_time(t, None))
+                    .unwrap_or_else(|_| String::from("Unknown"))
+            },
+            "watched": self.watched,
+            "watch_error": self.watch_error,
+            "file": self.file
+        })
+    }
+
+    /// Every entry below a folder, in path order
+    fn below<'a>(&'a self, folder: &Folder) -> Box<dyn Iterator<Item = (&'a String, &'a IndexEntry)> + 'a> {
+        let prefix = folder.relative_path();
+        if prefix.is_empty() {
+            return Box::new(self.entries.iter().filter(|(path, _)| !path.is_empty()));
+        }
+        // '0' comes right after '/' so this is every path starting with prefix/
+        Box::new(self.entries.range(format!("{}/", prefix)..format!("{}0", prefix)))
+    }
+
+    /// The total size and number of files and folders below a folder
+    pub fn usage(&self, folder: &Folder) -> Usage {
+        let mut usage = Usage::default();
+        for (_, entry) in self.below(folder) {
+            add_entry(&mut usage, entry);
+        }
+        usage
+    }
+
+    /// The usage of every folder and file directly in a folder, by name
+    pub fn breakdown(&self, folder: &Folder) -> BTreeMap<String, (bool, Usage)> {
+        let skip = if folder.is_root() { 0 } else { folder.relative_path().len() + 1 };
+        let mut children: BTreeMap<String, (bool, Usage)> = BTreeMap::new();
+        for (path, entry) in self.below(folder) {
+            let relative = &path[skip..];
+            match relative.split_once('/') {
+                None => {
+                    let child = children.entry(relative.to_owned()).or_default();
+                    child.0 = entry.is_folder;
+                    if !entry.is_folder {
+                        add_entry(&mut child.1, entry);
+                    }
+                },
+                Some((name, _)) => add_entry(&mut children.entry(name.to_owned()).or_default().1, entry)
+            }
+        }
+        children
+    }
+
+    /// Finds the entries below a folder matching a search the user can see, closest folders
+    /// first. Also returns if there were more than limit
+    pub fn search(&self, folder: &Folder, search: &Search, access: &AccessList, show_hidden: bool, limit: usize) -> (Vec<SearchResult>, bool) {
+        let mut found: Vec<(&String, &IndexEntry)> = self.below(folder)
+            .filter(|(path, entry)| {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                search.matches(name, entry.is_folder, entry.size, entry.modified) && is_visible(path, access, show_hidden)
+            })
+            .collect();
+        found.sort_by_key(|(path, _)| path.matches('/').count());
+        let truncated = found.len() > limit;
+        (found.into_iter().take(limit).filter_map(|(path, entry)| to_result(path, entry)).collect(), truncated)
+    }
+
+    /// The most recently modified files below a folder the user can see, newest first
+    pub fn recent(&self, folder: &Folder, access: &AccessList, show_hidden: bool, count: usize) -> Vec<SearchResult> {
+        let mut files: Vec<(&String, &IndexEntry)> = self.below(folder)
+            .filter(|(path, entry)| !entry.is_folder && is_visible(path, access, show_hidden))
+            .collect();
+        files.sort_by(|a, b| b.1.modified.cmp(&a.1.modified).then_with(|| a.0.cmp(b.0)));
+        files.into_iter().take(count).filter_map(|(path, entry)| to_result(path, entry)).collect()
+    }
+
+    /// Files below a folder the user can see that have the same size as another, grouped by size.
+    /// Only these can be duplicates, so only these need their contents hashed
+    pub fn same_size(&self, folder: &Folder, access: &AccessList, show_hidden: bool) -> Vec<Vec<(String, IndexEntry)>> {
+        let mut by_size: HashMap<u64, Vec<(String, IndexEntry)>> = HashMap::new();
+        for (path, entry) in self.below(folder) {
+            if !entry.is_folder && entry.size > 0 && is_visible(path, access, show_hidden) {
+                by_size.entry(entry.size).or_default().push((path.clone(), entry.clone()));
+            }
+        }
+        let mut groups: Vec<Vec<(String, IndexEntry)>> = by_size.into_values().filter(|group| group.len() > 1).collect();
+        groups.sort_by(|a, b| b[0].1.size.cmp(&a[0].1.size));
+        groups
+    }
+
+    /// Remembers the hash of a file, unless it changed since it was hashed
+    pub fn set_hash(&mut self, path: &str, hashed: &IndexEntry, hash: String) {
+        if let Some(entry) = self.entries.get_mut(path) {
+            if entry.size == hashed.size && entry.modified == hashed.modified {
+                entry.hash = Some(hash);
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Reads the index file, which is done before the indexer takes the lock
+    fn load(file: Option<&str>) -> Option<StoredIndex> {
+        file.and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|contents| serde_json::from_str::<StoredIndex>(&contents).ok())
+    }
+
+    /// Copies what the index file needs, so it is written after the lock is released
+    fn snapshot(&mut self) -> Option<(String, StoredIndex)> {
+        self.dirty = false;
+        let file = self.file.clone()?;
+        Some((file, StoredIndex { built: self.built, entries: self.entries.clone() }))
+    }
+
+    /// Replaces the entries with ones the indexer updated on a copy, keeping the hashes
+    /// worked out meanwhile for files that did not change
+    fn swap_entries(&mut self, mut entries: BTreeMap<String, IndexEntry>) {
+        for (path, entry) in entries.iter_mut().filter(|(_, entry)| entry.hash.is_none() && !entry.is_folder) {
+            if let Some(old) = self.entries.get(path).filter(|old| old.size == entry.size && old.modified == entry.modified) {
+                entry.hash = old.hash.clone();
+            }
+        }
+        self.entries = entries;
+        self.dirty = true;
+    }
+}
+
+/// Writes a snapshot of the index to its file
+fn save(snapshot: Option<(String, StoredIndex)>) {
+    let (file, stored) = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return
+    };
+    let tmp_file = format!("{}.tmp", file);
+    let result = File::create(&tmp_file)
+        .and_then(|writer| serde_json::to_writer(io::BufWriter::new(writer), &stored).map_err(io::Error::other))
+        .and_then(|_| fs::rename(&tmp_file, &file));
+    if let Err(err) = result {
+        log::error!("could not save index {}: {}", file, err);
+    }
+}
+
+impl Default for FileIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn add_entry(usage: &mut Usage, entry: &IndexEntry) {
+    if entry.is_folder {
+        usage.folders += 1;
+    }
+    else {
+        usage.files += 1;
+        usage.size += entry.size;
+    }
+}
+
+/// Returns if a user could reach a path by browsing. Hidden names are left out unless they
+/// show hidden files and nothing in or under a denied folder is shown
+fn is_visible(path: &str, access: &AccessList, show_hidden: bool) -> bool {
+    let mut end = 0;
+    for name in path.split('/') {
+        end += name.len();
+        if (!show_hidden && name.starts_with('.')) || access.access(&path[..end]) == Access::Deny {
+            return false;
+        }
+        end += 1;
+    }
+    true
+}
+
+fn to_result(path: &str, entry: &IndexEntry) -> Option<SearchResult> {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    Some(SearchResult {
+        folder: Folder::from_relative_path(parent).ok()?,
+        name: name.to_owned(),
+        is_folder: entry.is_folder,
+        size: entry.size,
+        modified: entry.modified,
+        hits: None,
+    })
+}
+
+/// Returns the SHA-256 of a file's contents as hex
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Starts the thread that fills the index and keeps it current, publishing what changes
+/// to live. The thread stops once nothing else holds on to the index
+pub fn start(index: Arc<Mutex<FileIndex>>, live: Arc<Mutex<LiveUpdates>>) -> Result<(), io::Error> {
+    let root = Folder::canonical_root().map_err(|err| io::Error::other(err.to_string()))?;
+    thread::Builder::new().name(String::from("indexer")).spawn(move || {
+        let mut indexer = Indexer { root, watcher: Watcher::new(), index, live };
+        indexer.run();
+    })?;
+    Ok(())
+}
+
+struct Indexer {
+    root: PathBuf,
+    watcher: Watcher,
+    index: Arc<Mutex<FileIndex>>,
+    live: Arc<Mutex<LiveUpdates>>,
+}
+
+impl Indexer {
+    fn lock(&self) -> MutexGuard<'_, FileIndex> {
+        self.index.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn run(&mut self) {
+        let file = self.lock().file.clone();
+        if let Some(mut stored) = FileIndex::load(file.as_deref()) {
+            // catch up with what changed while the server was not running
+            self.reconcile(&mut stored.entries);
+            let snapshot = {
+                let mut index = self.lock();
+                index.entries = stored.entries;
+                index.built = stored.built;
+                index.state = IndexState::Ready;
+                index.snapshot()
+            };
+            save(snapshot);
+        }
+        else {
+            self.rebuild();
+        }
+        let mut last_save = Instant::now();
+        #[cfg(not(target_os = "linux"))]
+        let mut last_reconcile = Instant::now();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            if Arc::strong_count(&self.index) == 1 {
+                return;
+            }
+            if self.lock().rebuild_requested {
+                self.rebuild();
+                continue;
+            }
+            let events = self.watcher.events();
+            if !events.is_empty() {
+                self.apply(events);
+            }
+            #[cfg(not(target_os = "linux"))]
+            if last_reconcile.elapsed() >= RECONCILE_INTERVAL {
+                let mut entries = self.lock().entries.clone();
+                self.reconcile(&mut entries);
+                self.lock().swap_entries(entries);
+                last_reconcile = Instant::now();
+            }
+            let snapshot = {
+                let mut index = self.lock();
+                index.watched = self.watcher.len();
+                index.watch_error = self.watcher.error.clone();
+                (index.dirty && last_save.elapsed() >= SAVE_INTERVAL).then(|| index.snapshot())
+            };
+            if let Some(snapshot) = snapshot {
+                save(snapshot);
+                last_save = Instant::now();
+            }
+        }
+    }
+
+    /// Scans everything into a new index. The old one keeps answering until it is done
+    fn rebuild(&mut self) {
+        {
+            let mut index = self.lock();
+            index.rebuild_requested = false;
+            index.scanning = true;
+        }
+        let started = Instant::now();
+        self.watcher.clear();
+        let mut entries = BTreeMap::new();
+        self.rescan("", &mut entries);
+        log::info!("indexed {} entries in {:.1}s", entries.len(), started.elapsed().as_secs_f32());
+        let snapshot = {
+            let mut index = self.lock();
+            index.entries = entries;
+            index.state = IndexState::Ready;
+            index.scanning = false;
+            index.built = audit::now();
+            index.watched = self.watcher.len();
+            index.snapshot()
+        };
+        save(snapshot);
+    }
+
+    /// Adds everything in and below the folder at relative, and the folder itself, watching each folder
+    fn scan(&mut self, relative: &str, path: &Path, rules: Arc<IgnoreRules>, entries: &mut BTreeMap<String, IndexEntry>) {
+        let mut pending = vec![(relative.to_owned(), path.to_path_buf(), rules)];
+        while let Some((relative, path, rules)) = pending.pop() {
+            let data = match fs::symlink_metadata(&path) {
+                Ok(data) if data.is_dir() => data,
+                _ => continue
+            };
+            entries.insert(relative.clone(), to_entry(&data));
+            self.watcher.watch(&path, &relative);
+            let dir = match fs::read_dir(&path) {
+                Ok(dir) => dir,
+                Err(err) => {
+                    log::debug!("indexer could not read '{}': {}", path.display(), err);
+                    continue;
+                }
+            };
+            for dir_entry in dir.flatten() {
+                let (name, file_type) = match (dir_entry.file_name().into_string(), dir_entry.file_type()) {
+                    (Ok(name), Ok(file_type)) => (name, file_type),
+                    _ => continue
+                };
+                let child = join(&relative, &name);
+                let names: Vec<String> = child.split('/').map(str::to_owned).collect();
+                if file_type.is_symlink() || rules.is_ignored(&names, &dir_entry.path()) {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    let child_path = dir_entry.path();
+                    let child_rules = if child_path.join(IGNORE_FILE).is_file() {
+                        let mut child_rules = IgnoreRules::clone(&rules);
+                        child_rules.add_folder(&child_path, names.len());
+                        Arc::new(child_rules)
+                    }
+                    else {
+                        Arc::clone(&rules)
+                    };
+                    pending.push((child, child_path, child_rules));
+                }
+                else if let Ok(data) = dir_entry.metadata() {
+                    entries.insert(child, to_entry(&data));
+                }
+            }
+        }
+    }
+
+    /// Rescans every folder whose modified time changed, which means something was added,
+    /// removed or renamed directly in it. Removes folders that no longer exist
+    fn reconcile(&mut self, entries: &mut BTreeMap<String, IndexEntry>) {
+        let folders: Vec<String> = entries.iter().filter(|(_, entry)| entry.is_folder).map(|(path, _)| path.clone()).collect();
+        for relative in folders {
+            let stored = match entries.get(&relative) {
+                Some(stored) => stored.clone(),
+                None => continue
+            };
+            let path = self.root.join(&relative);
+            match fs::symlink_metadata(&path) {
+                Ok(data) if data.is_dir() && to_entry(&data).modified == stored.modified => self.watcher.watch(&path, &relative),
+                Ok(data) if data.is_dir() => self.refresh_children(&relative, entries),
+                _ => self.refresh(&relative, entries)
+            }
+        }
+        if !entries.contains_key("") {
+            self.rescan("", entries);
+        }
+    }
+
+    /// Updates what is directly in a folder. Folders already in the index are left
+    /// for reconcile to check, new ones are scanned
+    fn refresh_children(&mut self, relative: &str, entries: &mut BTreeMap<String, IndexEntry>) {
+        let path = self.root.join(relative);
+        let (rules, dir) = match (self.rules_for(relative), fs::read_dir(&path)) {
+            (Some(rules), Ok(dir)) => (Arc::new(rules), dir),
+            _ => return remove(relative, entries, &mut self.watcher)
+        };
+        let mut present = HashSet::new();
+        for dir_entry in dir.flatten() {
+            let (name, file_type) = match (dir_entry.file_name().into_string(), dir_entry.file_type()) {
+                (Ok(name), Ok(file_type)) => (name, file_type),
+                _ => continue
+            };
+            let child = join(relative, &name);
+            let names: Vec<String> = child.split('/').map(str::to_owned).collect();
+            if file_type.is_symlink() || rules.is_ignored(&names, &dir_entry.path()) {
+                continue;
+            }
+            present.insert(child.clone());
+            let known_folder = entries.get(&child).is_some_and(|entry| entry.is_folder);
+            if file_type.is_dir() && !known_folder {
+                self.rescan(&child, entries);
+            }
+            else if !file_type.is_dir() {
+                if known_folder {
+                    remove(&child, entries, &mut self.watcher);
+                }
+                if let Ok(data) = dir_entry.metadata() {
+                    entries.insert(child, to_entry(&data));
+                }
+            }
+        }
+        let skip = if relative.is_empty() { 0 } else { relative.len() + 1 };
+        let gone: Vec<String> = entries.keys()
+            .filter(|path| path.len() > skip && (relative.is_empty() || path.starts_with(&format!("{}/", relative))))
+            .filter(|path| !path[skip..].contains('/') && !present.contains(*path))
+            .cloned()
+            .collect();
+        for path in gone {
+            remove(&path, entries, &mut self.watcher);
+        }
+        if let Ok(data) = fs::symlink_metadata(&path) {
+            entries.insert(relative.to_owned(), to_entry(&data));
+        }
+        self.watcher.watch(&path, relative);
+    }
+
+    /// Replaces everything in and below the folder at relative with a fresh scan
+    fn rescan(&mut self, relative: &str, entries: &mut BTreeMap<String, IndexEntry>) {
+        remove(relative, entries, &mut self.watcher);
+        if let Some(rules) = self.rules_for(relative) {
+            let path = self.root.join(relative);
+            self.scan(relative, &path, Arc::new(rules), entries);
+        }
+    }
+
+    /// Works the events into a copy of the entries, so searches keep using the index while
+    /// the disk is read, and only locks it to swap the copy in
+    fn apply(&mut self, events: Vec<WatchEvent>) {
+        let mut entries = self.lock().entries.clone();
+        // whether each changed path was in the index before and after, to tell what happened to it
+        let mut changed = Vec::new();
+        let mut renames = Vec::new();
+        for event in events {
+            match event {
+                WatchEvent::Overflow => {
+                    log::warn!("indexer missed changes, checking every folder");
+                    self.reconcile(&mut entries);
+                },
+                WatchEvent::Changed(relative) => {
+                    let before = entries.contains_key(&relative);
+                    self.refresh(&relative, &mut entries);
+                    changed.push((relative.clone(), before, entries.contains_key(&relative)));
+                },
+                WatchEvent::Renamed(from, to) => renames.push((from, to))
+            }
+        }
+        self.lock().swap_entries(entries);
+        self.publish(changed, renames);
+    }
+
+    /// Tells the folder feeds what was added, changed, removed or renamed. A rename within
+    /// one folder is one change instead of a removal and an addition
+    fn publish(&self, mut changed: Vec<(String, bool, bool)>, renames: Vec<(String, String)>) {
+        let mut live = self.live.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (from, to) in renames {
+            let parent = |path: &str| path.rsplit_once('/').map(|(parent, _)| parent.to_owned()).unwrap_or_default();
+            let removed = changed.iter().position(|(path, before, after)| *path == from && *before && !*after);
+            let added = changed.iter().position(|(path, _, after)| *path == to && *after);
+            if let (Some(removed), Some(added), true) = (removed, added, parent(&from) == parent(&to)) {
+                live.publish(ChangeKind::Renamed, &from, to.rsplit('/').next());
+                changed[removed].1 = false;
+                changed[added] = (to, false, false);
+            }
+        }
+        for (relative, before, after) in changed {
+            let kind = match (before, after) {
+                (false, true) => ChangeKind::Added,
+                (true, true) => ChangeKind::Changed,
+                (true, false) => ChangeKind::Removed,
+                (false, false) => continue
+            };
+            if !relative.is_empty() {
+                live.publish(kind, &relative, None);
+            }
+        }
+    }
+
+    /// Brings the index up to date for one path: removes it if it is gone or ignored,
+    /// updates it if it is a file or a known folder and scans it if it is a new folder
+    fn refresh(&mut self, relative: &str, entries: &mut BTreeMap<String, IndexEntry>) {
+        let parent = relative.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("");
+        if relative.rsplit('/').next() == Some(IGNORE_FILE) {
+            // the patterns changed, so everything in the folder is checked again
+            return self.rescan(parent, entries);
+        }
+        let path = self.root.join(relative);
+        let data = fs::symlink_metadata(&path).ok().filter(|data| !data.file_type().is_symlink());
+        let known_folder = entries.get(relative).is_some_and(|entry| entry.is_folder);
+        match (self.rules_for(relative).is_some(), data) {
+            (true, Some(data)) if data.is_dir() && known_folder => {
+                entries.insert(relative.to_owned(), to_entry(&data));
+            },
+            (true, Some(data)) if data.is_dir() => self.rescan(relative, entries),
+            (true, Some(data)) => {
+                if known_folder {
+                    remove(relative, entries, &mut self.watcher);
+                }
+                entries.insert(relative.to_owned(), to_entry(&data));
+            },
+            _ => remove(relative, entries, &mut self.watcher)
+        }
+        // the parent folder's modified time changed with it
+        if !relative.is_empty() {
+            if let (Some(entry), Ok(data)) = (entries.get_mut(parent), fs::metadata(self.root.join(parent))) {
+                entry.modified = to_entry(&data).modified;
+            }
+        }
+    }
+
+    /// The ignore rules that apply inside the folder at relative, if it is not ignored itself
+    fn rules_for(&self, relative: &str) -> Option<IgnoreRules> {
+        let mut rules = IgnoreRules::global();
+        let mut path = self.root.clone();
+        let names: Vec<String> = relative.split('/').filter(|name| !name.is_empty()).map(str::to_owned).collect();
+        for index in 0..names.len() {
+            rules.add_folder(&path, index);
+            path.push(&names[index]);
+            if rules.is_ignored(&names[..=index], &path) {
+                return None;
+            }
+        }
+        rules.add_folder(&path, names.len());
+        Some(rules)
+    }
+}
+
+/// Removes an entry and everything below it
+fn remove(relative: &str, entries: &mut BTreeMap<String, IndexEntry>, watcher: &mut Watcher) {
+    entries.remove(relative);
+    let below: Vec<String> = if relative.is_empty() {
+        entries.keys().cloned().collect()
+    }
+    else {
+        entries.range(format!("{}/", relative)..format!("{}0", relative)).map(|(path, _)| path.clone()).collect()
+    };
+    for path in below {
+        entries.remove(&path);
+    }
+    watcher.unwatch_below(relative);
+}
+
+fn join(relative: &str, name: &str) -> String {
+    if relative.is_empty() { name.to_owned() } else { format!("{}/{}", relative, name) }
+}
+
+fn to_entry(data: &fs::Metadata) -> IndexEntry {
+    IndexEntry {
+        is_folder: data.is_dir(),
+        size: if data.is_dir() { 0 } else { data.len() },
+        modified: data.modified().ok()
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0),
+        hash: None,
+    }
+}
+
+enum WatchEvent {
+    /// Something changed at a path relative to the root folder
+    Changed(String),
+    /// Something was moved from one watched path to another. Both paths are also Changed
+    Renamed(String, String),
+    /// Too many changes happened at once to know which
+    Overflow,
+}
+
+/// Watches folders with inotify for changes
+#[cfg(target_os = "linux")]
+struct Watcher {
+    inotify: Option<inotify::Inotify>,
+    folders: HashMap<inotify::WatchDescriptor, String>,
+    error: Option<String>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn new() -> Self {
+        match inotify::Inotify::init() {
+            Ok(inotify) => Self { inotify: Some(inotify), folders: HashMap::new(), error: None },
+            Err(err) => {
+                log::error!("could not start watching for changes, the index will only update on a rebuild: {}", err);
+                Self { inotify: None, folders: HashMap::new(), error: Some(err.to_string()) }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.folders.len()
+    }
+
+    fn watch(&mut self, path: &Path, relative: &str) {
+        use inotify::WatchMask;
+        let inotify = match &mut self.inotify {
+            Some(inotify) => inotify,
+            None => return
+        };
+        let mask = WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO
+            | WatchMask::CLOSE_WRITE | WatchMask::ATTRIB | WatchMask::ONLYDIR | WatchMask::DONT_FOLLOW;
+        match inotify.watches().add(path, mask) {
+            Ok(wd) => {
+                self.folders.insert(wd, relative.to_owned());
+            },
+            Err(err) => {
+                if self.error.is_none() {
+                    log::error!("could not watch '{}' for changes, raise fs.inotify.max_user_watches if there are too many folders: {}", path.display(), err);
+                }
+                self.error = Some(err.to_string());
+            }
+        }
+    }
+
+    fn unwatch_below(&mut self, relative: &str) {
+        let prefix = format!("{}/", relative);
+        let removed: Vec<inotify::WatchDescriptor> = self.folders.iter()
+            .filter(|(_, folder)| relative.is_empty() || folder.as_str() == relative || folder.starts_with(&prefix))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+        for wd in removed {
+            self.folders.remove(&wd);
+            if let Some(inotify) = &mut self.inotify {
+                // fails if the folder is already gone, which removed the watch with it
+                let _ = inotify.watches().remove(wd);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.unwatch_below("");
+    }
+
+    fn events(&mut self) -> Vec<WatchEvent> {
+        use inotify::EventMask;
+        let inotify = match &mut self.inotify {
+            Some(inotify) => inotify,
+            None => return Vec::new()
+        };
+        let mut buffer = [0; 4096];
+        let mut changed = HashSet::new();
+        // the two halves of a rename share a cookie
+        let mut moved_from = HashMap::new();
+        let mut events = Vec::new();
+        loop {
+            let read = match inotify.read_events(&mut buffer) {
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("could not read changes: {}", err);
+                    break;
+                }
+            };
+            for event in read {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    events.push(WatchEvent::Overflow);
+                    continue;
+                }
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.folders.remove(&event.wd);
+                    continue;
+                }
+                let (folder, name) = match (self.folders.get(&event.wd), event.name.and_then(|name| name.to_str())) {
+                    (Some(folder), Some(name)) => (folder, name),
+                    _ => continue
+                };
+                let relative = join(folder, name);
+                if event.mask.contains(EventMask::MOVED_FROM) {
+                    moved_from.insert(event.cookie, relative.clone());
+                }
+                else if let Some(from) = event.mask.contains(EventMask::MOVED_TO).then(|| moved_from.remove(&event.cookie)).flatten() {
+                    events.push(WatchEvent::Renamed(from, relative.clone()));
+                }
+                if changed.insert(relative.clone()) {
+                    events.push(WatchEvent::Changed(relative));
+                }
+            }
+        }
+        events
+    }
+}
+
+/// Without inotify the index is kept current by checking folders every few minutes
+#[cfg(not(target_os = "linux"))]
+struct Watcher {
+    error: Option<String>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    fn new() -> Self {
+        Self { error: None }
+    }
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn watch(&mut self, _path: &Path, _relative: &str) {}
+
+    fn unwatch_below(&mut self, _relative: &str) {}
+
+    fn clear(&mut self) {}
+
+    fn events(&mut self) -> Vec<WatchEvent> {
+        Vec::new()
+    }
+}
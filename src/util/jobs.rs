@@ -0,0 +1,392 @@
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{self, Receiver, Sender}, Arc, Mutex, MutexGuard},
+    thread,
+};
+
+use serde::Serialize;
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::{models::{conflict::{ConflictPolicy, ItemOutcome, Outcome}, folder::Folder, listing::format_size}, util::{audit::{self, Auditor, Operation}, error::AppErrorKind, time_format}};
+
+const JOB_WORKERS_ENV: &str = "FS_JOB_WORKERS";
+const DEFAULT_JOB_WORKERS: usize = 2;
+/// Past this many finished jobs the oldest are forgotten
+const FINISHED_JOBS_KEPT: usize = 50;
+/// Files are copied in pieces this big, so a cancel is noticed in the middle of a large file
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// How far a job has got, shared between the worker running it and the pages showing it.
+/// The totals are counted before the work starts, so they can be zero for a moment
+#[derive(Debug, Default)]
+pub struct Progress {
+    files_total: AtomicU64,
+    files_done: AtomicU64,
+    bytes_total: AtomicU64,
+    bytes_done: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    pub fn add_total(&self, files: u64, bytes: u64) {
+        self.files_total.fetch_add(files, Ordering::Relaxed);
+        self.bytes_total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn file_done(&self) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Fails once the job is cancelled, so the work stops at the next file
+    pub fn check(&self) -> io::Result<()> {
+        match self.is_cancelled() {
+            true => Err(io::Error::new(io::ErrorKind::Interrupted, "the job was cancelled")),
+            false => Ok(())
+        }
+    }
+
+    /// Copies everything from reader to writer like io::copy, counting the bytes and
+    /// stopping if the job is cancelled
+    pub fn copy<R: Read + ?Sized, W: Write + ?Sized>(&self, reader: &mut R, writer: &mut W) -> io::Result<u64> {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut copied = 0;
+        loop {
+            self.check()?;
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(copied),
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            };
+            writer.write_all(&buffer[..read])?;
+            copied += read as u64;
+            self.bytes_done.fetch_add(read as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let files_total = self.files_total.load(Ordering::Relaxed);
+        let files_done = self.files_done.load(Ordering::Relaxed);
+        let bytes_total = self.bytes_total.load(Ordering::Relaxed);
+        let bytes_done = self.bytes_done.load(Ordering::Relaxed);
+        // links and files added while the job runs can take it past what was counted
+        let percent = match (bytes_total, files_total) {
+            (0, 0) => None,
+            (0, files_total) => Some((files_done * 100 / files_total).min(100)),
+            (bytes_total, _) => Some((bytes_done * 100 / bytes_total).min(100))
+        };
+        json!({
+            "files_total": files_total,
+            "files_done": files_done,
+            "bytes_total_display": format_size(bytes_total),
+            "bytes_done_display": format_size(bytes_done),
+            "percent": percent
+        })
+    }
+}
+
+/// Copies a file like fs::copy, keeping its permissions, counting it in the progress
+pub fn copy_file(from: &Path, to: &Path, progress: &Progress) -> io::Result<u64> {
+    let mut reader = fs::File::open(from)?;
+    let mut writer = fs::File::create(to)?;
+    let copied = progress.copy(&mut reader, &mut writer)?;
+    fs::set_permissions(to, reader.metadata()?.permissions())?;
+    progress.file_done();
+    Ok(copied)
+}
+
+/// Adds the files at and below path to the totals of the progress. Linked
+/// folders are not followed, since they are not copied or zipped either
+pub fn measure(path: &Path, progress: &Progress) {
+    match fs::symlink_metadata(path) {
+        Ok(data) if data.is_dir() => {
+            for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+                measure(&entry.path(), progress);
+            }
+        },
+        Ok(data) if data.file_type().is_symlink() => match fs::metadata(path) {
+            Ok(data) if data.is_file() => progress.add_total(1, data.len()),
+            _ => ()
+        },
+        Ok(data) => progress.add_total(1, data.len()),
+        Err(_) => ()
+    }
+}
+
+/// The task of a job copying the named folders and files of folder into new_folder, one
+/// after the other. Copies next to the originals always get new names, which is not news
+pub fn copy_task(auditor: Auditor, folder: Folder, names: Vec<String>, new_folder: Folder, policy: ConflictPolicy)
+    -> impl FnOnce(&Progress) -> Result<Vec<ItemOutcome>, AppErrorKind> + Send + 'static
+{
+    move |progress| {
+        for name in &names {
+            measure(Path::new(&folder.join(name)?.to_path()?), progress);
+        }
+        let mut outcomes = Vec::new();
+        for name in &names {
+            if progress.is_cancelled() {
+                break;
+            }
+            let result = folder.copy_into(name, &new_folder, policy, &auditor.user, progress);
+            let outcome = match auditor.audit_outcome(Operation::Copy, audit::path(&folder, name), &new_folder, name, result) {
+                Ok(outcome) if new_folder == folder && outcome.is_written() => Outcome::Done,
+                result => result.into()
+            };
+            outcomes.push(ItemOutcome::new(name, outcome));
+        }
+        Ok(outcomes)
+    }
+}
+
+/// The task of a job moving the named folders and files of folder into new_folder, one after
+/// the other. Progress counts the items, since most moves are a rename that takes no time
+/// and only a move to another drive copies anything
+pub fn move_task(auditor: Auditor, folder: Folder, names: Vec<String>, new_folder: Folder, policy: ConflictPolicy)
+    -> impl FnOnce(&Progress) -> Result<Vec<ItemOutcome>, AppErrorKind> + Send + 'static
+{
+    move |progress| {
+        progress.add_total(names.len() as u64, 0);
+        let mut outcomes = Vec::new();
+        for name in &names {
+            if progress.is_cancelled() {
+                break;
+            }
+            let result = folder.move_entity(name, &new_folder, policy, &auditor.user);
+            let outcome = auditor.audit_outcome(Operation::Move, audit::path(&folder, name), &new_folder, name, result);
+            outcomes.push(ItemOutcome::new(name, outcome.into()));
+            progress.file_done();
+        }
+        Ok(outcomes)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed | JobState::Cancelled)
+    }
+}
+
+/// The work of a job, run on a worker thread. Returns what happened to each item it wrote
+pub type Task = Box<dyn FnOnce(&Progress) -> Result<Vec<ItemOutcome>, AppErrorKind> + Send>;
+
+/// A long operation like a move, copy, zip or unzip that runs after the request that started it
+pub struct Job {
+    pub id: u64,
+    pub operation: Operation,
+    pub user: String,
+    /// What the job works on and where it writes to, relative to the root folder like in the audit log
+    pub path: String,
+    pub target: Option<String>,
+    /// The folder to open to see what the job made
+    pub folder: Folder,
+    pub state: JobState,
+    pub error: Option<String>,
+    pub outcomes: Vec<ItemOutcome>,
+    pub progress: Arc<Progress>,
+    created: i64,
+    finished: Option<i64>,
+    task: Option<Task>,
+}
+
+impl Job {
+    pub fn new(operation: Operation, user: &str, path: String, target: Option<String>, folder: Folder) -> Self {
+        Self {
+            id: 0,
+            operation,
+            user: user.to_owned(),
+            path,
+            target,
+            folder,
+            state: JobState::Queued,
+            error: None,
+            outcomes: Vec::new(),
+            progress: Arc::new(Progress::default()),
+            created: audit::now(),
+            finished: None,
+            task: None
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let format = |time: i64| OffsetDateTime::from_unix_timestamp(time)
+            .map(|time| time_format::format_time(time, None))
+            .unwrap_or_default();
+        json!({
+            "id": self.id,
+            "operation": self.operation,
+            "user": self.user,
+            "path": format!("/{}", self.path),
+            "target": self.target.as_ref().map(|target| format!("/{}", target)),
+            "folder_path": self.folder.url(),
+            "state": self.state,
+            "finished": self.state.is_finished(),
+            "error": self.error,
+            "messages": self.outcomes.iter().filter_map(ItemOutcome::message).collect::<Vec<String>>(),
+            "progress": self.progress.to_json(),
+            "created_display": format(self.created),
+            "finished_display": self.finished.map(format)
+        })
+    }
+}
+
+/// Jobs waiting, running and recently finished, shared by the handlers and the workers
+pub struct JobQueue {
+    jobs: Vec<Job>,
+    next_id: u64,
+    sender: Sender<u64>,
+    receiver: Option<Receiver<u64>>,
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self { jobs: Vec::new(), next_id: 1, sender, receiver: Some(receiver) }
+    }
+
+    /// Queues the job to run the task, returning its id
+    pub fn submit<F>(&mut self, mut job: Job, task: F) -> u64
+        where F: FnOnce(&Progress) -> Result<Vec<ItemOutcome>, AppErrorKind> + Send + 'static
+    {
+        job.id = self.next_id;
+        job.task = Some(Box::new(task));
+        self.next_id += 1;
+        let id = job.id;
+        self.jobs.push(job);
+        if self.sender.send(id).is_err() {
+            log::error!("job {} was queued but no worker is running", id);
+        }
+        id
+    }
+
+    /// Every job kept, newest first
+    pub fn jobs(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter().rev()
+    }
+
+    pub fn get(&self, id: u64) -> Result<&Job, AppErrorKind> {
+        self.jobs.iter().find(|job| job.id == id).ok_or(AppErrorKind::JobNotFound)
+    }
+
+    /// A queued job is dropped right away, a running one stops at the next file or piece of one
+    pub fn cancel(&mut self, id: u64) -> Result<(), AppErrorKind> {
+        let job = self.jobs.iter_mut().find(|job| job.id == id).ok_or(AppErrorKind::JobNotFound)?;
+        match job.state {
+            JobState::Queued => {
+                job.task = None;
+                job.state = JobState::Cancelled;
+                job.finished = Some(audit::now());
+            },
+            JobState::Running => job.progress.cancel(),
+            _ => return Err(AppErrorKind::JobFinished)
+        }
+        Ok(())
+    }
+
+    /// Takes the task of a queued job to run it
+    fn start(&mut self, id: u64) -> Option<(Task, Arc<Progress>)> {
+        let job = self.jobs.iter_mut().find(|job| job.id == id && job.state == JobState::Queued)?;
+        job.state = JobState::Running;
+        Some((job.task.take()?, job.progress.clone()))
+    }
+
+    fn finish(&mut self, id: u64, result: Result<Vec<ItemOutcome>, String>) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            job.finished = Some(audit::now());
+            job.state = match (&result, job.progress.is_cancelled()) {
+                (_, true) => JobState::Cancelled,
+                (Ok(outcomes), false) if !outcomes.is_empty() && outcomes.iter().all(|item| matches!(item.outcome, Outcome::Failed(_))) => JobState::Failed,
+                (Ok(_), false) => JobState::Done,
+                (Err(_), false) => JobState::Failed
+            };
+            match result {
+                Ok(outcomes) => job.outcomes = outcomes,
+                Err(err) if job.state == JobState::Failed => job.error = Some(err),
+                Err(_) => ()
+            }
+        }
+        let finished = self.jobs.iter().filter(|job| job.state.is_finished()).count();
+        let mut forget = finished.saturating_sub(FINISHED_JOBS_KEPT);
+        self.jobs.retain(|job| {
+            let keep = forget == 0 || !job.state.is_finished();
+            if !keep {
+                forget -= 1;
+            }
+            keep
+        });
+    }
+}
+
+/// Returns how many jobs can run at once, from the FS_JOB_WORKERS environment variable
+pub fn workers() -> usize {
+    env::var(JOB_WORKERS_ENV).ok()
+        .and_then(|workers| workers.parse::<usize>().ok())
+        .filter(|workers| *workers > 0)
+        .unwrap_or(DEFAULT_JOB_WORKERS)
+}
+
+/// Starts the threads that run queued jobs. They stop once the queue is gone
+pub fn start(queue: Arc<Mutex<JobQueue>>) -> Result<(), io::Error> {
+    let receiver = match lock(&queue).receiver.take() {
+        Some(receiver) => Arc::new(Mutex::new(receiver)),
+        None => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the job workers are already running"))
+    };
+    for number in 1..=workers() {
+        let queue = Arc::downgrade(&queue);
+        let receiver = receiver.clone();
+        thread::Builder::new().name(format!("job-worker-{}", number)).spawn(move || loop {
+            let id = match receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recv() {
+                Ok(id) => id,
+                Err(_) => return
+            };
+            let queue = match queue.upgrade() {
+                Some(queue) => queue,
+                None => return
+            };
+            let (task, progress) = match lock(&queue).start(id) {
+                Some(started) => started,
+                None => continue
+            };
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| task(&progress))) {
+                Ok(result) => result.map_err(|err| err.to_string()),
+                Err(_) => Err(String::from("the job stopped unexpectedly"))
+            };
+            if let Err(err) = &result {
+                log::warn!("job {} failed: {}", id, err);
+            }
+            lock(&queue).finish(id, result);
+        })?;
+    }
+    Ok(())
+}
+
+fn lock(queue: &Mutex<JobQueue>) -> MutexGuard<'_, JobQueue> {
+    queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,228 @@
+use std::{collections::{HashMap, VecDeque}, fs, sync::Mutex, time::{Duration, Instant}};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{models::{folder::Folder, listing::Entry, user::User}, util::jobs::{JobQueue, JobState}};
+
+/// How many changes are kept for browsers that fall behind or reconnect
+const CHANGES_KEPT: usize = 1000;
+/// How often a folder feed looks for changes and job progress
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long a feed stays quiet before it sends a comment, so a closed connection is noticed
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// How many polls a folder feed makes between checks that its user may still see the folder
+const POLLS_PER_RECHECK: u32 = 10;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Removed,
+    Renamed,
+}
+
+/// Something the indexer saw happen to one file or folder
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub id: u64,
+    pub kind: ChangeKind,
+    /// The folder it happened in, relative to the root folder
+    pub folder: String,
+    pub name: String,
+    /// The name after a rename within the same folder
+    pub new_name: Option<String>,
+}
+
+/// The latest changes to files and folders, shared by the indexer and the folder feeds
+#[derive(Default)]
+pub struct LiveUpdates {
+    changes: VecDeque<Change>,
+    last_id: u64,
+}
+
+impl LiveUpdates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a change to the path relative to the root folder, new_name is the name it
+    /// was renamed to
+    pub fn publish(&mut self, kind: ChangeKind, relative: &str, new_name: Option<&str>) {
+        let (folder, name) = relative.rsplit_once('/').unwrap_or(("", relative));
+        self.last_id += 1;
+        self.changes.push_back(Change {
+            id: self.last_id,
+            kind,
+            folder: folder.to_owned(),
+            name: name.to_owned(),
+            new_name: new_name.map(str::to_owned)
+        });
+        if self.changes.len() > CHANGES_KEPT {
+            self.changes.pop_front();
+        }
+    }
+
+    pub fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
+    /// The changes kept that came after the one with the id
+    pub fn since(&self, id: u64) -> impl Iterator<Item = &Change> {
+        self.changes.iter().filter(move |change| change.id > id)
+    }
+}
+
+/// What one browser showing a folder has been sent so far
+pub struct FolderFeed {
+    folder: Folder,
+    user: User,
+    last_change: u64,
+    /// The last state sent of each job of the user, as json
+    jobs: HashMap<u64, serde_json::Value>,
+    last_sent: Instant,
+    connected: bool,
+    /// Polls since the user was last checked
+    polls: u32,
+}
+
+impl FolderFeed {
+    /// Starts after the change with last_event_id when a browser reconnects, otherwise
+    /// with what happens from now on. Jobs that already finished are not announced again
+    pub fn new(folder: Folder, user: User, last_event_id: Option<u64>, live: &Mutex<LiveUpdates>, queue: &Mutex<JobQueue>) -> Self {
+        let last_change = last_event_id.unwrap_or_else(|| live.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).last_id());
+        let jobs = queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .jobs()
+            .filter(|job| job.user == user.username && job.state.is_finished())
+            .map(|job| (job.id, job.to_json()))
+            .collect();
+        Self { folder, user, last_change, jobs, last_sent: Instant::now(), connected: false, polls: 0 }
+    }
+
+    /// Returns true every POLLS_PER_RECHECK polls, when the session and the user should be checked again.
+    /// A feed can stay open for hours, during which the user can be logged out or lose access
+    pub fn recheck_due(&mut self) -> bool {
+        self.polls += 1;
+        if self.polls < POLLS_PER_RECHECK {
+            return false;
+        }
+        self.polls = 0;
+        true
+    }
+
+    /// Filters what is sent with the user as reloaded by a recheck, so role and access changes apply
+    pub fn set_user(&mut self, user: User) {
+        self.user = user;
+    }
+
+    /// The server-sent events for everything that happened since the last poll, empty if nothing did
+    pub fn poll(&mut self, live: &Mutex<LiveUpdates>, queue: &Mutex<JobQueue>) -> String {
+        let mut events = String::new();
+        if !self.connected {
+            // sent right away so proxies pass the stream on before anything happens
+            events.push_str(": connected\nretry: 5000\n\n");
+            self.connected = true;
+        }
+        let changes: Vec<Change> = live.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .since(self.last_change)
+            .cloned()
+            .collect();
+        for change in changes {
+            self.last_change = change.id;
+            if let Some(data) = self.entry_event(&change) {
+                events.push_str(&event("entry", Some(change.id), &data));
+            }
+        }
+        self.job_events(queue, &mut events);
+        if events.is_empty() && self.last_sent.elapsed() >= KEEP_ALIVE {
+            events.push_str(": ping\n\n");
+        }
+        if !events.is_empty() {
+            self.last_sent = Instant::now();
+        }
+        events
+    }
+
+    /// What the files view needs to show a change directly in the folder, if the user may see it
+    fn entry_event(&self, change: &Change) -> Option<serde_json::Value> {
+        if change.folder != self.folder.relative_path() {
+            if change.kind == ChangeKind::Removed && self.folder.parent().is_ok_and(|parent| parent.relative_path() == change.folder)
+                && change.name == self.folder.name() {
+                return Some(json!({ "kind": ChangeKind::Removed, "name": "", "folder_removed": true }));
+            }
+            return None;
+        }
+        let access = self.user.access_list();
+        let visible = |name: &str| self.folder.join(name).ok().filter(|entity| entity.is_visible(&access, self.user.show_hidden));
+        let (kind, name, new_name) = match (change.kind, change.new_name.as_deref()) {
+            (ChangeKind::Renamed, Some(new_name)) => match (visible(&change.name), visible(new_name)) {
+                (Some(_), Some(_)) => (ChangeKind::Renamed, change.name.as_str(), Some(new_name)),
+                (Some(_), None) => (ChangeKind::Removed, change.name.as_str(), None),
+                (None, Some(_)) => (ChangeKind::Added, new_name, None),
+                (None, None) => return None
+            },
+            (kind, _) => {
+                visible(&change.name)?;
+                (kind, change.name.as_str(), None)
+            }
+        };
+        let entry = match kind {
+            ChangeKind::Removed => None,
+            _ => {
+                let entity = self.folder.join(new_name.unwrap_or(name)).ok()?;
+                // gone again already, a later change says so
+                let data = fs::metadata(entity.to_path().ok()?).ok()?;
+                Some(Entry::new(entity, data.is_dir(), Some(&data)))
+            }
+        };
+        Some(json!({
+            "kind": kind,
+            "name": name,
+            "new_name": new_name,
+            "is_folder": entry.as_ref().map(|entry| entry.is_folder),
+            "entry": entry.as_ref().map(Entry::to_json)
+        }))
+    }
+
+    /// The progress of each job of the user that moved on, and a notice for each one that finished
+    fn job_events(&mut self, queue: &Mutex<JobQueue>, events: &mut String) {
+        let queue = queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut kept = HashMap::new();
+        for job in queue.jobs().filter(|job| job.user == self.user.username) {
+            let data = job.to_json();
+            let previous = self.jobs.remove(&job.id);
+            if previous.as_ref() != Some(&data) {
+                events.push_str(&event("job", None, &data));
+                if job.state.is_finished() {
+                    events.push_str(&event("notice", None, &notice(job.state, &data)));
+                }
+            }
+            kept.insert(job.id, data);
+        }
+        self.jobs = kept;
+    }
+}
+
+/// A notice about a finished job, with the level of the flash message it stands in for
+fn notice(state: JobState, job: &serde_json::Value) -> serde_json::Value {
+    let mut what = format!("{} of {}", job["operation"].as_str().unwrap_or_default(), job["path"].as_str().unwrap_or_default());
+    if let Some(target) = job["target"].as_str() {
+        what = format!("{} to {}", what, target);
+    }
+    let (level, message) = match state {
+        JobState::Failed => ("error", format!("{} failed: {}", what, job["error"].as_str().unwrap_or("see jobs"))),
+        JobState::Cancelled => ("info", format!("{} was cancelled", what)),
+        _ if job["messages"].as_array().is_some_and(|messages| !messages.is_empty()) => ("info", format!("{} is done, see jobs for what happened to each item", what)),
+        _ => ("success", format!("{} is done", what))
+    };
+    json!({ "level": level, "message": message, "job": job["id"] })
+}
+
+/// One server-sent event. The json has no line breaks so it fits on one data line
+fn event(name: &str, id: Option<u64>, data: &serde_json::Value) -> String {
+    match id {
+        Some(id) => format!("event: {}\nid: {}\ndata: {}\n\n", name, id, data),
+        None => format!("event: {}\ndata: {}\n\n", name, data)
+    }
+}
@@ -2,4 +2,12 @@ pub mod zip;
 pub mod forward;
 pub mod error;
 pub mod time_format;
-pub mod timelapse;
\ No newline at end of file
+pub mod timelapse;
+pub mod auth;
+pub mod throttle;
+pub mod csrf;
+pub mod sessions;
+pub mod audit;
+pub mod index;
+pub mod jobs;
+pub mod live;
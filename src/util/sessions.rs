@@ -0,0 +1,378 @@
+use std::{collections::{HashMap, VecDeque}, env, fs, sync::{Arc, Mutex, MutexGuard}};
+
+use actix_session::{Session, storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError}};
+use actix_web::{cookie::time::Duration, HttpRequest, http::header};
+use rand::{Rng, distributions::Alphanumeric, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{models::user::USER_SESSION_KEY, util::{error::AppErrorKind, time_format}};
+
+const SESSIONS_FILE_ENV: &str = "FS_SESSIONS_FILE";
+const DEFAULT_SESSIONS_FILE: &str = "private/sessions.json";
+const IDLE_TIMEOUT_ENV: &str = "FS_SESSION_IDLE_MINUTES";
+const DEFAULT_IDLE_TIMEOUT_MINUTES: i64 = 60;
+const ABSOLUTE_TIMEOUT_ENV: &str = "FS_SESSION_MAX_HOURS";
+const DEFAULT_ABSOLUTE_TIMEOUT_HOURS: i64 = 24;
+const MAX_ANONYMOUS_ENV: &str = "FS_MAX_ANONYMOUS_SESSIONS";
+/// Anyone can start a session by loading the login page, so past this many sessions
+/// without a logged in user the oldest of them are ended
+const DEFAULT_MAX_ANONYMOUS: i64 = 1000;
+/// How often at most every session is checked for having expired. A single expired
+/// session is still refused as soon as it is used
+const EXPIRY_SWEEP_SECONDS: i64 = 60;
+/// Describes a logged in session so it can be listed and revoked without
+/// ever showing the session key itself
+const SESSION_INFO_KEY: &str = "session_info";
+/// The only session keys written to the sessions file. Everything else, like a TOTP secret
+/// that is still being set up or a login waiting for its second step, stays in memory
+const PERSISTED_KEYS: [&str; 2] = [USER_SESSION_KEY, SESSION_INFO_KEY];
+const SESSION_KEY_LENGTH: usize = 64;
+const SESSION_ID_LENGTH: usize = 16;
+
+type SessionState = HashMap<String, String>;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub ip: String,
+    pub user_agent: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    state: SessionState,
+    created: i64,
+    last_seen: i64,
+}
+
+impl StoredSession {
+    fn username(&self) -> Option<String> {
+        let user: serde_json::Value = serde_json::from_str(self.state.get(USER_SESSION_KEY)?).ok()?;
+        user.get("username")?.as_str().map(str::to_owned)
+    }
+
+    fn info(&self) -> Option<SessionInfo> {
+        serde_json::from_str(self.state.get(SESSION_INFO_KEY)?).ok()
+    }
+}
+
+/// Every session known to the server. Sessions with a logged in user are written
+/// to the sessions file so a restart does not log everyone out. Activity alone
+/// only updates memory, so the file is not rewritten on every request
+pub struct SessionRegistry {
+    sessions: HashMap<String, StoredSession>,
+    /// The keys of sessions saved without a logged in user, oldest first. Keys of sessions
+    /// that have ended or logged in since are skipped when their turn to be ended comes
+    anonymous: VecDeque<String>,
+    file: Option<String>,
+    idle_timeout: i64,
+    absolute_timeout: i64,
+    max_anonymous: usize,
+    last_sweep: i64,
+}
+
+impl SessionRegistry {
+    /// Loads the sessions file named by FS_SESSIONS_FILE, defaulting to private/sessions.json.
+    /// Setting it to an empty string keeps sessions in memory only
+    pub fn load() -> Self {
+        let file = match env::var(SESSIONS_FILE_ENV) {
+            Ok(file) if file.is_empty() => None,
+            Ok(file) => Some(file),
+            Err(_) => Some(DEFAULT_SESSIONS_FILE.to_owned())
+        };
+        let sessions = file.as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        let mut registry = Self {
+            sessions,
+            anonymous: VecDeque::new(),
+            file,
+            idle_timeout: env_number(IDLE_TIMEOUT_ENV, DEFAULT_IDLE_TIMEOUT_MINUTES) * 60,
+            absolute_timeout: env_number(ABSOLUTE_TIMEOUT_ENV, DEFAULT_ABSOLUTE_TIMEOUT_HOURS) * 60 * 60,
+            max_anonymous: env_number(MAX_ANONYMOUS_ENV, DEFAULT_MAX_ANONYMOUS) as usize,
+            last_sweep: 0
+        };
+        registry.remove_expired();
+        registry
+    }
+
+    /// How long the session cookie lives, the absolute timeout
+    pub fn cookie_ttl(&self) -> Duration {
+        Duration::seconds(self.absolute_timeout)
+    }
+
+    /// Returns the logged in sessions of a user, newest first
+    pub fn sessions_of(&self, username: &str) -> Vec<serde_json::Value> {
+        let mut sessions: Vec<(&StoredSession, SessionInfo)> = self.sessions.values()
+            .filter(|session| session.username().as_deref() == Some(username))
+            .filter_map(|session| Some((session, session.info()?)))
+            .collect();
+        sessions.sort_by_key(|(session, _)| -session.created);
+        sessions.into_iter()
+            .map(|(session, info)| serde_json::json!({
+                "id": info.id,
+                "ip": info.ip,
+                "user_agent": info.user_agent,
+                "created": format_timestamp(session.created),
+                "last_seen": format_timestamp(session.last_seen)
+            }))
+            .collect()
+    }
+
+    /// Returns how many logged in sessions each user has
+    pub fn counts(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for username in self.sessions.values().filter_map(StoredSession::username) {
+            *counts.entry(username).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Ends one session of a user by the id shown on their user page
+    pub fn revoke(&mut self, username: &str, id: &str) -> Result<(), AppErrorKind> {
+        let count = self.sessions.len();
+        self.sessions.retain(|_, session| {
+            !(session.username().as_deref() == Some(username) && session.info().is_some_and(|info| info.id == id))
+        });
+        if self.sessions.len() == count {
+            return Err(AppErrorKind::SessionNotFound);
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Ends every session of a user and returns how many there were
+    pub fn revoke_all(&mut self, username: &str) -> usize {
+        let count = self.sessions.len();
+        self.sessions.retain(|_, session| session.username().as_deref() != Some(username));
+        let revoked = count - self.sessions.len();
+        if revoked > 0 {
+            self.persist();
+        }
+        revoked
+    }
+
+    /// Returns whether the logged in session with the id shown in session lists has not
+    /// been revoked, logged out or expired
+    pub fn is_active(&self, id: &str) -> bool {
+        let now = now();
+        self.sessions.values().any(|session| {
+            session.info().is_some_and(|info| info.id == id) && session.username().is_some() && !self.is_expired(session, now)
+        })
+    }
+
+    fn is_expired(&self, session: &StoredSession, now: i64) -> bool {
+        now - session.last_seen > self.idle_timeout || now - session.created > self.absolute_timeout
+    }
+
+    /// Removes every expired session, unless that was done less than EXPIRY_SWEEP_SECONDS ago
+    fn remove_expired(&mut self) {
+        let now = now();
+        if now - self.last_sweep < EXPIRY_SWEEP_SECONDS {
+            return;
+        }
+        self.last_sweep = now;
+        let expired: Vec<String> = self.sessions.iter()
+            .filter(|(_, session)| self.is_expired(session, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.sessions.remove(&key);
+        }
+        let sessions = &self.sessions;
+        self.anonymous.retain(|key| sessions.contains_key(key));
+    }
+
+    /// Ends the oldest sessions without a logged in user once there are more than max_anonymous
+    fn limit_anonymous(&mut self) {
+        while self.anonymous.len() > self.max_anonymous {
+            let key = match self.anonymous.pop_front() {
+                Some(key) => key,
+                None => return
+            };
+            if self.sessions.get(&key).is_some_and(|session| session.username().is_none()) {
+                self.sessions.remove(&key);
+            }
+        }
+    }
+
+    /// Writes who is logged in to each logged in session to the sessions file, see PERSISTED_KEYS.
+    /// Anonymous sessions only hold things like a CSRF token so losing them on restart does not matter
+    fn persist(&self) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return
+        };
+        let logged_in: HashMap<&String, StoredSession> = self.sessions.iter()
+            .filter(|(_, session)| session.username().is_some())
+            .map(|(key, session)| (key, StoredSession {
+                state: session.state.iter()
+                    .filter(|(name, _)| PERSISTED_KEYS.contains(&name.as_str()))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+                created: session.created,
+                last_seen: session.last_seen
+            }))
+            .collect();
+        let tmp_file = format!("{}.tmp", file);
+        let result = serde_json::to_string(&logged_in)
+            .map_err(std::io::Error::other)
+            .and_then(|contents| fs::write(&tmp_file, contents))
+            .and_then(|_| {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    fs::set_permissions(&tmp_file, fs::Permissions::from_mode(0o600))?;
+                }
+                fs::rename(&tmp_file, file)
+            });
+        if let Err(err) = result {
+            log::error!("could not write sessions file {}: {}", file, err);
+        }
+    }
+}
+
+/// The actix-session store backed by the shared registry
+#[derive(Clone)]
+pub struct ServerSessionStore {
+    registry: Arc<Mutex<SessionRegistry>>,
+}
+
+impl ServerSessionStore {
+    pub fn new(registry: Arc<Mutex<SessionRegistry>>) -> Self {
+        Self { registry }
+    }
+
+    fn registry(&self) -> MutexGuard<'_, SessionRegistry> {
+        lock(&self.registry)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for ServerSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let mut registry = self.registry();
+        let now = now();
+        let expired = match registry.sessions.get(session_key.as_ref()) {
+            Some(session) => registry.is_expired(session, now),
+            None => return Ok(None)
+        };
+        if expired {
+            let session = registry.sessions.remove(session_key.as_ref());
+            if session.and_then(|session| session.username()).is_some() {
+                registry.persist();
+            }
+            return Ok(None);
+        }
+        Ok(registry.sessions.get(session_key.as_ref()).map(|session| session.state.clone()))
+    }
+
+    async fn save(&self, session_state: SessionState, _ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let key = generate_key(SESSION_KEY_LENGTH);
+        let mut registry = self.registry();
+        registry.remove_expired();
+        let now = now();
+        let session = StoredSession { state: session_state, created: now, last_seen: now };
+        let logged_in = session.username().is_some();
+        registry.sessions.insert(key.clone(), session);
+        if logged_in {
+            registry.persist();
+        }
+        else {
+            registry.anonymous.push_back(key.clone());
+            registry.limit_anonymous();
+        }
+        key.try_into().map_err(|err| SaveError::Other(anyhow::Error::new(err)))
+    }
+
+    /// A session that was revoked while a request was in flight is not brought
+    /// back. It is replaced with an empty one so the user stays logged out
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, UpdateError> {
+        let updated = {
+            let mut registry = self.registry();
+            match registry.sessions.get_mut(session_key.as_ref()) {
+                Some(session) => {
+                    let was_logged_in = session.username().is_some();
+                    session.state = session_state;
+                    session.last_seen = now();
+                    let logged_in = session.username().is_some();
+                    if was_logged_in || logged_in {
+                        registry.persist();
+                    }
+                    true
+                },
+                None => false
+            }
+        };
+        if updated {
+            return Ok(session_key);
+        }
+        self.save(SessionState::new(), ttl).await
+            .map_err(|err| UpdateError::Other(anyhow::Error::new(err)))
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, _ttl: &Duration) -> Result<(), anyhow::Error> {
+        if let Some(session) = self.registry().sessions.get_mut(session_key.as_ref()) {
+            session.last_seen = now();
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let mut registry = self.registry();
+        let session = registry.sessions.remove(session_key.as_ref());
+        if session.and_then(|session| session.username()).is_some() {
+            registry.persist();
+        }
+        Ok(())
+    }
+}
+
+/// Marks a session as logged in from this request. The session is renewed first
+/// so a session key set before login cannot be reused by someone else after it
+pub fn start(session: &Session, req: &HttpRequest) -> Result<(), AppErrorKind> {
+    session.renew();
+    let info = SessionInfo {
+        id: generate_key(SESSION_ID_LENGTH),
+        ip: req.peer_addr().map_or_else(|| String::from("unknown"), |addr| addr.ip().to_string()),
+        user_agent: req.headers().get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("unknown")
+            .to_owned()
+    };
+    session.insert(SESSION_INFO_KEY, info)
+        .map_err(|err| AppErrorKind::Session(err.to_string(), None))
+}
+
+/// Returns the id of the current session as shown in session lists
+pub fn current_id(session: &Session) -> Option<String> {
+    session.get::<SessionInfo>(SESSION_INFO_KEY).ok().flatten().map(|info| info.id)
+}
+
+/// A poisoned lock still holds valid sessions so keep using it
+pub fn lock(registry: &Mutex<SessionRegistry>) -> MutexGuard<'_, SessionRegistry> {
+    registry.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn generate_key(length: usize) -> String {
+    OsRng.sample_iter(&Alphanumeric).take(length).map(char::from).collect()
+}
+
+fn env_number(name: &str, default: i64) -> i64 {
+    env::var(name).ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .map(|t| time_format::format_time(t, None))
+        .unwrap_or_else(|_| String::from("Unknown"))
+}
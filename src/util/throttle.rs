@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use time::{Duration, OffsetDateTime};
+
+use super::{error::AppErrorKind, time_format};
+
+/// Failed logins allowed for one username before it is locked
+const USERNAME_FREE_ATTEMPTS: u32 = 5;
+/// Failed logins allowed from one address before it is locked. Higher than the
+/// username limit since several people can share an address
+const IP_FREE_ATTEMPTS: u32 = 20;
+/// First lockout, doubled for every failure after that
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+/// Failures are forgotten once there has not been one for this long
+const FORGET_AFTER_SECS: i64 = 24 * 60 * 60;
+
+/// What failed login attempts are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "kind", content = "key", rename_all = "lowercase")]
+pub enum AttemptKey {
+    Ip(String),
+    Username(String),
+}
+
+#[derive(Debug)]
+struct Attempts {
+    failures: u32,
+    last_failure: OffsetDateTime,
+    locked_until: Option<OffsetDateTime>,
+}
+
+/// Counts failed logins per address and per username and locks them out
+/// with exponential backoff. Kept in memory so a restart clears all lockouts
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    attempts: HashMap<AttemptKey, Attempts>,
+}
+
+impl LoginThrottle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails if the address or the username is currently locked out
+    pub fn check(&mut self, ip: &str, username: &str) -> Result<(), AppErrorKind> {
+        let now = OffsetDateTime::now_utc();
+        self.attempts.retain(|_, attempts| now - attempts.last_failure < Duration::seconds(FORGET_AFTER_SECS));
+        let remaining = keys(ip, username).iter()
+            .filter_map(|key| self.attempts.get(key)?.locked_until)
+            .map(|until| (until - now).whole_seconds())
+            .max()
+            .unwrap_or(0);
+        if remaining > 0 {
+            return Err(AppErrorKind::LoginLocked(remaining));
+        }
+        Ok(())
+    }
+
+    pub fn record_failure(&mut self, ip: &str, username: &str) {
+        let now = OffsetDateTime::now_utc();
+        for key in keys(ip, username) {
+            let free_attempts = match key {
+                AttemptKey::Ip(_) => IP_FREE_ATTEMPTS,
+                AttemptKey::Username(_) => USERNAME_FREE_ATTEMPTS
+            };
+            let attempts = self.attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: None
+            });
+            attempts.failures += 1;
+            attempts.last_failure = now;
+            if attempts.failures >= free_attempts {
+                let doublings = (attempts.failures - free_attempts).min(16);
+                let lockout = (BASE_LOCKOUT_SECS << doublings).min(MAX_LOCKOUT_SECS);
+                attempts.locked_until = Some(now + Duration::seconds(lockout));
+                log::warn!("login locked for {:?} for {} seconds after {} failed attempts", key, lockout, attempts.failures);
+            }
+        }
+    }
+
+    /// Clears the username's failures. The address keeps its count so one
+    /// valid account cannot be used to keep guessing the passwords of others
+    pub fn record_success(&mut self, username: &str) {
+        self.attempts.remove(&AttemptKey::Username(username.to_owned()));
+    }
+
+    /// Returns the addresses and usernames that are locked right now
+    pub fn lockouts(&self) -> Vec<serde_json::Value> {
+        let now = OffsetDateTime::now_utc();
+        let mut lockouts: Vec<(&AttemptKey, &Attempts)> = self.attempts.iter()
+            .filter(|(_, attempts)| attempts.locked_until.is_some_and(|until| until > now))
+            .collect();
+        lockouts.sort_by_key(|(_, attempts)| attempts.locked_until);
+        lockouts.into_iter()
+            .map(|(key, attempts)| serde_json::json!({
+                "key": key,
+                "failures": attempts.failures,
+                "locked_until": attempts.locked_until.map(|until| time_format::format_time(until, None))
+            }))
+            .collect()
+    }
+
+    pub fn unlock(&mut self, key: &AttemptKey) -> bool {
+        self.attempts.remove(key).is_some()
+    }
+}
+
+fn keys(ip: &str, username: &str) -> [AttemptKey; 2] {
+    [AttemptKey::Ip(ip.to_owned()), AttemptKey::Username(username.to_owned())]
+}
@@ -22,7 +22,7 @@ pub struct TimelapseSettings {
 
 impl Clone for TimelapseSettings {
     fn clone(&self) -> Self {
-        Self { frequency: self.frequency.clone(), quality: self.quality.clone(), folder_name: self.folder_name.clone(), file_prefix: self.file_prefix.clone() }
+        Self { frequency: self.frequency, quality: self.quality, folder_name: self.folder_name.clone(), file_prefix: self.file_prefix.clone() }
     }
 }
 
@@ -33,6 +33,12 @@ pub struct TimelapseThread {
     settings: Option<TimelapseSettings>
 }
 
+impl Default for TimelapseThread {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TimelapseThread {
     pub fn new() -> Self {
         Self {
@@ -54,13 +60,13 @@ impl TimelapseThread {
             thread::sleep(Duration::from_secs(settings.frequency));
             match rx.try_recv() {
                 Ok(()) => break,
-                Err(err) if err == TryRecvError::Empty => {
+                Err(TryRecvError::Empty) => {
                     let time = time_format::now(Some("[year]_[month]_[day]-[hour]_[minute]_[second]"));
                     match Command::new("raspistill")
                       .arg("-t")
                       .arg("1000")
                       .arg("-q")
-                      .arg(format!("{}", settings.quality.to_string()))
+                      .arg(settings.quality.to_string())
                       .arg("-o")
                       .arg(format!(
                               "{}{}{}{}-{}.jpg",
@@ -92,19 +98,16 @@ impl TimelapseThread {
     }
 
     pub fn stop(&mut self) {
-        match &self.tx {
-            Some(tx) => {
-                match tx.send(()) {
-                    Ok(()) => {}
-                    Err(err) => {
-                        println!("Timelapse stop err: {}", err);
-                    }
-                };
-                self.tx = None;
-                self.thread = None;
-                self.settings = None;
-            }
-            None => {}
+        if let Some(tx) = &self.tx {
+            match tx.send(()) {
+                Ok(()) => {}
+                Err(err) => {
+                    println!("Timelapse stop err: {}", err);
+                }
+            };
+            self.tx = None;
+            self.thread = None;
+            self.settings = None;
         };
     }
 }
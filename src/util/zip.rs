@@ -1,15 +1,22 @@
-use std::io::prelude::*;
 use std::io::{Write, Seek};
 use std::iter::Iterator;
 use zip::write::FileOptions;
 
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::fs::{File, self, DirEntry, ReadDir};
+use std::time::SystemTime;
+use time::{Date, Month, PrimitiveDateTime, Time, UtcOffset};
+
+use crate::models::conflict::{ConflictPolicy, ItemOutcome, Outcome, Resolution};
+use crate::models::folder::Folder;
+use crate::util::jobs::Progress;
 
 // const ZIP_METHOD : zip::CompressionMethod = zip::CompressionMethod::Stored;
 const DEFLATED_METHOD : zip::CompressionMethod = zip::CompressionMethod::Deflated;
 
-pub fn create_zip_from_folder(folder_path: &str, folder_name: &str) -> std::io::Result<()> {
+/// Zips a folder next to itself. Symbolic links are only added when follow_links is set
+/// and they point to a file inside root, linked folders are never added
+pub fn create_zip_from_folder(folder_path: &str, folder_name: &str, root: &Path, follow_links: bool, progress: &Progress) -> std::io::Result<()> {
     let src_dir = format!("{}/{}", folder_path, folder_name);
     let dst_file = format!("{}/{}.zip", folder_path, folder_name);
     // log::debug!("zip: {} to {}", src_dir, dst_file);
@@ -17,16 +24,61 @@ pub fn create_zip_from_folder(folder_path: &str, folder_name: &str) -> std::io::
         return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Can only zip folders"));
     }
     let path = Path::new(&dst_file);
-    let file = File::create(&path)?;
-    let dir = fs::read_dir(src_dir.to_string())?;
-    let all_dirs = read_all_dirs(dir);
+    let file = File::create(path)?;
+    let dir = fs::read_dir(&src_dir)?;
+    let all_dirs = read_all_dirs(dir).into_iter()
+        .map(|entry| entry.path())
+        .filter(|path| is_included(path, root, follow_links))
+        .collect();
 
-    zip_dir(all_dirs, &src_dir, file)?;
+    if let Err(err) = zip_dir(all_dirs, Path::new(&src_dir), progress, file) {
+        let _ = fs::remove_file(path);
+        return Err(err.into());
+    }
     Ok(())
 }
 
-fn zip_dir<T>(all_dirs: Vec<DirEntry>, prefix: &str, writer: T)
-              -> zip::result::ZipResult<()>
+/// Zips the named folders and files of a folder into a new archive, which must not exist yet
+/// A zip that fails or is cancelled part way is removed
+pub fn create_zip_from_entries(folder_path: &Path, names: &[String], archive_path: &Path, root: &Path, follow_links: bool, progress: &Progress) -> std::io::Result<()> {
+    let file = fs::OpenOptions::new().write(true).create_new(true).open(archive_path)?;
+    if let Err(err) = zip_entries(folder_path, names, root, follow_links, progress, file) {
+        let _ = fs::remove_file(archive_path);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Zips the named folders and files of a folder, with everything in those folders, into
+/// writer. Symbolic links are added the same as when zipping a folder
+pub fn zip_entries<T: Write + Seek>(folder_path: &Path, names: &[String], root: &Path, follow_links: bool, progress: &Progress, writer: T) -> std::io::Result<T> {
+    let mut paths = Vec::new();
+    for name in names {
+        let path = folder_path.join(name);
+        if !is_included(&path, root, follow_links) {
+            continue;
+        }
+        if fs::symlink_metadata(&path)?.is_dir() {
+            paths.push(path.clone());
+            paths.extend(read_all_dirs(fs::read_dir(&path)?).into_iter()
+                .map(|entry| entry.path())
+                .filter(|path| is_included(path, root, follow_links)));
+        }
+        else {
+            paths.push(path);
+        }
+    }
+    Ok(zip_dir(paths, folder_path, progress, writer)?)
+}
+
+/// Links are left out unless follow_links is set and they point to a file inside root
+fn is_included(path: &Path, root: &Path, follow_links: bool) -> bool {
+    !fs::symlink_metadata(path).is_ok_and(|data| data.file_type().is_symlink())
+        || (follow_links && fs::canonicalize(path).is_ok_and(|target| target.starts_with(root) && target.is_file()))
+}
+
+fn zip_dir<T>(all_dirs: Vec<PathBuf>, prefix: &Path, progress: &Progress, writer: T)
+              -> zip::result::ZipResult<T>
     where T: Write+Seek
 {
     let mut zip = zip::ZipWriter::new(writer);
@@ -34,57 +86,71 @@ fn zip_dir<T>(all_dirs: Vec<DirEntry>, prefix: &str, writer: T)
         .compression_method(DEFLATED_METHOD)
         .unix_permissions(0o755);
 
-    let mut buffer = Vec::new();
-    for entry in all_dirs {
-        let path = entry.path();
-        let name = path.strip_prefix(Path::new(prefix)).unwrap().to_str().unwrap();
+    for path in all_dirs.iter().filter(|path| path.is_file()) {
+        progress.add_total(1, fs::metadata(path).map_or(0, |data| data.len()));
+    }
+    for path in all_dirs {
+        progress.check()?;
+        let name = path.strip_prefix(prefix).unwrap().to_str().unwrap();
         // Write file or directory explicitly
         // Some unzip tools unzip files with directory paths correctly, some do not!
         if path.is_file() {
             // log::debug!("adding file {:?} as {:?} ...", path, name);
             zip.start_file(name, options)?;
-            let mut f = File::open(path)?;
-            f.read_to_end(&mut buffer)?;
-            zip.write_all(&*buffer)?;
-            buffer.clear();
-        } else if name.len() != 0 {
+            let mut f = File::open(&path)?;
+            progress.copy(&mut f, &mut zip)?;
+            progress.file_done();
+        } else if !name.is_empty() {
             // Only if not root! Avoids path spec / warning
             // and mapname conversion failed error on unzip
             // log::debug!("adding dir {:?} as {:?} ...", path, name);
             zip.add_directory(name, options)?;
         }
     }
-    zip.finish()?;
-    Ok(())
+    zip.finish()
 }
 
 fn read_all_dirs(dir: ReadDir) -> Vec<DirEntry> {
     let mut sub_dirs = Vec::new();
-    let mut dir: Vec<DirEntry> = dir.into_iter().filter_map(|e| e.ok()).map(|entity| {
-        if entity.path().is_dir() {
+    let mut dir: Vec<DirEntry> = dir.into_iter().filter_map(|e| e.ok()).inspect(|entity| {
+        if entity.file_type().is_ok_and(|file_type| file_type.is_dir()) {
             if let Ok(sub_dir) = fs::read_dir(entity.path().to_str().unwrap()) {
                 sub_dirs.append(&mut read_all_dirs(sub_dir));
             }
         }
-        entity
     }).collect();
     dir.append(&mut sub_dirs);
     dir
 }
 
-pub fn extract_zip(archive_path: &str) -> std::io::Result<()> {
-    let archive_path = std::path::Path::new(archive_path);
+/// Extracts a zip into the extracted folder, applying the conflict policy to each file
+/// already there. Nothing is written outside of root, even through a symbolic link
+/// already in the folder being extracted to
+pub fn extract_zip(archive_path: &str, extracted: &Folder, root: &Path, policy: ConflictPolicy, user: &str, progress: &Progress) -> std::io::Result<Vec<ItemOutcome>> {
     let archive_file = fs::File::open(archive_path)?;
-    let file_name = archive_path.file_name().unwrap().to_str().unwrap().replace(".tar.bz2", "").replace(".zip", "");
-    let extracted_path = archive_path.parent().unwrap().join(file_name);
     let mut archive = zip::ZipArchive::new(archive_file)?;
+    let mut outcomes = Vec::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        if !file.is_dir() {
+            progress.add_total(1, file.size());
+        }
+    }
 
     for i in 0..archive.len() {
+        progress.check()?;
         let mut file = archive.by_index(i)?;
-        let outpath = match file.enclosed_name() {
-            Some(path) => extracted_path.join(path),
+        let entry = match file.enclosed_name().and_then(|path| entry_folder(extracted, path)) {
+            Some(entry) => entry,
             None => continue,
         };
+        let outpath = match entry.to_path() {
+            Ok(path) => PathBuf::from(path),
+            Err(err) => {
+                log::warn!("skipped extracting \"{}\": {}", entry.relative_path(), err);
+                continue;
+            }
+        };
 
         {
             let comment = file.comment();
@@ -93,19 +159,45 @@ pub fn extract_zip(archive_path: &str) -> std::io::Result<()> {
             }
         }
 
+        if !stays_inside(&outpath, root) {
+            log::warn!("skipped extracting \"{}\" outside of the root folder", outpath.display());
+            continue;
+        }
         if (*file.name()).ends_with('/') {
             log::debug!("File {} extracted to \"{}\"", i, outpath.display());
             fs::create_dir_all(&outpath)?;
-        } else {
-            log::debug!("File {} extracted to \"{}\" ({} bytes)", i, outpath.display(), file.size());
-            if let Some(p) = outpath.parent() {
-                if !p.exists() {
-                    fs::create_dir_all(&p)?;
+            continue;
+        }
+        if let Some(p) = outpath.parent() {
+            if !p.exists() {
+                fs::create_dir_all(p)?;
+            }
+        }
+        let name = entry.names()[extracted.names().len()..].join("/");
+        let (outpath, outcome) = match policy.resolve(&entry, modified_time(file.last_modified()), user) {
+            Ok(Resolution::Write(target, outcome)) => match target.to_path() {
+                Ok(path) => (PathBuf::from(path), outcome),
+                Err(err) => {
+                    outcomes.push(ItemOutcome::new(&name, Outcome::Failed(err.to_string())));
+                    continue;
                 }
+            },
+            Ok(Resolution::Skip) => {
+                outcomes.push(ItemOutcome::new(&name, Outcome::Skipped));
+                continue;
+            },
+            Err(err) => {
+                outcomes.push(ItemOutcome::new(&name, Outcome::Failed(err.to_string())));
+                continue;
             }
-            let mut outfile = fs::File::create(&outpath)?;
-            std::io::copy(&mut file, &mut outfile)?;
+        };
+        log::debug!("File {} extracted to \"{}\" ({} bytes)", i, outpath.display(), file.size());
+        let mut outfile = fs::OpenOptions::new().write(true).create_new(true).open(&outpath)?;
+        if let Err(err) = progress.copy(&mut file, &mut outfile) {
+            let _ = fs::remove_file(&outpath);
+            return Err(err);
         }
+        progress.file_done();
 
         // Get and Set permissions
         #[cfg(unix)]
@@ -116,6 +208,35 @@ pub fn extract_zip(archive_path: &str) -> std::io::Result<()> {
                 fs::set_permissions(&outpath, fs::Permissions::from_mode(mode))?;
             }
         }
+        outcomes.push(ItemOutcome::new(&name, outcome));
     }
-    Ok(())
-}
\ No newline at end of file
+    Ok(outcomes)
+}
+
+/// The folder or file a path in a zip is extracted to
+fn entry_folder(extracted: &Folder, path: &Path) -> Option<Folder> {
+    path.components().try_fold(extracted.clone(), |folder, component| match component {
+        Component::Normal(name) => folder.join(name.to_str()?).ok(),
+        _ => None
+    })
+}
+
+/// Zips keep the local time a file was modified without an offset
+fn modified_time(modified: zip::DateTime) -> Option<SystemTime> {
+    let date = Date::from_calendar_date(modified.year() as i32, Month::try_from(modified.month()).ok()?, modified.day()).ok()?;
+    let time = Time::from_hms(modified.hour(), modified.minute(), modified.second()).ok()?;
+    let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+    Some(PrimitiveDateTime::new(date, time).assume_offset(offset).into())
+}
+
+/// Checks that writing to path cannot end up outside of root. The closest part of
+/// the path that already exists is resolved, and the path itself must not be a link
+fn stays_inside(path: &Path, root: &Path) -> bool {
+    if fs::symlink_metadata(path).is_ok_and(|data| data.file_type().is_symlink()) {
+        return false;
+    }
+    path.ancestors()
+        .find(|ancestor| ancestor.exists())
+        .and_then(|ancestor| fs::canonicalize(ancestor).ok())
+        .is_some_and(|ancestor| ancestor.starts_with(root))
+}